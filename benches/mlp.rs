@@ -0,0 +1,114 @@
+//! Training steps of a small MLP, once on batched tensors and once micrograd-style with one scalar tensor
+//! per weight, where the cost of recording and walking the graph dominates.
+//!
+//! Run with `cargo bench --bench mlp`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use grad::{
+    nn::{manual_seed, Activation, Mlp, Module},
+    optim::{Adam, Optimizer, Sgd},
+    tensor::Tensor,
+};
+
+fn xor() -> (Tensor, Tensor) {
+    let x = Tensor::from_vec(vec![0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 1.0, 1.0], &[4, 2]);
+    let y = Tensor::from_vec(vec![0.0, 1.0, 1.0, 0.0], &[4, 1]);
+    (x, y)
+}
+
+fn batched(c: &mut Criterion) {
+    manual_seed(0);
+    let model = Mlp::new(&[2, 16, 16, 1], Activation::Tanh);
+    let mut optimizer = Adam::new(model.parameters(), 0.01, (0.9, 0.999), 1e-8, 0.0);
+    let (x, y) = xor();
+
+    c.bench_function("mlp_train_step/batched", |b| {
+        b.iter(|| {
+            optimizer.zero_grad();
+            let loss = model.forward(&x).unwrap().mse_loss(&y).unwrap();
+            loss.backward();
+            optimizer.step();
+            black_box(loss.item())
+        })
+    });
+}
+
+/// A `[2, 16, 16, 1]` tanh network where every weight, bias and activation is its own scalar tensor
+struct ScalarMlp {
+    layers: Vec<Vec<(Vec<Tensor>, Tensor)>>,
+}
+
+impl ScalarMlp {
+    fn new(sizes: &[usize]) -> ScalarMlp {
+        manual_seed(0);
+        // reuses the seeded initialization of `Mlp`, one element at a time
+        let mlp = Mlp::new(sizes, Activation::Tanh);
+        let parameters = mlp.parameters();
+        let layers = parameters
+            .chunks(2)
+            .map(|layer| {
+                let (weight, bias) = (layer[0].data(), layer[1].data());
+                let outputs = bias.len();
+                (0..outputs)
+                    .map(|j| {
+                        let inputs = weight.len() / outputs;
+                        let w = (0..inputs).map(|i| Tensor::from(weight[i * outputs + j])).collect();
+                        (w, Tensor::from(bias[j]))
+                    })
+                    .collect()
+            })
+            .collect();
+        ScalarMlp { layers }
+    }
+
+    fn forward(&self, x: &[Tensor]) -> Tensor {
+        let mut x = x.to_vec();
+        for (l, layer) in self.layers.iter().enumerate() {
+            x = layer
+                .iter()
+                .map(|(w, b)| {
+                    let z = w
+                        .iter()
+                        .zip(&x)
+                        .fold(b.clone(), |acc, (w, x)| acc + w.clone() * x.clone());
+                    if l + 1 < self.layers.len() {
+                        z.tanh()
+                    } else {
+                        z
+                    }
+                })
+                .collect();
+        }
+        x.remove(0)
+    }
+
+    fn parameters(&self) -> Vec<Tensor> {
+        let neurons = self.layers.iter().flatten();
+        neurons.flat_map(|(w, b)| w.iter().chain([b]).cloned()).collect()
+    }
+}
+
+fn scalar(c: &mut Criterion) {
+    let model = ScalarMlp::new(&[2, 16, 16, 1]);
+    let mut optimizer = Sgd::new(model.parameters(), 0.05, 0.9);
+    let (x, y) = xor();
+    let (x, y) = (x.data(), y.data());
+
+    c.bench_function("mlp_train_step/scalar", |b| {
+        b.iter(|| {
+            optimizer.zero_grad();
+            let mut loss = Tensor::from(0.0);
+            for (row, target) in x.chunks(2).zip(&y) {
+                let row = row.iter().map(|x| Tensor::from(*x)).collect::<Vec<_>>();
+                let diff = model.forward(&row) - Tensor::from(*target);
+                loss = loss + diff.clone() * diff;
+            }
+            loss.backward();
+            optimizer.step();
+            black_box(loss.item())
+        })
+    });
+}
+
+criterion_group!(benches, batched, scalar);
+criterion_main!(benches);
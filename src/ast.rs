@@ -1,6 +1,9 @@
 use core::fmt;
 
-use crate::scanner::{Lexer, TokenType};
+use crate::{
+    scanner::{Lexer, Token, TokenType},
+    source::Span,
+};
 use serde::{Deserialize, Serialize};
 
 /// Represents a node in the Abstract Syntax Tree (AST)
@@ -10,16 +13,22 @@ pub enum ASTNode {
     FloatNumber(f64),
     Identifier(String),
     Boolean(bool),
+    Nil,
     String(String),
+    List(Vec<ASTNode>),
     Op(Ops, Vec<ASTNode>),
     Callee(String, Vec<ASTNode>),
     Let(String, Vec<ASTNode>),
     Assign(String, Vec<ASTNode>),
     If(Vec<ASTNode>, Vec<ASTNode>, Option<Vec<ASTNode>>),
     While(Vec<ASTNode>, Vec<ASTNode>),
+    NoGrad(Vec<ASTNode>),
     Print(Vec<ASTNode>),
     Function(String, Vec<String>, Vec<ASTNode>),
+    Return(Vec<ASTNode>),
     Block(Vec<ASTNode>),
+    /// A statement or an expression along with the part of the source it was parsed from
+    Spanned(Span, Box<ASTNode>),
 }
 
 /// Represents binary operations
@@ -50,6 +59,7 @@ pub enum UnaryOp {
 pub enum PostfixOp {
     Index,
     Call,
+    Invoke,
     StarStar,
 }
 
@@ -61,25 +71,37 @@ pub enum Ops {
     PostfixOp(PostfixOp),
 }
 
-#[derive(Debug)]
+/// A syntax error, along with the span of the token it was found at
+#[derive(Debug, Clone, PartialEq)]
 pub enum ParseError {
-    UnexpectedToken(TokenType, String),
-    MissingToken(TokenType, String),
-    InvalidOperator(String),
-    SyntaxError(String),
+    UnexpectedToken(TokenType, String, Span),
+    MissingToken(TokenType, String, Span),
+    InvalidOperator(String, Span),
+    SyntaxError(String, Span),
+}
+
+impl ParseError {
+    pub fn span(&self) -> &Span {
+        match self {
+            ParseError::UnexpectedToken(_, _, span)
+            | ParseError::MissingToken(_, _, span)
+            | ParseError::InvalidOperator(_, span)
+            | ParseError::SyntaxError(_, span) => span,
+        }
+    }
 }
 
 impl std::fmt::Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ParseError::UnexpectedToken(token, context) => {
+            ParseError::UnexpectedToken(token, context, _) => {
                 write!(f, "Unexpected token {:?} {}", token, context)
             }
-            ParseError::MissingToken(token, context) => {
+            ParseError::MissingToken(token, context, _) => {
                 write!(f, "Missing token {:?} {}", token, context)
             }
-            ParseError::InvalidOperator(msg) => write!(f, "Invalid operator: {}", msg),
-            ParseError::SyntaxError(msg) => write!(f, "Syntax error: {}", msg),
+            ParseError::InvalidOperator(msg, _) => write!(f, "Invalid operator: {}", msg),
+            ParseError::SyntaxError(msg, _) => write!(f, "Syntax error: {}", msg),
         }
     }
 }
@@ -91,26 +113,81 @@ type ParseResult<T> = Result<T, ParseError>;
 /// Parser struct for recursive descent parsing
 pub struct Parser<'a> {
     lexer: &'a mut Lexer,
+
+    // syntax errors found so far, parsing carries on after each one
+    errors: Vec<ParseError>,
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(lexer: &mut Lexer) -> Parser {
-        Parser { lexer }
+    pub fn new(lexer: &mut Lexer) -> Parser<'_> {
+        Parser {
+            lexer,
+            errors: Vec::new(),
+        }
     }
 
-    /// Main parsing function
-    pub fn parse(&mut self) -> ParseResult<Vec<ASTNode>> {
+    /// Main parsing function, returning every syntax error in the source if there are any
+    pub fn parse(&mut self) -> Result<Vec<ASTNode>, Vec<ParseError>> {
         let mut statements = vec![];
 
         while self.lexer.peek().token_type != TokenType::EOF {
-            statements.push(self.parse_statement()?);
+            statements.extend(self.parse_recovering());
+        }
+
+        if self.errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    /// Parses a statement. On a syntax error the error is recorded and the rest of the statement skipped,
+    /// so parsing picks up again at the next one
+    fn parse_recovering(&mut self) -> Option<ASTNode> {
+        let remaining = self.lexer.tokens.len();
+        match self.parse_statement() {
+            Ok(statement) => Some(statement),
+            Err(e) => {
+                self.errors.push(e);
+                // the statement failed on its first token, e.g. a stray `}`, which can't start the next one
+                if self.lexer.tokens.len() == remaining {
+                    self.lexer.next();
+                }
+                self.synchronize();
+                None
+            }
         }
+    }
 
-        Ok(statements)
+    /// Skips tokens up to a statement boundary: past a semicolon, or up to a keyword or a brace starting a
+    /// statement, or a brace closing the block. Stopping at `{` keeps the body of a broken `fn` or `if`
+    /// balanced, instead of its `}` being reported as well
+    fn synchronize(&mut self) {
+        loop {
+            match self.lexer.peek().token_type {
+                TokenType::SEMICOLON => {
+                    self.lexer.next();
+                    return;
+                }
+                TokenType::LET
+                | TokenType::FN
+                | TokenType::IF
+                | TokenType::WHILE
+                | TokenType::PRINT
+                | TokenType::RETURN
+                | TokenType::LeftBrace
+                | TokenType::RightBrace
+                | TokenType::EOF => return,
+                _ => {
+                    self.lexer.next();
+                }
+            }
+        }
     }
 
     /// Parse a single statement
     fn parse_statement(&mut self) -> ParseResult<ASTNode> {
+        let start = self.lexer.peek().span.start;
         let statement = match self.lexer.peek().token_type {
             TokenType::PRINT => self.parse_print(),
             TokenType::LET => self.parse_let(),
@@ -118,6 +195,8 @@ impl<'a> Parser<'a> {
             TokenType::LeftBrace => self.parse_block(),
             TokenType::IF => self.parse_if(),
             TokenType::WHILE => self.parse_while(),
+            TokenType::NoGrad => self.parse_no_grad(),
+            TokenType::RETURN => self.parse_return(),
             TokenType::Identifier if self.is_assignment() => self.parse_assign(),
             TokenType::SEMICOLON => {
                 self.lexer.next(); // Consume the semicolon
@@ -125,6 +204,7 @@ impl<'a> Parser<'a> {
             }
             _ => self.parse_expression(),
         }?;
+        let statement = spanned(self.lexer, start, statement);
 
         // Consume the semicolon if present
         if self.lexer.peek().token_type == TokenType::SEMICOLON {
@@ -135,30 +215,22 @@ impl<'a> Parser<'a> {
     }
     fn parse_print(&mut self) -> ParseResult<ASTNode> {
         self.lexer.next();
-        if self.lexer.next().token_type != TokenType::LeftParen {
+        let token = self.lexer.next();
+        if token.token_type != TokenType::LeftParen {
             return Err(ParseError::SyntaxError(
                 "Expected '(' after print".to_string(),
+                token.span,
             ));
         }
         let expr = self.parse_expression()?;
-        if self.lexer.next().token_type != TokenType::RightParen {
-            return Err(ParseError::MissingToken(
-                TokenType::RightParen,
-                "to close print statement".to_string(),
-            ));
-        }
+        expect(self.lexer, TokenType::RightParen, "to close print statement")?;
         Ok(ASTNode::Print(vec![expr]))
     }
 
     fn parse_let(&mut self) -> ParseResult<ASTNode> {
         self.lexer.next();
-        let identifier = self.lexer.next().lexeme;
-        if self.lexer.next().token_type != TokenType::EQUAL {
-            return Err(ParseError::MissingToken(
-                TokenType::EQUAL,
-                "to assign value to variable".to_string(),
-            ));
-        }
+        let identifier = expect(self.lexer, TokenType::Identifier, "to name the variable")?.lexeme;
+        expect(self.lexer, TokenType::EQUAL, "to assign value to variable")?;
         let expr = self.parse_expression()?;
         Ok(ASTNode::Let(identifier, vec![expr]))
     }
@@ -167,10 +239,10 @@ impl<'a> Parser<'a> {
     fn parse_block(&mut self) -> ParseResult<ASTNode> {
         self.lexer.next();
         let mut statements = vec![];
-        while self.lexer.peek().token_type != TokenType::RightBrace {
-            statements.push(self.parse_statement()?);
+        while !matches!(self.lexer.peek().token_type, TokenType::RightBrace | TokenType::EOF) {
+            statements.extend(self.parse_recovering());
         }
-        self.lexer.next(); // consume RightBrace
+        expect(self.lexer, TokenType::RightBrace, "to close block")?;
         Ok(ASTNode::Block(statements))
     }
 
@@ -196,19 +268,9 @@ impl<'a> Parser<'a> {
     // TODO: might need fixing
     fn parse_if(&mut self) -> ParseResult<ASTNode> {
         self.lexer.next();
-        if self.lexer.next().token_type != TokenType::LeftParen {
-            return Err(ParseError::MissingToken(
-                TokenType::LeftParen,
-                "to start if condition".to_string(),
-            ));
-        }
+        expect(self.lexer, TokenType::LeftParen, "to start if condition")?;
         let condition = self.parse_expression()?;
-        if self.lexer.next().token_type != TokenType::RightParen {
-            return Err(ParseError::MissingToken(
-                TokenType::RightParen,
-                "to close if condition".to_string(),
-            ));
-        }
+        expect(self.lexer, TokenType::RightParen, "to close if condition")?;
         // let then_branch = self.parse_block()?;
 
         // let else_branch = if self.lexer.peek().token_type == TokenType::ELSE {
@@ -230,82 +292,86 @@ impl<'a> Parser<'a> {
     // TODO: might need fixing
     fn parse_while(&mut self) -> ParseResult<ASTNode> {
         self.lexer.next();
-        if self.lexer.next().token_type != TokenType::LeftParen {
-            return Err(ParseError::MissingToken(
-                TokenType::LeftParen,
-                "to start while condition".to_string(),
-            ));
-        }
+        expect(self.lexer, TokenType::LeftParen, "to start while condition")?;
         let condition = self.parse_expression()?;
-        if self.lexer.next().token_type != TokenType::RightParen {
-            return Err(ParseError::MissingToken(
-                TokenType::RightParen,
-                "to close while condition".to_string(),
-            ));
-        }
+        expect(self.lexer, TokenType::RightParen, "to close while condition")?;
         // let body = self.parse_block()?;
         let body = vec![self.parse_statement()?];
         Ok(ASTNode::While(vec![condition], body))
     }
 
+    /// `no_grad` followed by the statement to run without recording the computation graph
+    fn parse_no_grad(&mut self) -> ParseResult<ASTNode> {
+        self.lexer.next();
+        let body = vec![self.parse_statement()?];
+        Ok(ASTNode::NoGrad(body))
+    }
+
     fn parse_function(&mut self) -> ParseResult<ASTNode> {
         self.lexer.next();
-        let name = self.lexer.next().lexeme;
-        if self.lexer.next().token_type != TokenType::LeftParen {
-            return Err(ParseError::MissingToken(
-                TokenType::LeftParen,
-                "to start function parameters".to_string(),
-            ));
-        }
+        let name = expect(self.lexer, TokenType::Identifier, "to name the function")?.lexeme;
+        expect(self.lexer, TokenType::LeftParen, "to start function parameters")?;
         let mut params = vec![];
         while self.lexer.peek().token_type != TokenType::RightParen {
-            params.push(self.lexer.next().lexeme);
-            if self.lexer.peek().token_type == TokenType::COMMA {
-                self.lexer.next();
+            params.push(expect(self.lexer, TokenType::Identifier, "as a parameter name")?.lexeme);
+            if self.lexer.peek().token_type != TokenType::COMMA {
+                break;
             }
+            self.lexer.next();
         }
-        self.lexer.next(); // consume RightParen
-                           // let body = self.parse_block()?;
+        expect(self.lexer, TokenType::RightParen, "to close function parameters")?;
+        // let body = self.parse_block()?;
         let body = vec![self.parse_statement()?];
         Ok(ASTNode::Function(name, params, body))
     }
+    fn parse_return(&mut self) -> ParseResult<ASTNode> {
+        self.lexer.next();
+        match self.lexer.peek().token_type {
+            TokenType::SEMICOLON | TokenType::RightBrace | TokenType::EOF => {
+                Ok(ASTNode::Return(vec![]))
+            }
+            _ => Ok(ASTNode::Return(vec![self.parse_expression()?])),
+        }
+    }
+
     fn parse_assign(&mut self) -> ParseResult<ASTNode> {
-        let id = self.lexer.next().lexeme;
-        let op = self.lexer.next().token_type;
+        let id = self.lexer.next();
+        let op = self.lexer.next();
         let expr = self.parse_expression()?;
 
-        let expr = match op {
+        let expr = match op.token_type {
             TokenType::EQUAL => expr,
             TokenType::PlusEqual
             | TokenType::MinusEqual
             | TokenType::StarEqual
             | TokenType::SlashEqual => {
-                let bin_op = match op {
+                let bin_op = match op.token_type {
                     TokenType::PlusEqual => BinaryOp::Add,
                     TokenType::MinusEqual => BinaryOp::Sub,
                     TokenType::StarEqual => BinaryOp::Mul,
                     TokenType::SlashEqual => BinaryOp::Div,
                     _ => {
-                        return Err(ParseError::InvalidOperator(format!(
-                            "Invalid assignment operator: {:?}",
-                            op
-                        )))
+                        return Err(ParseError::InvalidOperator(
+                            format!("Invalid assignment operator: {:?}", op.token_type),
+                            op.span,
+                        ))
                     }
                 };
+                let target = ASTNode::Identifier(id.lexeme.clone());
                 ASTNode::Op(
                     Ops::BinaryOp(bin_op),
-                    vec![ASTNode::Identifier(id.clone()), expr],
+                    vec![ASTNode::Spanned(id.span, Box::new(target)), expr],
                 )
             }
             _ => {
-                return Err(ParseError::InvalidOperator(format!(
-                    "Invalid assignment operator: {:?}",
-                    op
-                )))
+                return Err(ParseError::InvalidOperator(
+                    format!("Invalid assignment operator: {:?}", op.token_type),
+                    op.span,
+                ))
             }
         };
 
-        Ok(ASTNode::Assign(id, vec![expr]))
+        Ok(ASTNode::Assign(id.lexeme, vec![expr]))
     }
 
     /// Parse an expression using Pratt parsing
@@ -322,30 +388,15 @@ impl<'a> Parser<'a> {
             || peek_types.contains(&TokenType::StarEqual)
             || peek_types.contains(&TokenType::SlashEqual)
     }
-
-    fn expect_token(&mut self, expected: TokenType, context: &str) -> ParseResult<()> {
-        let token = self.lexer.next();
-        if token.token_type != expected {
-            Err(ParseError::UnexpectedToken(
-                token.token_type,
-                format!("Expected {:?} {}", expected, context),
-            ))
-        } else {
-            Ok(())
-        }
-    }
 }
 
 /// Pratt parser for expressions
 fn expr_bp(lexer: &mut Lexer, min_bp: u8) -> ParseResult<ASTNode> {
-    let mut lhs = parse_prefix(lexer)?;
-
-    loop {
-        let op = match infix_op(lexer.peek().token_type) {
-            Some(op) => op,
-            None => break,
-        };
+    let start = lexer.peek().span.start;
+    let lhs = parse_prefix(lexer)?;
+    let mut lhs = spanned(lexer, start, lhs);
 
+    while let Some(op) = infix_op(lexer.peek().token_type) {
         if let Some((l_bp, r_bp)) = infix_binding_power(op) {
             if l_bp < min_bp {
                 break;
@@ -353,14 +404,15 @@ fn expr_bp(lexer: &mut Lexer, min_bp: u8) -> ParseResult<ASTNode> {
             lexer.next();
 
             let rhs = expr_bp(lexer, r_bp)?;
-            lhs = ASTNode::Op(op, vec![lhs, rhs]);
+            lhs = spanned(lexer, start, ASTNode::Op(op, vec![lhs, rhs]));
         } else if let Some((l_bp, ())) = postfix_binding_power(op) {
             if l_bp < min_bp {
                 break;
             }
             lexer.next();
 
-            lhs = parse_postfix(op, lhs, lexer)?;
+            let postfix = parse_postfix(op, lhs, lexer)?;
+            lhs = spanned(lexer, start, postfix);
         } else {
             break;
         }
@@ -375,6 +427,7 @@ fn parse_prefix(lexer: &mut Lexer) -> ParseResult<ASTNode> {
         return Err(ParseError::UnexpectedToken(
             TokenType::EOF,
             "Unexpected end of input".to_string(),
+            lexer.peek().span,
         ));
     }
 
@@ -384,15 +437,25 @@ fn parse_prefix(lexer: &mut Lexer) -> ParseResult<ASTNode> {
         TokenType::FloatNumber(n) => Ok(ASTNode::FloatNumber(n)),
         TokenType::Identifier => Ok(ASTNode::Identifier(token.lexeme)),
         TokenType::Boolean(b) => Ok(ASTNode::Boolean(b)),
-        TokenType::String => Ok(ASTNode::String(token.lexeme)),
+        TokenType::NIL => Ok(ASTNode::Nil),
+        TokenType::String => Ok(ASTNode::String(
+            token.lexeme[1..token.lexeme.len() - 1].to_string(),
+        )),
+        TokenType::LeftBracket => {
+            let mut elements = Vec::new();
+            while lexer.peek().token_type != TokenType::RightBracket {
+                elements.push(expr_bp(lexer, 0)?);
+                if lexer.peek().token_type != TokenType::COMMA {
+                    break;
+                }
+                lexer.next();
+            }
+            expect(lexer, TokenType::RightBracket, "to close list")?;
+            Ok(ASTNode::List(elements))
+        }
         TokenType::LeftParen => {
             let expr = expr_bp(lexer, 0)?;
-            if lexer.next().token_type != TokenType::RightParen {
-                return Err(ParseError::MissingToken(
-                    TokenType::RightParen,
-                    "to close parenthesized expression".to_string(),
-                ));
-            }
+            expect(lexer, TokenType::RightParen, "to close parenthesized expression")?;
             Ok(expr)
         }
         TokenType::PLUS | TokenType::MINUS | TokenType::BANG => {
@@ -400,19 +463,25 @@ fn parse_prefix(lexer: &mut Lexer) -> ParseResult<ASTNode> {
                 TokenType::MINUS => Ops::UnaryOp(UnaryOp::Negate),
                 TokenType::BANG => Ops::UnaryOp(UnaryOp::Not),
                 _ => {
-                    return Err(ParseError::InvalidOperator(format!(
-                        "Invalid prefix operator: {:?}",
-                        token.token_type
-                    )))
+                    return Err(ParseError::InvalidOperator(
+                        format!("Invalid prefix operator: {:?}", token.token_type),
+                        token.span,
+                    ))
                 }
             };
-            let ((), r_bp) = prefix_binding_power(op);
+            let Some(((), r_bp)) = prefix_binding_power(op) else {
+                return Err(ParseError::InvalidOperator(
+                    format!("Invalid prefix operator: {:?}", op),
+                    token.span,
+                ));
+            };
             let rhs = expr_bp(lexer, r_bp)?;
             Ok(ASTNode::Op(op, vec![rhs]))
         }
         _ => Err(ParseError::UnexpectedToken(
             token.token_type,
             "in prefix position".to_string(),
+            token.span,
         )),
     }
 }
@@ -422,39 +491,53 @@ fn parse_postfix(op: Ops, lhs: ASTNode, lexer: &mut Lexer) -> ParseResult<ASTNod
     match op {
         Ops::PostfixOp(PostfixOp::Index) => {
             let rhs = expr_bp(lexer, 0)?;
-            if lexer.next().token_type != TokenType::RightBracket {
-                return Err(ParseError::MissingToken(
-                    TokenType::RightBracket,
-                    "to close index operation".to_string(),
-                ));
-            }
+            expect(lexer, TokenType::RightBracket, "to close index operation")?;
             Ok(ASTNode::Op(op, vec![lhs, rhs]))
         }
         Ops::PostfixOp(PostfixOp::Call) => {
-            let callee = lexer.next().lexeme;
-            if lexer.next().token_type != TokenType::LeftParen {
-                return Err(ParseError::MissingToken(
-                    TokenType::LeftParen,
-                    "to start function call arguments".to_string(),
-                ));
+            let callee = expect(lexer, TokenType::Identifier, "to name the method")?.lexeme;
+            // properties like `a.grad` are calls without arguments
+            if lexer.peek().token_type != TokenType::LeftParen {
+                return Ok(ASTNode::Op(op, vec![lhs, ASTNode::Callee(callee, vec![])]));
             }
+            lexer.next();
             let args = parse_args(lexer)?;
-            if lexer.next().token_type != TokenType::RightParen {
-                return Err(ParseError::MissingToken(
-                    TokenType::RightParen,
-                    "to close function call arguments".to_string(),
-                ));
-            }
+            expect(lexer, TokenType::RightParen, "to close function call arguments")?;
             Ok(ASTNode::Op(op, vec![lhs, ASTNode::Callee(callee, args)]))
         }
+        Ops::PostfixOp(PostfixOp::Invoke) => {
+            let mut operands = vec![lhs];
+            operands.extend(parse_args(lexer)?);
+            expect(lexer, TokenType::RightParen, "to close function call arguments")?;
+            Ok(ASTNode::Op(op, operands))
+        }
         Ops::PostfixOp(PostfixOp::StarStar) => {
             let rhs = expr_bp(lexer, 0)?;
             Ok(ASTNode::Op(op, vec![lhs, rhs]))
         }
-        _ => Err(ParseError::InvalidOperator(format!(
-            "Invalid postfix operator: {:?}",
-            op
-        ))),
+        _ => Err(ParseError::InvalidOperator(
+            format!("Invalid postfix operator: {:?}", op),
+            lexer.previous_span(),
+        )),
+    }
+}
+
+/// Consumes the next token, which must be `expected`
+fn expect(lexer: &mut Lexer, expected: TokenType, context: &str) -> ParseResult<Token> {
+    let token = lexer.next();
+    if token.token_type != expected {
+        return Err(ParseError::MissingToken(expected, context.to_string(), token.span));
+    }
+    Ok(token)
+}
+
+/// Attaches the source from `start` up to the last token consumed to `node`
+fn spanned(lexer: &Lexer, start: usize, node: ASTNode) -> ASTNode {
+    let span = start..lexer.previous_span().end;
+    match node {
+        // e.g. a parenthesized expression, which keeps a single span including the parentheses
+        ASTNode::Spanned(_, node) => ASTNode::Spanned(span, node),
+        node => ASTNode::Spanned(span, Box::new(node)),
     }
 }
 
@@ -485,6 +568,7 @@ fn infix_op(token_type: TokenType) -> Option<Ops> {
         TokenType::GREATER => Some(Ops::BinaryOp(BinaryOp::Gt)),
         TokenType::GreaterEqual => Some(Ops::BinaryOp(BinaryOp::Ge)),
         TokenType::DOT => Some(Ops::PostfixOp(PostfixOp::Call)),
+        TokenType::LeftParen => Some(Ops::PostfixOp(PostfixOp::Invoke)),
         TokenType::LeftBracket => Some(Ops::PostfixOp(PostfixOp::Index)),
         TokenType::StarStar => Some(Ops::PostfixOp(PostfixOp::StarStar)),
         _ => None,
@@ -492,10 +576,10 @@ fn infix_op(token_type: TokenType) -> Option<Ops> {
 }
 
 /// Get the binding power for prefix operators
-fn prefix_binding_power(op: Ops) -> ((), u8) {
+fn prefix_binding_power(op: Ops) -> Option<((), u8)> {
     match op {
-        Ops::UnaryOp(UnaryOp::Not) | Ops::UnaryOp(UnaryOp::Negate) => ((), 15),
-        _ => panic!("Invalid prefix operator: {:?}", op),
+        Ops::UnaryOp(UnaryOp::Not) | Ops::UnaryOp(UnaryOp::Negate) => Some(((), 15)),
+        _ => None,
     }
 }
 
@@ -503,7 +587,7 @@ fn prefix_binding_power(op: Ops) -> ((), u8) {
 fn postfix_binding_power(op: Ops) -> Option<(u8, ())> {
     match op {
         Ops::PostfixOp(PostfixOp::Index) => Some((13, ())),
-        Ops::PostfixOp(PostfixOp::Call) => Some((14, ())),
+        Ops::PostfixOp(PostfixOp::Call) | Ops::PostfixOp(PostfixOp::Invoke) => Some((14, ())),
         Ops::PostfixOp(PostfixOp::StarStar) => Some((16, ())),
         _ => None,
     }
@@ -550,6 +634,7 @@ impl fmt::Display for Ops {
 
             Ops::PostfixOp(PostfixOp::Index) => write!(f, "["),
             Ops::PostfixOp(PostfixOp::Call) => write!(f, "."),
+            Ops::PostfixOp(PostfixOp::Invoke) => write!(f, "call"),
             Ops::PostfixOp(PostfixOp::StarStar) => write!(f, "**"),
         }
     }
@@ -563,7 +648,18 @@ impl fmt::Display for ASTNode {
             ASTNode::FloatNumber(i) => write!(f, "{}", i.to_string().blue()),
             ASTNode::Identifier(s) => write!(f, "{}", s.red()),
             ASTNode::Boolean(b) => write!(f, "{}", b.to_string().yellow()),
+            ASTNode::Nil => write!(f, "{}", "nil".yellow()),
             ASTNode::String(s) => write!(f, "{}", s.yellow()),
+            ASTNode::List(elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "]")
+            }
             ASTNode::Callee(callee, args) => {
                 write!(f, "({}", callee.purple().magenta())?;
                 for arg in args {
@@ -612,6 +708,13 @@ impl fmt::Display for ASTNode {
                 }
                 write!(f, "}}")
             }
+            ASTNode::NoGrad(body) => {
+                write!(f, "no_grad {{")?;
+                for stmt in body {
+                    write!(f, "{}", stmt)?;
+                }
+                write!(f, "}}")
+            }
             ASTNode::Op(head, rest) => {
                 write!(f, "({}", head)?;
                 for s in rest {
@@ -633,6 +736,11 @@ impl fmt::Display for ASTNode {
                 }
                 write!(f, "}}")
             }
+            ASTNode::Return(expr) => match expr.first() {
+                Some(value) => write!(f, "return {}", value),
+                None => write!(f, "return"),
+            },
+            ASTNode::Spanned(_, node) => write!(f, "{}", node),
         }
     }
 }
@@ -648,7 +756,14 @@ pub fn ast_to_ascii(node: &ASTNode, indent: usize) -> String {
         ASTNode::FloatNumber(n) => writeln!(result, "{}FloatNumber({})", indent_str, n).unwrap(),
         ASTNode::Identifier(s) => writeln!(result, "{}Identifier({})", indent_str, s).unwrap(),
         ASTNode::Boolean(b) => writeln!(result, "{}Boolean({})", indent_str, b).unwrap(),
+        ASTNode::Nil => writeln!(result, "{}Nil", indent_str).unwrap(),
         ASTNode::String(s) => writeln!(result, "{}String(\"{}\")", indent_str, s).unwrap(),
+        ASTNode::List(elements) => {
+            writeln!(result, "{}List", indent_str).unwrap();
+            for element in elements {
+                result.push_str(&ast_to_ascii(element, indent + 1));
+            }
+        }
         ASTNode::Op(op, args) => {
             writeln!(result, "{}Op({:?})", indent_str, op).unwrap();
             for arg in args {
@@ -701,6 +816,12 @@ pub fn ast_to_ascii(node: &ASTNode, indent: usize) -> String {
                 result.push_str(&ast_to_ascii(stmt, indent + 2));
             }
         }
+        ASTNode::NoGrad(body) => {
+            writeln!(result, "{}NoGrad", indent_str).unwrap();
+            for stmt in body {
+                result.push_str(&ast_to_ascii(stmt, indent + 1));
+            }
+        }
         ASTNode::Print(args) => {
             writeln!(result, "{}Print", indent_str).unwrap();
             for arg in args {
@@ -715,12 +836,19 @@ pub fn ast_to_ascii(node: &ASTNode, indent: usize) -> String {
                 result.push_str(&ast_to_ascii(stmt, indent + 2));
             }
         }
+        ASTNode::Return(value) => {
+            writeln!(result, "{}Return", indent_str).unwrap();
+            for v in value {
+                result.push_str(&ast_to_ascii(v, indent + 1));
+            }
+        }
         ASTNode::Block(statements) => {
             writeln!(result, "{}Block", indent_str).unwrap();
             for stmt in statements {
                 result.push_str(&ast_to_ascii(stmt, indent + 1));
             }
         }
+        ASTNode::Spanned(_, node) => result.push_str(&ast_to_ascii(node, indent)),
     }
 
     result
@@ -793,6 +921,18 @@ mod tests {
 
         let s = expr("--1");
         assert_eq!(s, "(- -1)");
+
+        let s = expr("a.grad + b.relu().grad");
+        assert_eq!(s, "(+ (. a (grad)) (. (. b (relu)) (grad)))");
+
+        let s = expr("[[1, 2], [3, -4]] @ [a, b.c()]");
+        assert_eq!(s, "(@ [[1 2] [3 -4]] [a (. b (c))])");
+
+        let s = expr("add(1, 2 * 3)");
+        assert_eq!(s, "(call add 1 (* 2 3))");
+
+        let s = expr("f()(x).relu()");
+        assert_eq!(s, "(. (call (call f) x) (relu))");
     }
 
     #[test]
@@ -824,5 +964,40 @@ mod tests {
         // function definition test
         let s = parse("fn add(a, b) { a + b; }");
         assert_eq!(s, "fn add(a, b) {(+ a b)}");
+
+        // return tests
+        let s = parse("fn add(a, b) { return a + b; }");
+        assert_eq!(s, "fn add(a, b) {return (+ a b)}");
+
+        let s = parse("fn noop() { return; }");
+        assert_eq!(s, "fn noop() {return}");
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        fn errors(source: &str) -> Vec<String> {
+            let mut lexer = Lexer::new(source.to_string());
+            let errors = Parser::new(&mut lexer).parse().unwrap_err();
+            errors.iter().map(|e| format!("{:?} {}", e.span(), e)).collect()
+        }
+
+        // tokens that can't start a statement are skipped up to the next one
+        assert_eq!(
+            errors(") ) ) let a = 1; let b = ;"),
+            [
+                "0..1 Unexpected token RightParen in prefix position",
+                "25..26 Unexpected token SEMICOLON in prefix position"
+            ]
+        );
+
+        // unfinished input stops at the end instead of looping on EOF
+        assert_eq!(
+            errors("fn f(a, "),
+            ["8..8 Missing token Identifier as a parameter name"]
+        );
+        assert_eq!(errors("{ let a = 1;"), ["12..12 Missing token RightBrace to close block"]);
+
+        // a method name has to be an identifier
+        assert_eq!(errors("let x = a.1;"), ["10..11 Missing token Identifier to name the method"]);
     }
 }
@@ -1,8 +1,13 @@
+use std::rc::Rc;
+
 use serde::{Deserialize, Serialize};
 
 /// This module contains the implementation of the Chunk struct and its methods.
 /// The Chunk struct is used to store the bytecode and the constants.
-use crate::value::ValueType;
+use crate::{
+    source::{Source, Span},
+    value::ValueType,
+};
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 #[repr(u8)]
@@ -17,6 +22,7 @@ pub enum OpCode {
     OpMultiply,
     OpDivide,
     OpPower,
+    OpMatmul,
 
     OpNot,
     OpEqualEqual,
@@ -37,7 +43,13 @@ pub enum OpCode {
     OpJump,
     OpLoop,
 
-    // OpCall,
+    OpCall,
+    OpCallMethod,
+
+    OpBuildList,
+
+    OpNoGrad,
+    OpEndNoGrad,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -46,11 +58,15 @@ pub enum VectorType {
     Code(OpCode),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Chunk {
     /// VectorType is either a index to the constants or an OpCode, see `VectorType` enum
     pub code: Vec<VectorType>,
     pub constants: Vec<ValueType>,
+
+    /// the part of `source` each entry of `code` was compiled from, for locating errors
+    pub spans: Vec<Span>,
+    pub source: Rc<Source>,
 }
 
 impl Chunk {
@@ -58,11 +74,14 @@ impl Chunk {
         Self {
             code: Vec::new(),
             constants: Vec::new(),
+            spans: Vec::new(),
+            source: Rc::default(),
         }
     }
 
-    pub fn write(&mut self, byte: VectorType) {
+    pub fn write(&mut self, byte: VectorType, span: Span) {
         self.code.push(byte);
+        self.spans.push(span);
     }
 
     pub fn add_constant(&mut self, value: ValueType) -> usize {
@@ -72,7 +91,7 @@ impl Chunk {
 }
 
 ////////////////////////
-/// Display impls
+// Display impls
 ////////////////////////
 
 impl std::fmt::Display for OpCode {
@@ -86,6 +105,7 @@ impl std::fmt::Display for OpCode {
             OpCode::OpMultiply => write!(f, "OP_MULTIPLY"),
             OpCode::OpDivide => write!(f, "OP_DIVIDE"),
             OpCode::OpPower => write!(f, "OP_POWER"),
+            OpCode::OpMatmul => write!(f, "OP_MATMUL"),
 
             OpCode::OpNil => write!(f, "OP_NIL"),
             OpCode::OpTrue => write!(f, "OP_TRUE"),
@@ -108,7 +128,13 @@ impl std::fmt::Display for OpCode {
             OpCode::OpJump => write!(f, "OP_JUMP"),
             OpCode::OpLoop => write!(f, "OP_LOOP"),
 
-            // OpCode::OpCall => write!(f, "OP_CALL"),
+            OpCode::OpCall => write!(f, "OP_CALL"),
+            OpCode::OpCallMethod => write!(f, "OP_CALL_METHOD"),
+
+            OpCode::OpBuildList => write!(f, "OP_BUILD_LIST"),
+
+            OpCode::OpNoGrad => write!(f, "OP_NO_GRAD"),
+            OpCode::OpEndNoGrad => write!(f, "OP_END_NO_GRAD"),
         }
     }
 }
@@ -1,10 +1,13 @@
+use std::rc::Rc;
+use thiserror::Error;
 
 use crate::{
     ast::{ASTNode, BinaryOp, Ops, PostfixOp, UnaryOp},
     chunk::{Chunk, OpCode, VectorType},
+    diagnostics::Diagnostic,
     interner::Interner,
-    tensor::Tensor,
-    value::ValueType,
+    source::{Source, Span},
+    value::{Function, ValueType},
 };
 
 #[derive(Debug, Clone, Default)]
@@ -20,39 +23,55 @@ impl std::fmt::Display for Local {
     }
 }
 
-#[derive(Debug, Clone)]
-struct Function {
-    name: String,
-    arity: u8,
-    chunk: Chunk,
+/// Whether the code being compiled is the top-level script or a function body
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionType {
+    Script,
+    Function,
 }
 
-impl Function {
-    pub fn new(name: String, arity: u8) -> Self {
-        Self {
-            name,
-            arity,
-            chunk: Chunk::new(),
-        }
-    }
+/// A program the compiler can't turn into bytecode, even though it parsed
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum CompileError {
+    #[error("Can't return from top-level code")]
+    ReturnOutsideFunction,
+
+    #[error("Too many local variables in function")]
+    TooManyLocals,
+
+    #[error("Can't have more than 255 parameters, got {0}")]
+    TooManyParameters(usize),
+
+    #[error("Duplicate parameter '{0}'")]
+    DuplicateParameter(String),
+
+    #[error("{0} is not supported yet")]
+    Unsupported(&'static str),
+
+    /// a syntax tree the parser doesn't build, e.g. one put together by hand
+    #[error("Malformed syntax tree: {0}")]
+    MalformedAst(&'static str),
 }
 
+type CompileResult<T> = Result<T, Diagnostic>;
+
 pub struct Compiler {
     chunk: Chunk,
+    function_type: FunctionType,
+
+    // span of the innermost node being compiled, recorded for each code entry written
+    span: Span,
     interner: Interner,
 
     locals: Vec<Local>,
     local_count: usize,
     scope_depth: u8,
-
-    functions: Vec<Function>,
-    function_count: usize,
 }
 
 // write a macro that can take single or multiple opcodes and write them to the chunk, (without mentioning self.chunk)
 macro_rules! write_op {
-    ($chunk:expr, $($op:expr),*) => {
-        $( $chunk.write(VectorType::Code($op)))*
+    ($compiler:expr, $($op:expr),*) => {
+        { $( $compiler.chunk.write(VectorType::Code($op), $compiler.span.clone()); )* }
     };
 }
 
@@ -63,196 +82,298 @@ macro_rules! add_con {
 }
 
 macro_rules! write_cons {
-    ($chunk:expr, $code:expr) => {
-        $chunk.write(VectorType::Constant($code))
+    ($compiler:expr, $code:expr) => {
+        $compiler.chunk.write(VectorType::Constant($code), $compiler.span.clone())
     };
 }
 
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Compiler {
     pub fn new() -> Self {
         Self {
             chunk: Chunk::new(),
+            function_type: FunctionType::Script,
+            span: 0..0,
             interner: Interner::default(),
             locals: Vec::new(),
             local_count: 0,
             scope_depth: 0,
-            functions: Vec::new(),
-            function_count: 0,
         }
     }
 
-    pub fn compile(&mut self, ast: Vec<ASTNode>) -> (Chunk, Interner) {
-        ast.iter().for_each(|stmt| self.visit(stmt.clone()));
+    /// Starts from an existing interner, so identifiers keep the indices a running VM knows them by
+    pub fn with_interner(interner: Interner) -> Self {
+        Self {
+            interner,
+            ..Self::new()
+        }
+    }
 
-        // add return
-        self.chunk.write(VectorType::Code(OpCode::OpReturn));
+    /// Records `source` in the compiled chunks, so errors can point back into it
+    pub fn with_source(mut self, source: Rc<Source>) -> Self {
+        self.chunk.source = source;
+        self
+    }
+
+    pub fn compile(&mut self, ast: Vec<ASTNode>) -> CompileResult<(Chunk, Interner)> {
+        self.compile_script(ast, false)
+    }
 
-        (self.chunk.clone(), self.interner.clone())
+    /// Like `compile`, but a trailing expression statement is returned by the script instead of being
+    /// discarded, so the REPL can show its value
+    pub fn compile_interactive(&mut self, ast: Vec<ASTNode>) -> CompileResult<(Chunk, Interner)> {
+        self.compile_script(ast, true)
     }
 
-    fn visit_function(&mut self, name: String, params: Vec<String>, body: Vec<ASTNode>) {
-        let function = Function::new(name.clone(), params.len() as u8);
-        self.functions.push(function);
-        self.function_count += 1;
+    fn compile_script(
+        &mut self,
+        mut ast: Vec<ASTNode>,
+        return_last: bool,
+    ) -> CompileResult<(Chunk, Interner)> {
+        let last = match ast.last() {
+            Some(node) if return_last && is_expression(node) => ast.pop(),
+            _ => None,
+        };
+        for stmt in ast {
+            self.visit_statement(stmt)?;
+        }
+
+        // otherwise the script implicitly returns nil, just like any other function
+        match last {
+            Some(expr) => self.visit(expr)?,
+            None => write_op!(self, OpCode::OpNil),
+        }
+        write_op!(self, OpCode::OpReturn);
+
+        Ok((self.chunk.clone(), self.interner.clone()))
+    }
 
-        // Compile function body
-        for stmt in body {
-            self.visit(stmt);
+    /// Compiles the function body into its own chunk, with the callee in slot 0 and the
+    /// parameters bound as locals in the following slots. Slot 0 is named after the function,
+    /// so the body can call itself even when the function is local to another one
+    fn visit_function(
+        &mut self,
+        name: String,
+        params: Vec<String>,
+        body: Vec<ASTNode>,
+    ) -> CompileResult<Function> {
+        if params.len() > u8::MAX as usize {
+            return Err(self.error(CompileError::TooManyParameters(params.len())));
         }
+        if let Some(i) = (1..params.len()).find(|&i| params[..i].contains(&params[i])) {
+            return Err(self.error(CompileError::DuplicateParameter(params[i].clone())));
+        }
+        let mut function = Function::new(name.clone(), params.len() as u8);
+
+        let enclosing_chunk = std::mem::take(&mut self.chunk);
+        self.chunk.source = Rc::clone(&enclosing_chunk.source);
+        let enclosing_type = std::mem::replace(&mut self.function_type, FunctionType::Function);
+        let enclosing_locals = std::mem::take(&mut self.locals);
+        let enclosing_local_count = std::mem::replace(&mut self.local_count, 0);
+        let enclosing_scope_depth = std::mem::replace(&mut self.scope_depth, 1);
+
+        let compiled = std::iter::once(name)
+            .chain(params)
+            .try_for_each(|name| self.add_local(name))
+            .and_then(|_| body.into_iter().try_for_each(|stmt| self.visit_statement(stmt)));
+
+        // implicit `nil` return if the body runs to completion
+        write_op!(self, OpCode::OpNil, OpCode::OpReturn);
+
+        function.chunk = std::mem::replace(&mut self.chunk, enclosing_chunk);
+        self.function_type = enclosing_type;
+        self.locals = enclosing_locals;
+        self.local_count = enclosing_local_count;
+        self.scope_depth = enclosing_scope_depth;
+
+        compiled.map(|_| function)
+    }
 
-        // Add return
-        self.chunk.write(VectorType::Code(OpCode::OpReturn));
+    /// Visits a node in statement position, discarding the value left behind by expressions
+    fn visit_statement(&mut self, node: ASTNode) -> CompileResult<()> {
+        let is_expression = is_expression(&node);
 
-        let function_idx = self.chunk.add_constant(ValueType::Function(name));
-        write_cons!(self.chunk, function_idx);
+        self.visit(node)?;
+
+        if is_expression {
+            write_op!(self, OpCode::OpPop);
+        }
+        Ok(())
     }
 
-    fn visit(&mut self, node: ASTNode) {
+    fn visit(&mut self, node: ASTNode) -> CompileResult<()> {
         match node {
             // ASTNode::Number(n) => {
-            //     write_op!(self.chunk, OpCode::OpConstant);
+            //     write_op!(self, OpCode::OpConstant);
             //     // add_con!(self.chunk, ValueType::Tensor(Tensor::from(n)));
             //     add_con!(self.chunk, ValueType::Float(n));
-            //     write_cons!(self.chunk, self.chunk.constants.len() - 1);
+            //     write_cons!(self, self.chunk.constants.len() - 1);
             // }
             ASTNode::IntNumber(n) => {
-                write_op!(self.chunk, OpCode::OpConstant);
+                write_op!(self, OpCode::OpConstant);
                 add_con!(self.chunk, ValueType::Integer(n));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
+                write_cons!(self, self.chunk.constants.len() - 1);
             }
             ASTNode::FloatNumber(n) => {
-                write_op!(self.chunk, OpCode::OpConstant);
+                write_op!(self, OpCode::OpConstant);
                 add_con!(self.chunk, ValueType::Float(n));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
+                write_cons!(self, self.chunk.constants.len() - 1);
             }
             ASTNode::Boolean(b) => {
-                write_op!(self.chunk, if b { OpCode::OpTrue } else { OpCode::OpFalse })
+                write_op!(self, if b { OpCode::OpTrue } else { OpCode::OpFalse })
             }
+            ASTNode::Nil => write_op!(self, OpCode::OpNil),
 
             ASTNode::String(s) => {
-                write_op!(self.chunk, OpCode::OpConstant);
+                write_op!(self, OpCode::OpConstant);
                 add_con!(
                     self.chunk,
                     ValueType::String(self.interner.intern_string(s))
                 );
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
+                write_cons!(self, self.chunk.constants.len() - 1);
             }
             ASTNode::Identifier(iden) => {
                 if let Some(local) = self.resolve_local(&iden) {
-                    write_op!(self.chunk, OpCode::OpGetLocal);
-                    write_cons!(self.chunk, local);
+                    write_op!(self, OpCode::OpGetLocal);
+                    write_cons!(self, local);
                 } else {
-                    write_op!(self.chunk, OpCode::OpGetGlobal);
+                    write_op!(self, OpCode::OpGetGlobal);
                     let global = self
                         .chunk
                         .add_constant(ValueType::Identifier(self.interner.intern_string(iden)));
-                    write_cons!(self.chunk, global);
+                    write_cons!(self, global);
+                }
+            }
+            ASTNode::Op(Ops::PostfixOp(PostfixOp::Invoke), vec) => {
+                let Some(arg_count) = vec.len().checked_sub(1) else {
+                    return Err(self.error(CompileError::MalformedAst("call without a callee")));
+                };
+                for node in vec {
+                    self.visit(node)?;
                 }
+
+                write_op!(self, OpCode::OpCall);
+                write_cons!(self, arg_count);
+            }
+            ASTNode::Op(Ops::PostfixOp(PostfixOp::Call), vec) => {
+                let mut operands = vec.into_iter();
+                let (receiver, callee) = (operands.next(), operands.next());
+                let (Some(receiver), Some(ASTNode::Callee(method, args))) = (receiver, callee) else {
+                    let error = "method call without a receiver or callee";
+                    return Err(self.error(CompileError::MalformedAst(error)));
+                };
+
+                self.visit(receiver)?;
+                let arg_count = args.len();
+                for arg in args {
+                    self.visit(arg)?;
+                }
+
+                let method = add_con!(
+                    self.chunk,
+                    ValueType::Identifier(self.interner.intern_string(method))
+                );
+                write_op!(self, OpCode::OpCallMethod);
+                write_cons!(self, method);
+                write_cons!(self, arg_count);
             }
             ASTNode::Op(op, vec) => {
                 for node in vec {
-                    self.visit(node);
+                    self.visit(node)?;
                 }
 
                 match op {
-                    Ops::BinaryOp(BinaryOp::Add) => write_op!(self.chunk, OpCode::OpAdd),
-                    Ops::BinaryOp(BinaryOp::Sub) => write_op!(self.chunk, OpCode::OpSubtract),
-                    Ops::BinaryOp(BinaryOp::Mul) => write_op!(self.chunk, OpCode::OpMultiply),
-                    // @ - dot product - TODO: need to implement
-                    Ops::BinaryOp(BinaryOp::At) => write_op!(self.chunk, OpCode::OpMultiply),
-                    Ops::BinaryOp(BinaryOp::Div) => write_op!(self.chunk, OpCode::OpDivide),
-                    Ops::BinaryOp(BinaryOp::Eq) => write_op!(self.chunk, OpCode::OpEqualEqual),
+                    Ops::BinaryOp(BinaryOp::Add) => write_op!(self, OpCode::OpAdd),
+                    Ops::BinaryOp(BinaryOp::Sub) => write_op!(self, OpCode::OpSubtract),
+                    Ops::BinaryOp(BinaryOp::Mul) => write_op!(self, OpCode::OpMultiply),
+                    Ops::BinaryOp(BinaryOp::At) => write_op!(self, OpCode::OpMatmul),
+                    Ops::BinaryOp(BinaryOp::Div) => write_op!(self, OpCode::OpDivide),
+                    Ops::BinaryOp(BinaryOp::Eq) => write_op!(self, OpCode::OpEqualEqual),
                     Ops::BinaryOp(BinaryOp::Ne) => {
-                        write_op!(self.chunk, OpCode::OpEqualEqual);
-                        write_op!(self.chunk, OpCode::OpNot);
+                        write_op!(self, OpCode::OpEqualEqual);
+                        write_op!(self, OpCode::OpNot);
                     }
-                    Ops::BinaryOp(BinaryOp::Lt) => write_op!(self.chunk, OpCode::OpLess),
+                    Ops::BinaryOp(BinaryOp::Lt) => write_op!(self, OpCode::OpLess),
                     Ops::BinaryOp(BinaryOp::Le) => {
-                        write_op!(self.chunk, OpCode::OpGreater);
-                        write_op!(self.chunk, OpCode::OpNot);
+                        write_op!(self, OpCode::OpGreater);
+                        write_op!(self, OpCode::OpNot);
                     }
                     Ops::BinaryOp(BinaryOp::Gt) => {
-                        write_op!(self.chunk, OpCode::OpGreater);
+                        write_op!(self, OpCode::OpGreater);
                     }
                     Ops::BinaryOp(BinaryOp::Ge) => {
-                        write_op!(self.chunk, OpCode::OpLess);
-                        write_op!(self.chunk, OpCode::OpNot);
+                        write_op!(self, OpCode::OpLess);
+                        write_op!(self, OpCode::OpNot);
                     }
                     Ops::UnaryOp(UnaryOp::Negate) => {
-                        write_op!(self.chunk, OpCode::OpNegate);
+                        write_op!(self, OpCode::OpNegate);
                     }
+                    Ops::UnaryOp(UnaryOp::Not) => write_op!(self, OpCode::OpNot),
 
                     Ops::PostfixOp(PostfixOp::StarStar) => {
-                        write_op!(self.chunk, OpCode::OpPower);
+                        write_op!(self, OpCode::OpPower);
                     }
-                    Ops::PostfixOp(PostfixOp::Call) => {
-                        panic!("Call not implemented");
-
-                        // write_op!(self.chunk, OpCode::OpCall);
-                        // self.chunk
-                        //     .write(VectorType::Constant(self.chunk.constants.len() - 1));
-                        // TODO: need for testing for this - a.relu(c.relu()), a.relu().relu()
+                    Ops::PostfixOp(PostfixOp::Call) | Ops::PostfixOp(PostfixOp::Invoke) => {
+                        return Err(self.error(CompileError::MalformedAst("misplaced call")));
+                    }
+                    Ops::PostfixOp(PostfixOp::Index) => {
+                        return Err(self.error(CompileError::Unsupported("Indexing")));
                     }
-                    Ops::UnaryOp(UnaryOp::Not) | Ops::PostfixOp(PostfixOp::Index) => todo!(),
                 }
             }
+            ASTNode::List(elements) => {
+                let len = elements.len();
+                for element in elements {
+                    self.visit(element)?;
+                }
+                write_op!(self, OpCode::OpBuildList);
+                write_cons!(self, len);
+            }
             ASTNode::Print(expr) => {
-                assert!(expr.len() == 1);
-                self.visit(expr[0].clone());
-                write_op!(self.chunk, OpCode::OpPrint);
+                let expr = self.single(expr)?;
+                self.visit(expr)?;
+                write_op!(self, OpCode::OpPrint);
             }
             ASTNode::Let(iden, expr) => {
-                assert!(expr.len() == 1);
-
-                if self.scope_depth > 0 {
-                    if self.local_count == 256 {
-                        panic!("Too many local variables.");
-                    }
-                    self.locals.push(Local {
-                        name: iden,
-                        depth: self.scope_depth,
-                    });
-                    self.local_count += 1;
-                    self.visit(expr[0].clone());
-                    return;
-                }
-
-                let global = add_con!(
-                    self.chunk,
-                    ValueType::Identifier(self.interner.intern_string(iden))
-                );
-                self.visit(expr[0].clone());
-                write_op!(self.chunk, OpCode::OpDefineGlobal);
-                write_cons!(self.chunk, global);
+                let expr = self.single(expr)?;
+                self.visit(expr)?;
+                self.define_variable(iden)?;
             }
             ASTNode::Assign(iden, expr) => {
-                assert!(expr.len() == 1);
-                self.visit(expr[0].clone());
+                let expr = self.single(expr)?;
+                self.visit(expr)?;
 
                 if let Some(local) = self.resolve_local(&iden) {
-                    write_op!(self.chunk, OpCode::OpSetLocal);
-                    write_cons!(self.chunk, local);
+                    write_op!(self, OpCode::OpSetLocal);
+                    write_cons!(self, local);
                 } else {
                     let global = add_con!(
                         self.chunk,
                         ValueType::Identifier(self.interner.intern_string(iden))
                     );
-                    write_op!(self.chunk, OpCode::OpSetGlobal);
-                    write_cons!(self.chunk, global);
+                    write_op!(self, OpCode::OpSetGlobal);
+                    write_cons!(self, global);
                 }
+                write_op!(self, OpCode::OpPop);
             }
             ASTNode::Block(stmts) => {
                 self.scope_depth += 1;
                 for stmt in stmts {
-                    self.visit(stmt);
+                    self.visit_statement(stmt)?;
                 }
                 self.scope_depth -= 1;
 
                 while self.local_count > 0
                     && self.locals[self.local_count - 1].depth > self.scope_depth
                 {
+                    self.locals.pop();
                     self.local_count -= 1;
-                    write_op!(self.chunk, OpCode::OpPop);
+                    write_op!(self, OpCode::OpPop);
                 }
             }
             ASTNode::Callee(iden, _) => {
@@ -260,38 +381,40 @@ impl Compiler {
                     self.chunk,
                     ValueType::Identifier(self.interner.intern_string(iden))
                 );
-                write_cons!(self.chunk, global);
+                write_cons!(self, global);
             }
             ASTNode::If(cond, then, els) => {
-                assert_eq!(cond.len(), 1);
-                self.visit(cond[0].clone());
+                let cond = self.single(cond)?;
+                self.visit(cond)?;
 
                 let else_jump_offset = self.chunk.code.len();
-                write_op!(self.chunk, OpCode::OpJumpIfFalse);
+                write_op!(self, OpCode::OpJumpIfFalse);
                 add_con!(self.chunk, ValueType::JumpOffset(else_jump_offset));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
+                write_cons!(self, self.chunk.constants.len() - 1);
                 let else_jump_const_idx = add_con!(self.chunk, ValueType::JumpOffset(0));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
-                write_op!(self.chunk, OpCode::OpPop);
+                write_cons!(self, self.chunk.constants.len() - 1);
+                write_op!(self, OpCode::OpPop);
 
-                then.iter().for_each(|stmt| {
-                    self.visit(stmt.clone())
-                });
+                for stmt in then {
+                    self.visit_statement(stmt)?;
+                }
 
                 let jump_to_end = self.chunk.code.len();
-                write_op!(self.chunk, OpCode::OpJump);
+                write_op!(self, OpCode::OpJump);
                 add_con!(self.chunk, ValueType::JumpOffset(jump_to_end));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
+                write_cons!(self, self.chunk.constants.len() - 1);
                 let jump_const_idx = add_con!(self.chunk, ValueType::JumpOffset(0));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
-                write_op!(self.chunk, OpCode::OpPop);
+                write_cons!(self, self.chunk.constants.len() - 1);
+                write_op!(self, OpCode::OpPop);
 
                 let else_offset = self.chunk.code.len();
                 self.chunk.constants[else_jump_const_idx] = ValueType::JumpOffset(else_offset - 1);
 
                 // Compile the "else" block if it exists
                 if let Some(els) = els {
-                    els.iter().for_each(|stmt| self.visit(stmt.clone()));
+                    for stmt in els {
+                        self.visit_statement(stmt)?;
+                    }
                 }
 
                 let end_offset = self.chunk.code.len();
@@ -300,42 +423,133 @@ impl Compiler {
             ASTNode::While(cond, body) => {
                 let loop_start = self.chunk.code.len();
 
-                assert_eq!(cond.len(), 1);
-                self.visit(cond[0].clone());
+                let cond = self.single(cond)?;
+                self.visit(cond)?;
 
                 let exit_jump_offset = self.chunk.code.len();
-                write_op!(self.chunk, OpCode::OpJumpIfFalse);
+                write_op!(self, OpCode::OpJumpIfFalse);
                 add_con!(self.chunk, ValueType::JumpOffset(exit_jump_offset));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
+                write_cons!(self, self.chunk.constants.len() - 1);
                 let exit_jump_const_idx = add_con!(self.chunk, ValueType::JumpOffset(0));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
-                write_op!(self.chunk, OpCode::OpPop);
+                write_cons!(self, self.chunk.constants.len() - 1);
+                write_op!(self, OpCode::OpPop);
 
-                body.iter().for_each(|stmt| self.visit(stmt.clone()));
+                for stmt in body {
+                    self.visit_statement(stmt)?;
+                }
 
                 let loop_jump_offset = self.chunk.code.len();
-                write_op!(self.chunk, OpCode::OpLoop);
+                write_op!(self, OpCode::OpLoop);
                 add_con!(self.chunk, ValueType::JumpOffset(loop_jump_offset));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
+                write_cons!(self, self.chunk.constants.len() - 1);
                 add_con!(self.chunk, ValueType::JumpOffset(loop_start));
-                write_cons!(self.chunk, self.chunk.constants.len() - 1);
-                write_op!(self.chunk, OpCode::OpPop);
+                write_cons!(self, self.chunk.constants.len() - 1);
+                write_op!(self, OpCode::OpPop);
 
                 let exit_offset = self.chunk.code.len();
                 self.chunk.constants[exit_jump_const_idx] = ValueType::JumpOffset(exit_offset - 1);
             }
+            ASTNode::NoGrad(body) => {
+                write_op!(self, OpCode::OpNoGrad);
+                for stmt in body {
+                    self.visit_statement(stmt)?;
+                }
+                write_op!(self, OpCode::OpEndNoGrad);
+            }
             ASTNode::Function(name, params, body) => {
-                self.visit_function(name, params, body);
+                let function = self.visit_function(name.clone(), params, body)?;
+
+                write_op!(self, OpCode::OpConstant);
+                add_con!(self.chunk, ValueType::Function(Rc::new(function)));
+                write_cons!(self, self.chunk.constants.len() - 1);
+                self.define_variable(name)?;
+            }
+            ASTNode::Spanned(span, node) => {
+                let enclosing_span = std::mem::replace(&mut self.span, span);
+                let compiled = self.visit(*node);
+                self.span = enclosing_span;
+                compiled?;
+            }
+            ASTNode::Return(expr) => {
+                if self.function_type == FunctionType::Script {
+                    return Err(self
+                        .error(CompileError::ReturnOutsideFunction)
+                        .with_help("`return` can only be used inside a function body"));
+                }
+
+                match expr.first() {
+                    Some(value) => self.visit(value.clone())?,
+                    None => write_op!(self, OpCode::OpNil),
+                }
+                write_op!(self, OpCode::OpReturn);
             }
         }
+
+        Ok(())
     }
 
-    fn resolve_local(&self, name: &String) -> Option<usize> {
-        for i in (0..self.local_count).rev() {
-            if self.locals[i].name == *name {
-                return Some(i);
-            }
+    /// An error at the node being compiled
+    fn error(&self, error: CompileError) -> Diagnostic {
+        let source = Rc::clone(&self.chunk.source);
+        Diagnostic::new(error, source).with_span(self.span.clone())
+    }
+
+    /// The one expression of a `print`, `let`, assignment or condition
+    fn single(&self, mut nodes: Vec<ASTNode>) -> CompileResult<ASTNode> {
+        match (nodes.pop(), nodes.is_empty()) {
+            (Some(node), true) => Ok(node),
+            _ => Err(self.error(CompileError::MalformedAst("expected a single expression"))),
+        }
+    }
+
+    /// Binds the value on top of the stack to `name`, as a local inside a scope or as a global otherwise
+    fn define_variable(&mut self, name: String) -> CompileResult<()> {
+        if self.scope_depth > 0 {
+            return self.add_local(name);
         }
-        None
+
+        let global = add_con!(
+            self.chunk,
+            ValueType::Identifier(self.interner.intern_string(name))
+        );
+        write_op!(self, OpCode::OpDefineGlobal);
+        write_cons!(self, global);
+        Ok(())
+    }
+
+    fn add_local(&mut self, name: String) -> CompileResult<()> {
+        if self.local_count == 256 {
+            return Err(self.error(CompileError::TooManyLocals));
+        }
+        self.locals.push(Local {
+            name,
+            depth: self.scope_depth,
+        });
+        self.local_count += 1;
+        Ok(())
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<usize> {
+        (0..self.local_count)
+            .rev()
+            .find(|&i| self.locals[i].name == name)
+    }
+}
+
+/// Whether `node` leaves a value on the stack
+fn is_expression(node: &ASTNode) -> bool {
+    match node {
+        ASTNode::Spanned(_, node) => is_expression(node),
+        node => matches!(
+            node,
+            ASTNode::IntNumber(_)
+                | ASTNode::FloatNumber(_)
+                | ASTNode::Identifier(_)
+                | ASTNode::Boolean(_)
+                | ASTNode::Nil
+                | ASTNode::String(_)
+                | ASTNode::List(_)
+                | ASTNode::Op(..)
+        ),
     }
 }
@@ -34,6 +34,17 @@ impl Debug {
         }
 
         output.push(self.format_footer());
+
+        // functions are compiled into their own chunks, disassemble them after this one
+        for constant in self.chunk.constants.iter() {
+            if let ValueType::Function(function) = constant {
+                let mut debug =
+                    Debug::new(&function.name, function.chunk.clone(), self.interner.clone());
+                debug.set_color_usage(self.use_colors);
+                output.push(debug.disassemble());
+            }
+        }
+
         output.join("\n")
     }
 
@@ -49,9 +60,15 @@ impl Debug {
             chunk::VectorType::Code(op) if op.uses_constant() => {
                 self.format_constant_instruction(offset, op)
             },
+            chunk::VectorType::Code(op) if op.uses_byte() => {
+                self.format_byte_instruction(offset, op)
+            },
             chunk::VectorType::Code(op) if op.is_jump() => {
                 self.format_jump_instruction(offset, op)
             },
+            chunk::VectorType::Code(chunk::OpCode::OpCallMethod) => {
+                self.format_method_instruction(offset)
+            },
             chunk::VectorType::Constant(_) => {
                 (offset + 1, "Unexpected constant in code vector".to_string())
             },
@@ -69,7 +86,6 @@ impl Debug {
             .ok_or_else(|| "Invalid constant index".to_string())
             .unwrap();
 
-        let constant = &self.chunk.constants[constant_idx];
         let constant_str = self.format_constant(constant_idx);
         
         (offset + 2, format!("{} {} {} | {}",
@@ -79,6 +95,32 @@ impl Debug {
             self.colorize_constant_str(&constant_str)))
     }
 
+    fn format_byte_instruction(&self, offset: usize, op: &chunk::OpCode) -> (usize, String) {
+        let operand = match self.chunk.code.get(offset + 1) {
+            Some(chunk::VectorType::Constant(operand)) => *operand,
+            _ => return (offset + 2, "Invalid operand".to_string()),
+        };
+
+        (offset + 2, format!("{} {} {}",
+            self.colorize_offset(offset),
+            self.colorize_op(op),
+            self.colorize_constant_idx(operand)))
+    }
+
+    fn format_method_instruction(&self, offset: usize) -> (usize, String) {
+        let (constant_idx, arg_count) = match (self.chunk.code.get(offset + 1), self.chunk.code.get(offset + 2)) {
+            (Some(chunk::VectorType::Constant(idx)), Some(chunk::VectorType::Constant(count))) => (*idx, *count),
+            _ => return (offset + 3, "Invalid method call".to_string()),
+        };
+
+        (offset + 3, format!("{} {} {} | {}({})",
+            self.colorize_offset(offset),
+            self.colorize_op(&chunk::OpCode::OpCallMethod),
+            self.colorize_constant_idx(constant_idx),
+            self.colorize_constant_str(&self.format_constant(constant_idx)),
+            arg_count))
+    }
+
     fn format_jump_instruction(&self, offset: usize, op: &chunk::OpCode) -> (usize, String) {
         let current_loc = self.get_constant_value(offset + 1);
         let jump_offset = self.get_constant_value(offset + 2);
@@ -184,6 +226,7 @@ impl Debug {
 trait OpCodeExt {
     fn is_simple(&self) -> bool;
     fn uses_constant(&self) -> bool;
+    fn uses_byte(&self) -> bool;
     fn is_jump(&self) -> bool;
 }
 
@@ -192,10 +235,10 @@ impl OpCodeExt for chunk::OpCode {
         matches!(self, 
             chunk::OpCode::OpReturn | chunk::OpCode::OpNegate | chunk::OpCode::OpAdd |
             chunk::OpCode::OpSubtract | chunk::OpCode::OpMultiply | chunk::OpCode::OpDivide |
-            chunk::OpCode::OpPower | chunk::OpCode::OpNil | chunk::OpCode::OpTrue |
+            chunk::OpCode::OpPower | chunk::OpCode::OpMatmul | chunk::OpCode::OpNil | chunk::OpCode::OpTrue |
             chunk::OpCode::OpFalse | chunk::OpCode::OpNot | chunk::OpCode::OpEqualEqual |
             chunk::OpCode::OpGreater | chunk::OpCode::OpLess | chunk::OpCode::OpPrint |
-            chunk::OpCode::OpPop
+            chunk::OpCode::OpPop | chunk::OpCode::OpNoGrad | chunk::OpCode::OpEndNoGrad
         )
     }
 
@@ -203,8 +246,15 @@ impl OpCodeExt for chunk::OpCode {
         matches!(self,
             chunk::OpCode::OpConstant | chunk::OpCode::OpDefineGlobal |
             chunk::OpCode::OpGetGlobal | chunk::OpCode::OpSetGlobal |
-            chunk::OpCode::OpDefineLocal | chunk::OpCode::OpGetLocal |
-            chunk::OpCode::OpSetLocal
+            chunk::OpCode::OpDefineLocal
+        )
+    }
+
+    /// Instructions whose operand is a raw value (stack slot, argument count) rather than a constant index
+    fn uses_byte(&self) -> bool {
+        matches!(self,
+            chunk::OpCode::OpGetLocal | chunk::OpCode::OpSetLocal | chunk::OpCode::OpCall |
+            chunk::OpCode::OpBuildList
         )
     }
 
@@ -0,0 +1,233 @@
+//! Errors reported to the user, rendered rustc-style: the error code and message, the line of source the
+//! error points at with its span underlined, and help notes.
+//!
+//! ```text
+//! error[E0200]: Undefined variable 'missing'
+//!  --> script.grad:3:16
+//!   |
+//! 3 |     return x + missing;
+//!   |                ^^^^^^^
+//! ```
+
+use std::rc::Rc;
+
+use colored::*;
+use thiserror::Error;
+
+use crate::{
+    ast::ParseError,
+    compiler::CompileError,
+    source::{Source, Span},
+    vm::RuntimeError,
+};
+
+// error codes, parse errors start at E0001, compile errors at E0100 and runtime errors at E0200
+pub const UNEXPECTED_TOKEN: &str = "E0001";
+pub const MISSING_TOKEN: &str = "E0002";
+pub const INVALID_OPERATOR: &str = "E0003";
+pub const SYNTAX_ERROR: &str = "E0004";
+pub const COMPILE_ERROR: &str = "E0100";
+pub const RUNTIME_ERROR: &str = "E0200";
+pub const TYPE_ERROR: &str = "E0201";
+pub const ARITY_ERROR: &str = "E0202";
+pub const SHAPE_ERROR: &str = "E0203";
+pub const INVALID_ARGUMENT: &str = "E0204";
+
+/// What went wrong, hosts can match on it rather than on the message
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ErrorKind {
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error(transparent)]
+    Compile(#[from] CompileError),
+    #[error(transparent)]
+    Runtime(#[from] RuntimeError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub kind: Box<ErrorKind>,
+
+    // where the error happened in `source`, if it points at any code
+    pub span: Option<Span>,
+    pub source: Rc<Source>,
+
+    pub help: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(kind: impl Into<ErrorKind>, source: Rc<Source>) -> Self {
+        Self {
+            kind: Box::new(kind.into()),
+            span: None,
+            source,
+            help: Vec::new(),
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help.push(help.into());
+        self
+    }
+
+    pub fn from_parse_error(error: &ParseError, source: Rc<Source>) -> Self {
+        Diagnostic::new(error.clone(), source).with_span(error.span().clone())
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self.kind.as_ref() {
+            ErrorKind::Parse(ParseError::UnexpectedToken(..)) => UNEXPECTED_TOKEN,
+            ErrorKind::Parse(ParseError::MissingToken(..)) => MISSING_TOKEN,
+            ErrorKind::Parse(ParseError::InvalidOperator(..)) => INVALID_OPERATOR,
+            ErrorKind::Parse(ParseError::SyntaxError(..)) => SYNTAX_ERROR,
+            ErrorKind::Compile(_) => COMPILE_ERROR,
+            ErrorKind::Runtime(error) => match error {
+                RuntimeError::InvalidOperands { .. }
+                | RuntimeError::InvalidOperand { .. }
+                | RuntimeError::NotCallable(_)
+                | RuntimeError::TypeMismatch(_) => TYPE_ERROR,
+                RuntimeError::ArityMismatch { .. } | RuntimeError::MethodArityMismatch { .. } => {
+                    ARITY_ERROR
+                }
+                RuntimeError::ShapeMismatch(_) => SHAPE_ERROR,
+                RuntimeError::InvalidArgument(_) | RuntimeError::DivisionByZero => INVALID_ARGUMENT,
+                _ => RUNTIME_ERROR,
+            },
+        }
+    }
+
+    /// The full report, with ANSI colors for a terminal if `color` is set
+    pub fn render(&self, color: bool) -> String {
+        let paint = |text: &str, style: fn(ColoredString) -> ColoredString| {
+            if color {
+                style(text.normal()).to_string()
+            } else {
+                text.to_string()
+            }
+        };
+        let bar = |text: &str| paint(text, |s| s.blue().bold());
+
+        let mut out = paint(&format!("error[{}]", self.code()), |s| s.red().bold());
+        out.push_str(&paint(&format!(": {}", self.kind), |s| s.bold()));
+
+        let mut gutter = String::new();
+        if let Some(span) = &self.span {
+            let (line, column) = self.source.line_column(span.start);
+            let text = self.source.line(line);
+            gutter = " ".repeat(line.to_string().len());
+
+            // tabs are kept so the carets line up with the code above them
+            let padding: String = text
+                .chars()
+                .take(column - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            // a span running over several lines is underlined up to the end of its first one
+            let spanned = self.source.text.get(span.clone()).unwrap_or_default();
+            let width = spanned.chars().take_while(|&c| c != '\n').count();
+            let carets = paint(&"^".repeat(width.max(1)), |s| s.red().bold());
+
+            out.push_str(&format!("\n{}{} {}", gutter, bar("-->"), self.source.locate(span)));
+            out.push_str(&format!("\n{} {}", gutter, bar("|")));
+            out.push_str(&format!("\n{} {}", bar(&format!("{} |", line)), text));
+            out.push_str(&format!("\n{} {} {}{}", gutter, bar("|"), padding, carets));
+        }
+        for help in &self.help {
+            let note = paint("help:", |s| s.bold());
+            out.push_str(&format!("\n{} {} {} {}", gutter, bar("="), note, help));
+        }
+        out
+    }
+}
+
+/// Diagnostics for all the syntax errors `Parser::parse` found in `source`
+pub fn from_parse_errors(errors: &[ParseError], source: Rc<Source>) -> Vec<Diagnostic> {
+    let diagnostic = |e| Diagnostic::from_parse_error(e, Rc::clone(&source));
+    errors.iter().map(diagnostic).collect()
+}
+
+/// Full reports of several errors, e.g. all the syntax errors of a script, separated by blank lines
+pub fn render_all(diagnostics: &[Diagnostic], color: bool) -> String {
+    let reports: Vec<String> = diagnostics.iter().map(|d| d.render(color)).collect();
+    reports.join("\n\n")
+}
+
+/// The errors on a line each
+pub fn summarize(diagnostics: &[Diagnostic]) -> String {
+    let lines: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+    lines.join("\n")
+}
+
+/// The error on a single line, `name:line:column: message`
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.span {
+            Some(span) => write!(f, "{}: {}", self.source.locate(span), self.kind),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{scanner::TokenType, tensor::TensorError};
+
+    #[test]
+    fn test_render() {
+        let text = "let a = 1;\nfn f(x) {\n\treturn x + missing;\n}";
+        let source = Rc::new(Source::new("main.grad", text));
+        let error = RuntimeError::UndefinedVariable("missing".to_string());
+        let diagnostic = Diagnostic::new(error, source.clone())
+            .with_span(33..40)
+            .with_help("declare it with `let` first");
+
+        assert_eq!(diagnostic.to_string(), "main.grad:3:13: Undefined variable 'missing'");
+        assert_eq!(
+            diagnostic.render(false),
+            "\
+error[E0200]: Undefined variable 'missing'
+ --> main.grad:3:13
+  |
+3 | \treturn x + missing;
+  | \t           ^^^^^^^
+  = help: declare it with `let` first"
+        );
+
+        // an error at the end of the input still gets a caret
+        let context = "to close block".to_string();
+        let error = ParseError::MissingToken(TokenType::RightBrace, context, 43..43);
+        let diagnostic = Diagnostic::from_parse_error(&error, source);
+        assert_eq!(diagnostic.code(), MISSING_TOKEN);
+        assert!(diagnostic.render(false).ends_with("4 | }\n  |  ^"));
+
+        let diagnostic = Diagnostic::new(CompileError::TooManyLocals, Rc::default());
+        assert_eq!(diagnostic.to_string(), "Too many local variables in function");
+        assert_eq!(
+            diagnostic.render(false),
+            "error[E0100]: Too many local variables in function"
+        );
+
+        // runtime errors of the main families have their own codes
+        let code = |error: RuntimeError| Diagnostic::new(error, Rc::default()).code();
+        assert_eq!(code(RuntimeError::StackOverflow), RUNTIME_ERROR);
+        assert_eq!(code(RuntimeError::NotCallable("int".to_string())), TYPE_ERROR);
+        let error = RuntimeError::ArityMismatch {
+            expected: "1".to_string(),
+            got: 2,
+            name: "f".to_string(),
+        };
+        assert_eq!(code(error), ARITY_ERROR);
+        let error = TensorError::Shape("Can't broadcast shapes [2] and [3]".to_string());
+        assert_eq!(code(error.into()), SHAPE_ERROR);
+        let error = TensorError::InvalidArgument("clamp expects min <= max".to_string());
+        assert_eq!(code(error.into()), INVALID_ARGUMENT);
+    }
+}
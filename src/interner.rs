@@ -25,6 +25,6 @@ impl Interner {
     }
 
     pub fn lookup(&self, idx: StringObjIdx) -> &str {
-        self.vec[idx as usize].as_str()
+        self.vec[idx].as_str()
     }
 }
@@ -2,32 +2,53 @@ pub mod ast;
 pub mod chunk;
 pub mod compiler;
 pub mod debug;
+pub mod diagnostics;
 pub mod interner;
+pub mod methods;
+pub mod natives;
+pub mod nn;
+pub mod optim;
 pub mod scanner;
+pub mod source;
 pub mod tensor;
+pub mod transforms;
 pub mod value;
 pub mod vm;
 
 use crate::vm::Result::{CompileErr, Ok, RuntimeErr};
-use crate::{ast::Parser, scanner::Lexer};
+use std::rc::Rc;
 
-use ast::{ast_to_ascii, ASTNode};
+use crate::{
+    ast::Parser,
+    diagnostics::{from_parse_errors, render_all},
+    scanner::Lexer,
+    source::Source,
+};
+
+use ast::ast_to_ascii;
 use wasm_bindgen::prelude::*;
 
 
 /// `wasm-pack build -t web`
 #[wasm_bindgen]
 pub fn run_source(src: &str) -> Vec<String> {
+    let source = Rc::new(Source::new("playground", src));
     let mut lexer = Lexer::new(src.to_string());
 
-    let out = Parser::new(&mut lexer).parse().unwrap();
+    let out = match Parser::new(&mut lexer).parse() {
+        std::result::Result::Ok(out) => out,
+        Err(errors) => return vec![render_all(&from_parse_errors(&errors, source), false)],
+    };
     // for stmt in out.iter() {
     //     println!("{:?}", stmt);
     // }
     // println!("-------------");
 
-    let mut compiler = compiler::Compiler::new();
-    let (bytecode, interner) = compiler.compile(out.clone());
+    let mut compiler = compiler::Compiler::new().with_source(source);
+    let (bytecode, interner) = match compiler.compile(out.clone()) {
+        std::result::Result::Ok(compiled) => compiled,
+        Err(e) => return vec![e.render(false)],
+    };
     // println!("{:?}", bytecode);
 
     let debug = debug::Debug::new("test", bytecode.clone(), interner.clone());
@@ -52,10 +73,8 @@ pub fn run_source(src: &str) -> Vec<String> {
 
             vec![result, disassemble_output, ast_output]
         }
-        // CompileErr(e) => format!("CompileError({:?})", e),
-        CompileErr(e) => vec![format!("CompileError({:?})", e), disassemble_output],
-        // RuntimeErr(e) => format!("RuntimeError({:?})", e),
-        RuntimeErr(e) => vec![format!("RuntimeError({:?})", e), disassemble_output],
+        CompileErr(errors) => vec![render_all(&errors, false), disassemble_output],
+        RuntimeErr(e) => vec![e.render(false), disassemble_output],
     }
 }
 
@@ -1,17 +1,16 @@
-mod ast;
-mod chunk;
-mod compiler;
-mod debug;
-mod interner;
-mod scanner;
-mod tensor;
-mod value;
-mod vm;
-
-use crate::{ast::Parser, scanner::Lexer};
-use ast::ast_to_ascii;
+use std::{io::IsTerminal, rc::Rc};
+
 use clap::Parser as ClapParser;
-use vm::Result;
+use grad::{
+    ast::{ast_to_ascii, Parser},
+    compiler, debug,
+    diagnostics::{self, from_parse_errors},
+    scanner::Lexer,
+    source::Source,
+    vm::{self, Result},
+};
+
+mod repl;
 
 #[derive(ClapParser, Debug)]
 #[command(version, about, long_about = None)]
@@ -22,6 +21,10 @@ struct Args {
 
     #[clap(short, long)]
     debug: bool,
+
+    /// Write the graphs drawn with `draw_dot` to this file as Graphviz DOT
+    #[clap(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    dot: Option<String>,
 }
 
 fn main() {
@@ -29,24 +32,47 @@ fn main() {
 
     // Check if args.script is provided
     if args.script.is_empty() {
-        // run as a repl
-        // run_repl();
-
-        panic!("REPL is not supported yet.");
+        repl::run();
     } else {
         // read file
 
         let src = match std::fs::read_to_string(&args.script) {
             Ok(source) => source,
-            Err(e) => panic!("Error reading file: {}", e),
+            Err(e) => {
+                eprintln!("Error reading {}: {}", args.script, e);
+                std::process::exit(1);
+            }
         };
 
-        run_source(&src, args.debug);
+        let source = Source::new(args.script.as_str(), src);
+        let result = run_source_with(source, args.debug, |vm| {
+            if let Some(path) = &args.dot {
+                write_graphs(vm, path);
+            }
+        });
+
+        match result {
+            Result::Ok(_) => {}
+            Result::CompileErr(errors) => {
+                eprintln!("{}", diagnostics::render_all(&errors, std::io::stderr().is_terminal()));
+                std::process::exit(1);
+            }
+            Result::RuntimeErr(e) => {
+                eprintln!("{}", e.render(std::io::stderr().is_terminal()));
+                std::process::exit(1);
+            }
+        }
     }
 }
 
 pub fn run_source(src: &str, debug: bool) -> Result {
-    let mut lexer = Lexer::new(src.to_string());
+    run_source_with(Source::new("script", src), debug, |_| {})
+}
+
+/// Runs `source`, then hands the VM to `inspect` once the script has finished, even on a runtime error
+fn run_source_with(source: Source, debug: bool, inspect: impl FnOnce(&vm::VM)) -> Result {
+    let source = Rc::new(source);
+    let mut lexer = Lexer::new(source.text.clone());
 
     if debug {
         println!("============= Tokens =============");
@@ -55,7 +81,10 @@ pub fn run_source(src: &str, debug: bool) -> Result {
         }
     };
 
-    let out = Parser::new(&mut lexer).parse().unwrap();
+    let out = match Parser::new(&mut lexer).parse() {
+        Ok(out) => out,
+        Err(errors) => return Result::CompileErr(from_parse_errors(&errors, source)),
+    };
 
     if debug {
         println!("============= AST =============");
@@ -66,8 +95,11 @@ pub fn run_source(src: &str, debug: bool) -> Result {
         println!("{}", ast_output);
     }
 
-    let mut compiler = compiler::Compiler::new();
-    let (bytecode, interner) = compiler.compile(out);
+    let mut compiler = compiler::Compiler::new().with_source(source);
+    let (bytecode, interner) = match compiler.compile(out) {
+        Ok(compiled) => compiled,
+        Err(e) => return Result::CompileErr(vec![e]),
+    };
 
     if debug {
         println!("============= Bytecode =============");
@@ -82,13 +114,30 @@ pub fn run_source(src: &str, debug: bool) -> Result {
 
     let mut vm = vm::VM::init(bytecode, interner);
     let result = vm.run();
+    inspect(&vm);
+    result
+}
 
-    return result;
+fn write_graphs(vm: &vm::VM, path: &str) {
+    if vm.graphs().is_empty() {
+        eprintln!("No graph was drawn, call draw_dot(tensor) to export one");
+        return;
+    }
+
+    let dot = vm.graphs().iter().map(|t| t.to_dot()).collect::<Vec<_>>().join("\n");
+    if let Err(e) = std::fs::write(path, dot + "\n") {
+        eprintln!("Error writing {}: {}", path, e);
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{run_source, tensor::Tensor, value::ValueType, vm::Result};
+    use crate::{run_source, run_source_with};
+    use grad::{
+        diagnostics::{self, ErrorKind},
+        source::Source,
+        vm::{Result, RuntimeError},
+    };
 
     #[test]
     fn test_micrograd_example() {
@@ -106,49 +155,960 @@ mod tests {
                         let g = f / 2.0;
                         g += 10.0 / f;        
                         print(g) // prints 24.7041, the outcome of this forward pass
+                        g.backward()
+                        print(a.grad) // prints 138.8338, i.e. the numerical value of dg/da
+                        print(b.grad) // prints 645.5773, i.e. the numerical value of dg/db
                         "#;
 
-        let out = run_source(&src, false);
-
-        // assert_eq!(
-        //     out,
-        //     Result::Ok(vec![ValueType::Tensor(Tensor::from(24.70408163265306))])
-        // );
-    }
-
-    // #[test]
-    // fn test_scopes() {
-    //     let src = r#"
-    //     let a = 4;
-    //     {
-    //         let b = 5;
-    //         print(b);
-    //         {
-    //             let c = 10;
-    //             print(c);
-    //             let b = 353;
-    //             print(b);
-    //         }
-    //         print(b);
-    //         b = 11;
-    //         print(b);
-    //         a = 12;
-    //     }
-    //     print(a);
-    //     "#;
-
-    //     let out = run_source(&src, false);
-
-    //     assert_eq!(
-    //         out,
-    //         Result::Ok(vec![
-    //             ValueType::Tensor(Tensor::from(5.0)),
-    //             ValueType::Tensor(Tensor::from(10.0)),
-    //             ValueType::Tensor(Tensor::from(353.0)),
-    //             ValueType::Tensor(Tensor::from(5.0)),
-    //             ValueType::Tensor(Tensor::from(11.0)),
-    //             ValueType::Tensor(Tensor::from(12.0))
-    //         ])
-    //     );
-    // }
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "24.70408163265306".to_string(),
+                "138.83381924198252".to_string(),
+                "645.5772594752186".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_scopes() {
+        let src = r#"
+        let a = 4;
+        {
+            let b = 5;
+            print(b);
+            {
+                let c = 10;
+                print(c);
+                let b = 353;
+                print(b);
+            }
+            print(b);
+            b = 11;
+            print(b);
+            a = 12;
+        }
+        print(a);
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "5".to_string(),
+                "10".to_string(),
+                "353".to_string(),
+                "5".to_string(),
+                "11".to_string(),
+                "12".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_error_locations() {
+        let src = "let a = 1;\nfn f(x) {\n    return x + missing;\n}\nprint(f(a));";
+        assert_eq!(
+            run_source(src, false).to_string(),
+            "Runtime error : script:3:16: Undefined variable 'missing'"
+        );
+
+        let out = run_source("let a = 1;\nlet b = [1, 2;", false);
+        assert_eq!(
+            out.to_string(),
+            "Compile error : script:2:14: Missing token RightBracket to close list"
+        );
+
+        let out = run_source("let a = (1 + 2", false);
+        assert_eq!(
+            out.to_string(),
+            "Compile error : script:1:15: Missing token RightParen to close parenthesized expression"
+        );
+
+        let Result::CompileErr(errors) = run_source("fn f() {}\nlet a = 1; return a;", false) else {
+            panic!("expected a compile error");
+        };
+        assert_eq!(
+            diagnostics::render_all(&errors, false),
+            "\
+error[E0100]: Can't return from top-level code
+ --> script:2:12
+  |
+2 | let a = 1; return a;
+  |            ^^^^^^^^
+  = help: `return` can only be used inside a function body"
+        );
+
+        // runtime errors render with the code of their family
+        let codes = [
+            ("print(1 + nil);", "E0201"),
+            ("fn f(x) { return x; } f(1, 2);", "E0202"),
+            ("tensor([1.0, 2.0]) + tensor([1.0, 2.0, 3.0]);", "E0203"),
+            ("tensor([1.0]).clamp(2, 1);", "E0204"),
+        ];
+        for (src, code) in codes {
+            let Result::RuntimeErr(error) = run_source(src, false) else {
+                panic!("expected a runtime error for {}", src);
+            };
+            assert_eq!(error.code(), code, "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        // every statement with a typo is reported, the ones in between still parse
+        let src = r#"
+        let a = 1 +;
+        let b = 2;
+        fn add(a b) { return a + b; }
+        {
+            print(b;
+            let c = 3;
+        }
+        print(a)
+        "#;
+
+        let out = run_source(src, false);
+        assert_eq!(
+            out.to_string(),
+            "Compile error : \
+script:2:20: Unexpected token SEMICOLON in prefix position
+script:4:18: Missing token RightParen to close function parameters
+script:6:20: Missing token RightParen to close print statement"
+        );
+
+        let out = run_source("fn f(x) {\n  return x;\n", false);
+        assert_eq!(
+            out.to_string(),
+            "Compile error : script:3:1: Missing token RightBrace to close block"
+        );
+
+        let Result::CompileErr(errors) = run_source("let = 1;\n}\nlet b = 2", false) else {
+            panic!("expected a compile error");
+        };
+        assert_eq!(
+            diagnostics::render_all(&errors, false),
+            "\
+error[E0002]: Missing token Identifier to name the variable
+ --> script:1:5
+  |
+1 | let = 1;
+  |     ^
+
+error[E0001]: Unexpected token RightBrace in prefix position
+ --> script:2:1
+  |
+2 | }
+  | ^"
+        );
+    }
+
+    #[test]
+    fn test_invalid_programs() {
+        // bad programs give errors instead of bringing the host down
+        let errors = [
+            (
+                "\"a\" - 1;",
+                "Runtime error : script:1:1: Operands of '-' must be numbers. Got: 'string' and 'int'",
+            ),
+            (
+                "1 + \"a\";",
+                "Runtime error : script:1:1: Operands of '+' must be numbers. Got: 'int' and 'string'",
+            ),
+            ("-true;", "Runtime error : script:1:1: Operand of '-' must be a number. Got: 'bool'"),
+            ("!1;", "Runtime error : script:1:1: Operand of '!' must be a boolean. Got: 'int'"),
+            ("1 / 0;", "Runtime error : script:1:1: Division by zero"),
+            ("9223372036854775807 + 1;", "Runtime error : script:1:1: Integer overflow in '+'"),
+            (
+                "tensor([1, 2, 3]) * tensor([1, 2]);",
+                "Runtime error : script:1:1: Can't broadcast shapes [3] and [2]",
+            ),
+            ("fn f() { return f(); } f();", "Runtime error : script:1:17: Stack overflow"),
+            ("let a = [1, 2]; a[0];", "Compile error : script:1:17: Indexing is not supported yet"),
+            (
+                "zeros([1000000000000]);",
+                "Runtime error : script:1:1: \
+                 A tensor of shape [1000000000000] would have more than 268435456 elements",
+            ),
+            (
+                "tensor([1.0]).reshape([4294967296, 4294967296]);",
+                "Runtime error : script:1:1: \
+                 A tensor of shape [4294967296, 4294967296] would have more than 268435456 elements",
+            ),
+            (
+                "MLP([2, 1000000, 1000000]);",
+                "Runtime error : script:1:1: \
+                 A tensor of shape [1000000, 1000000] would have more than 268435456 elements",
+            ),
+        ];
+        for (src, error) in errors {
+            assert_eq!(run_source(src, false).to_string(), error, "{}", src);
+        }
+
+        let params: Vec<String> = (0..256).map(|i| format!("p{}", i)).collect();
+        let src = format!("fn f({}) {{}}", params.join(", "));
+        assert_eq!(
+            run_source(&src, false).to_string(),
+            "Compile error : script:1:1: Can't have more than 255 parameters, got 256"
+        );
+
+        let Result::RuntimeErr(error) = run_source("let x = 1; x / 0;", false) else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(*error.kind, ErrorKind::Runtime(RuntimeError::DivisionByZero));
+
+        let out = run_source("print(!true); print(!(1 < 2)); print(2 ** -1);", false);
+        assert_eq!(
+            out,
+            Result::Ok(vec!["false".to_string(), "false".to_string(), "0.5".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_function_calls() {
+        let src = r#"
+        fn add(a, b) {
+            let sum = a + b;
+            print(sum);
+        }
+        fn twice(a) {
+            add(a, a);
+            add(a * 10, a);
+        }
+        add(1, 2);
+        twice(3);
+        print(add);
+        print(add(4, 5));
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "3".to_string(),
+                "6".to_string(),
+                "33".to_string(),
+                "fn->add".to_string(),
+                "9".to_string(),
+                "nil".to_string()
+            ])
+        );
+
+        let out = run_source("fn add(a, b) { a + b; } add(1);", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:25: Expected 2 arguments but got 1 when calling 'add'"
+        );
+
+        let out = run_source("let a = 1; a(2);", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:12: Can only call functions, got '1'"
+        );
+
+        let src = r#"
+        fn outer() {
+            fn countdown(n) {
+                if (n == 0) return 0;
+                print(n);
+                return countdown(n - 1);
+            }
+            countdown(2);
+        }
+        outer();
+        "#;
+        let out = run_source(src, false);
+        assert_eq!(out, Result::Ok(vec!["2".to_string(), "1".to_string()]));
+
+        let out = run_source("fn f(a, b, a) { a; }", false);
+        assert_eq!(
+            out.to_string(),
+            "Compile error : script:1:1: Duplicate parameter 'a'"
+        );
+    }
+
+    #[test]
+    fn test_return() {
+        let src = r#"
+        fn fib(n) {
+            if (n < 2) return n;
+            return fib(n - 1) + fib(n - 2);
+        }
+        fn first_positive(a, b) {
+            {
+                let x = a;
+                if (x > 0) {
+                    return x;
+                }
+            }
+            let i = 0;
+            while (i < 10) {
+                if (b > i) return b;
+                i += 1;
+            }
+            return;
+        }
+        print(fib(10));
+        print(first_positive(-1, 3));
+        print(first_positive(-1, -1));
+        print(1 + first_positive(5, 0) * 2);
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "55".to_string(),
+                "3".to_string(),
+                "nil".to_string(),
+                "11".to_string()
+            ])
+        );
+
+        let out = run_source("let a = 1; return a;", false);
+        assert_eq!(
+            out.to_string(),
+            "Compile error : script:1:12: Can't return from top-level code"
+        );
+    }
+
+    #[test]
+    fn test_method_calls() {
+        let src = r#"
+        let a = 2.0;
+        let c = -3.0;
+        print(a.relu().relu());
+        print(a.pow(c.relu().pow(2)));
+        print((a * 3).tanh() > 0.99);
+        print("hello".len());
+        print("Hello".upper() + "!");
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "2".to_string(),
+                "1".to_string(),
+                "true".to_string(),
+                "5".to_string(),
+                "HELLO!".to_string()
+            ])
+        );
+
+        let out = run_source("let a = 2.0; a.sigm();", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:14: Undefined method 'sigm' for type 'tensor'"
+        );
+
+        let out = run_source("let a = true; a.relu();", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:15: Undefined method 'relu' for type 'bool'"
+        );
+
+        let out = run_source("let a = 2.0; a.relu(1);", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:14: Method 'relu' of type 'tensor' expects 0 arguments but got 1"
+        );
+    }
+
+    #[test]
+    fn test_matmul() {
+        let src = r#"
+        let w = tensor([[1.0, 2.0], [3.0, 4.0], [5.0, 6.0]]);
+        let x = tensor([[1.0], [-1.0]]);
+        let y = w @ x;
+        print(y);
+        print(y.shape());
+        let loss = tensor([[1, 1, 1]]) @ y;
+        loss.backward();
+        print(w.grad());
+        print(x.grad());
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "[[-1], [-1], [-1]]".to_string(),
+                "[3, 1]".to_string(),
+                "[[1, -1], [1, -1], [1, -1]]".to_string(),
+                "[[9], [12]]".to_string(),
+            ])
+        );
+
+        let out = run_source("tensor([[1, 2], [3, 4]]) @ tensor([1, 2, 3]);", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:1: Shape mismatch for matrix multiplication: [2, 2] @ [3]"
+        );
+
+        let out = run_source("tensor([[1, 2], [3]]);", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:1: Can't create a tensor from ragged nested lists"
+        );
+    }
+
+    #[test]
+    fn test_broadcasting() {
+        let src = r#"
+        let x = tensor([[1.0, 2.0], [3.0, 4.0], [5.0, 6.0]]);
+        let bias = tensor([0.5, -0.5]);
+        let y = x * 2 + bias;
+        print(y);
+        y.backward();
+        print(bias.grad());
+        print(x > tensor([[2], [4], [6]]));
+        print(x != 4);
+        print(tensor(3.0) >= 3);
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "[[2.5, 3.5], [6.5, 7.5], [10.5, 11.5]]".to_string(),
+                "[3, 3]".to_string(),
+                "[[0, 0], [0, 0], [0, 0]]".to_string(),
+                "[[1, 1], [1, 0], [1, 1]]".to_string(),
+                "true".to_string(),
+            ])
+        );
+
+        let out = run_source("tensor([1, 2, 3]) < tensor([1, 2]);", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:1: Can't broadcast shapes [3] and [2]"
+        );
+    }
+
+    #[test]
+    fn test_reductions() {
+        let src = r#"
+        let x = tensor([[1.0, 2.0], [3.0, 4.0], [5.0, 6.0]]);
+        let y = tensor([1, 0, 1]);
+        let pred = x.sum(1) / 10;
+        let loss = ((pred - y) ** 2).mean();
+        print(loss);
+        loss.backward();
+        print(x.grad());
+        print(x.max(0, true));
+        print(x.argmax(nil, true));
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "0.32999999999999996".to_string(),
+                "[[-0.04666666666666666, -0.04666666666666666], [0.04666666666666666, 0.04666666666666666], [0.006666666666666672, 0.006666666666666672]]".to_string(),
+                "[[5, 6]]".to_string(),
+                "[[5]]".to_string(),
+            ])
+        );
+
+        let out = run_source("tensor([1, 2]).sum(1);", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:1: Axis 1 is out of bounds for a tensor of shape [2]"
+        );
+    }
+
+    #[test]
+    fn test_activations() {
+        let src = r#"
+        let x = tensor([[-2.0, 0.0, 2.0]]);
+        print(x.abs().sqrt().log().exp());
+        print(x.clamp(-1, 1.0));
+        print(x.leaky_relu());
+        print(x.softmax().sum(1));
+        let y = x.sigmoid().sum();
+        y.backward();
+        print(x.grad());
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "[[1.4142135623730951, 0, 1.4142135623730951]]".to_string(),
+                "[[-1, 0, 1]]".to_string(),
+                "[[-0.02, 0, 2]]".to_string(),
+                "[1]".to_string(),
+                "[[0.1049935854035065, 0.25, 0.10499358540350662]]".to_string(),
+            ])
+        );
+        let out = run_source("tensor([1, 2]).clamp(2, 1);", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:1: clamp expects min <= max, got 2 and 1"
+        );
+    }
+
+    #[test]
+    fn test_backward_through_long_loops() {
+        let src = r#"
+        let x = 1.0;
+        let loss = 0.0;
+        let i = 0;
+        while (i < 10000) {
+            loss = loss + x * x;
+            i = i + 1;
+        }
+        loss.backward();
+        print(x.grad());
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(out, Result::Ok(vec!["20000".to_string()]));
+    }
+
+    #[test]
+    fn test_no_grad() {
+        let src = r#"
+        let w = tensor([1.0, 2.0]);
+        (w * w).sum().backward();
+        print(w.grad());
+
+        // the update is not recorded, so w is a fresh leaf afterwards
+        no_grad {
+            w = w - w.grad() * 0.25;
+        }
+        (w * w).sum().backward();
+        print(w.grad());
+
+        fn double(x) {
+            no_grad {
+                return x * 2;
+            }
+        }
+        double(w).sum().backward();
+        (w * w.detach() * 3).sum().backward();
+        print(w.grad());
+
+        zero_grad([w]);
+        print(w.grad());
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "[2, 4]".to_string(),
+                "[1, 2]".to_string(),
+                "[2.5, 5]".to_string(),
+                "[0, 0]".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_optimizers() {
+        let src = r#"
+        let target = tensor([1.0, -2.0]);
+
+        fn train(opt, w, steps) {
+            let i = 0;
+            while (i < steps) {
+                opt.zero_grad();
+                ((w - target) ** 2).sum().backward();
+                opt.step();
+                i = i + 1;
+            }
+        }
+
+        let w = tensor([0.0, 0.0]);
+        train(SGD([w], 0.25), w, 1);
+        print(w);
+
+        let v = tensor([0.0, 0.0]);
+        let opt = Adam([v], 0.1, [0.9, 0.999]);
+        print(opt);
+        train(opt, v, 300);
+        print(((v - target).abs() < 0.01).sum());
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "[0.5, -1]".to_string(),
+                "optimizer->Adam".to_string(),
+                "2".to_string(),
+            ])
+        );
+
+        let out = run_source("SGD([1], 0.1);", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:1: Expected tensors or lists of tensors, got 'int'"
+        );
+    }
+
+    #[test]
+    fn test_nn() {
+        let src = r#"
+        manual_seed(7);
+        let model = MLP([2, 8, 1], "tanh");
+        print(model);
+        print(model(tensor([[0.5, -0.5], [1.0, 2.0]])).shape());
+
+        let x = tensor([[0, 0], [0, 1], [1, 0], [1, 1]]);
+        let y = tensor([[0], [1], [1], [0]]);
+        let opt = Adam(model.parameters(), 0.05);
+        let first = ((model(x) - y) ** 2).mean();
+        let i = 0;
+        while (i < 3) {
+            opt.zero_grad();
+            let loss = ((model(x) - y) ** 2).mean();
+            loss.backward();
+            opt.step();
+            i = i + 1;
+        }
+        // training itself is covered by the nn tests, a few steps check the optimizer reaches the model
+        print(((model(x) - y) ** 2).mean() < first);
+
+        let layer = Linear(3, 2);
+        print(layer.forward(tensor([1.0, 2.0, 3.0])).shape());
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "module->MLP".to_string(),
+                "[2, 1]".to_string(),
+                "true".to_string(),
+                "[2]".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_losses() {
+        let src = r#"
+        let pred = tensor([1.0, 2.0, 3.0]);
+        print(mse_loss(pred, [1, 0, 5]));
+        print(l1_loss(pred, [1, 0, 5]));
+        print(huber_loss(pred, [1, 0, 5], 2));
+        print(hinge_loss([2, 0.5, -1], [1, 1, 1]) * 3);
+
+        let logits = tensor([[0.0, 0.0], [5.0, -5.0]]);
+        let loss = cross_entropy(logits, [1, 0]);
+        loss.backward();
+        print(logits.grad() > 0);
+        print(binary_cross_entropy(tensor([0.5]), [1]) == tensor(0.5).log().abs());
+        print(binary_cross_entropy(tensor([1.0, 0.0]), [1, 0]));
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "2.6666666666666665".to_string(),
+                "1.3333333333333333".to_string(),
+                "1.3333333333333333".to_string(),
+                "2.5".to_string(),
+                "[[1, 0], [0, 1]]".to_string(),
+                "true".to_string(),
+                "0".to_string(),
+            ])
+        );
+
+        let out = run_source("cross_entropy(tensor([[1.0, 2.0]]), [0.5]);", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:1: Invalid class index 0.5 for 2 classes"
+        );
+    }
+
+    #[test]
+    fn test_gradcheck() {
+        let src = r#"
+        fn f(x, w) {
+            return (x @ w).tanh().sum(0);
+        }
+        let x = tensor([[0.5, -1.0], [2.0, 0.3]]);
+        let w = tensor([[1.5], [-0.7]]);
+        print(gradcheck(f, [x, w]));
+        fn scaled_sigmoid(x) {
+            return x.sigmoid() * 3;
+        }
+        print(gradcheck(scaled_sigmoid, x, 0.00001, 0.001));
+        print(x.grad());
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "true".to_string(),
+                "true".to_string(),
+                "[[0, 0], [0, 0]]".to_string(),
+            ])
+        );
+
+        let src = r#"
+        fn f(x) {
+            return x * x.detach();
+        }
+        gradcheck(f, tensor([1.0, 2.0]));
+        "#;
+        match run_source(src, false) {
+            Result::RuntimeErr(e) => {
+                let expected = "script:5:9: Gradcheck failed\ninput 0: worst mismatch at element 1";
+                assert!(e.to_string().starts_with(expected), "{}", e);
+            }
+            out => panic!("expected a runtime error, got {:?}", out),
+        }
+    }
+
+    #[test]
+    fn test_draw_dot() {
+        let src = r#"
+        let a = 2.0;
+        let b = (a * 3).relu();
+        b.backward();
+        print(draw_dot(b));
+        "#;
+
+        let mut graphs = 0;
+        let source = Source::new("script", src);
+        let out = run_source_with(source, false, |vm| graphs = vm.graphs().len());
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![r#"digraph {
+    rankdir=LR;
+    node [shape=record];
+    n0 [label="{ data 2.0000 | grad 3.0000 }"];
+    n1 [label="{ data 3.0000 | grad 2.0000 }"];
+    n2 [label="{ data 6.0000 | grad 1.0000 }"];
+    n2op [label="*", shape=ellipse];
+    n2op -> n2;
+    n0 -> n2op;
+    n1 -> n2op;
+    n3 [label="{ data 6.0000 | grad 1.0000 }"];
+    n3op [label="relu", shape=ellipse];
+    n3op -> n3;
+    n2 -> n3op;
+}"#
+            .to_string()])
+        );
+        assert_eq!(graphs, 1);
+    }
+
+    #[test]
+    fn test_higher_order_derivatives() {
+        let src = r#"
+        let x = tensor([-1.0, 0.5, 2.0]);
+        let y = ((x ** 3) - 2 * (x ** 2)).sum();
+        y.backward(true);
+
+        // dy/dx = 3x^2 - 4x
+        let dx = x.grad();
+        print(dx);
+        x.zero_grad();
+
+        // d2y/dx2 = 6x - 4
+        dx.sum().backward();
+        print(x.grad());
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec!["[7, -1.25, 4]".to_string(), "[-10, -1, 8]".to_string()])
+        );
+
+        // d/dx mean(x^2) = 2x / n, and its second derivative 2 / n
+        let src = r#"
+        let x = tensor([1.0, 3.0]);
+        mse_loss(x, zeros([2])).backward(true);
+        let dx = x.grad();
+        x.zero_grad();
+        dx.sum().backward();
+        print([dx, x.grad()]);
+        "#;
+        let out = run_source(src, false);
+        assert_eq!(out, Result::Ok(vec!["[[1, 3], [1, 1]]".to_string()]));
+    }
+
+    #[test]
+    fn test_forward_mode_jvp() {
+        let src = r#"
+        // f'(x) v = 3x^2 v + mean(2 (x - t) v)
+        fn f(x) {
+            print(x.tangent());
+            return (x ** 3) + mse_loss(x, zeros([2]));
+        }
+        print(jvp(f, tensor([1.0, 2.0]), tensor([1.0, 0.0])));
+
+        fn g(a, b) {
+            return (a @ b).tanh();
+        }
+        let a = tensor([1.0, 2.0]);
+        let b = tensor([0.5, -0.25]);
+        print(jvp(g, [a, b], [tensor([1.0, 0.0]), zeros([2])]));
+
+        // results that don't depend on the inputs have a zero tangent
+        fn h(x) {
+            return x.exp().value();
+        }
+        print(jvp(h, 0.0, 1.0));
+
+        // a list is always one input per argument, whatever its elements, a vector is a tensor
+        fn s(a, b) {
+            return a * b;
+        }
+        print(jvp(s, [1, 2], [1, 0]));
+        print(jvp(s, [1.0, 2.0], [1.0, 0.0]));
+        fn n(x) {
+            return x * x;
+        }
+        print(jvp(n, tensor([1, 2]), tensor([1, 0])));
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "[1, 0]".to_string(),
+                "[[3.5, 10.5], [4, 1]]".to_string(),
+                "[0, 0.5]".to_string(),
+                "[1, 0]".to_string(),
+                "[2, 2]".to_string(),
+                "[2, 2]".to_string(),
+                "[[1, 4], [2, 0]]".to_string()
+            ])
+        );
+
+        let src = "fn f(x) { return x; } jvp(f, tensor([1.0]), tensor([1.0, 2.0]));";
+        let out = run_source(src, false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:23: A tangent of shape [2] doesn't match a value of shape [1]"
+        );
+    }
+
+    #[test]
+    fn test_function_transforms() {
+        let src = r#"
+        // f(x, y) = sum(x^2 y), df/dx = 2xy, df/dy = sum(x^2)
+        fn f(x, y) {
+            return ((x ** 2) * y).sum();
+        }
+        let x = tensor([1.0, 2.0]);
+        let df = grad(f);
+        print(df);
+        print(df(x, 3.0));
+        print(grad(f, [0, 1])(x, 3.0));
+        print(value_and_grad(f, 1)(x, 3.0));
+        print(grad(f, [1, 0, 1])(x, 3.0));
+
+        // the inputs are left without gradients
+        print(x.grad());
+
+        fn g(x) {
+            return (x ** 3).sum();
+        }
+        print(grad(grad(g))(2.0));
+        print(hessian(g)(x));
+
+        fn h(x) {
+            return tensor([[1.0, 2.0], [3.0, 4.0], [5.0, 6.0]]) @ x;
+        }
+        print(jacobian(h)(x));
+
+        fn loss(x) {
+            return mse_loss(x, zeros([2]));
+        }
+        print(hessian(loss)(x));
+
+        // transforms record the graph they differentiate even inside no_grad, which still applies after them
+        no_grad {
+            print(grad(g)(2.0));
+            print(hessian(g)(x));
+            (x * x).sum().backward();
+            print(x.grad());
+        }
+        "#;
+
+        let out = run_source(src, false);
+
+        assert_eq!(
+            out,
+            Result::Ok(vec![
+                "fn->grad(f)".to_string(),
+                "[6, 12]".to_string(),
+                "[[6, 12], 5]".to_string(),
+                "[15, 5]".to_string(),
+                "[5, [6, 12], 5]".to_string(),
+                "[0, 0]".to_string(),
+                "12".to_string(),
+                "[[6, 0], [0, 12]]".to_string(),
+                "[[1, 2], [3, 4], [5, 6]]".to_string(),
+                "[[1, 0], [0, 1]]".to_string(),
+                "12".to_string(),
+                "[[6, 0], [0, 12]]".to_string(),
+                "[0, 0]".to_string(),
+            ])
+        );
+
+        let out = run_source("fn f(x) { return x; } grad(f)(tensor([1.0, 2.0]));", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:23: 'grad(f)' requires a scalar output, got a tensor of shape [2]"
+        );
+
+        let out = run_source("fn f(x) { return x; } grad(f, 1)(1.0);", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:23: Expected at least 2 arguments but got 1 when calling 'grad(f)'"
+        );
+
+        let src = "fn f(x) { return (x * x).sum(); } jvp(grad(f), tensor([1.0]), tensor([1.0]));";
+        let out = run_source(src, false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:35: 'grad(f)' can't take dual inputs, so it can't be called inside jvp()"
+        );
+
+        let src = "fn sq(x) { return x * x; } print(grad(sq, [0, 0])(tensor(3.0)));";
+        assert_eq!(run_source(src, false), Result::Ok(vec!["[6, 6]".to_string()]));
+
+        let out = run_source("fn f(x) { return x; } grad(f, -1);", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:23: argnum must be non-negative, got -1"
+        );
+
+        let out = run_source("grad(1);", false);
+        assert_eq!(
+            out.to_string(),
+            "Runtime error : script:1:1: grad() expects a function, got 'int'"
+        );
+    }
 }
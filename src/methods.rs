@@ -0,0 +1,504 @@
+//! Built-in methods available on runtime values through the `value.method(args)` syntax.
+//!
+//! Methods are looked up by name on the receiver's runtime type, each type has its own table.
+
+use std::{cell::RefCell, ops::RangeInclusive};
+
+use crate::{
+    interner::Interner,
+    natives::{number, to_shape},
+    nn::Module,
+    optim::Optimizer,
+    tensor::{Dual, Tensor, TensorResult},
+    value::ValueType,
+    vm::{format_arity, RuntimeError, RuntimeResult},
+};
+
+pub type MethodResult = Result<ValueType, RuntimeError>;
+
+/// A built-in method of receivers of type `T`
+struct Method<T: ?Sized> {
+    name: &'static str,
+    arity: RangeInclusive<usize>,
+    call: fn(&T, &[ValueType], &mut Interner) -> MethodResult,
+}
+
+const TENSOR_METHODS: &[Method<Tensor>] = &[
+    Method {
+        name: "relu",
+        arity: 0..=0,
+        call: |tensor, _, _| Ok(ValueType::Tensor(tensor.relu())),
+    },
+    Method {
+        name: "tanh",
+        arity: 0..=0,
+        call: |tensor, _, _| Ok(ValueType::Tensor(tensor.tanh())),
+    },
+    Method {
+        name: "exp",
+        arity: 0..=0,
+        call: |tensor, _, _| Ok(ValueType::Tensor(tensor.exp())),
+    },
+    Method {
+        name: "log",
+        arity: 0..=0,
+        call: |tensor, _, _| Ok(ValueType::Tensor(tensor.log())),
+    },
+    Method {
+        name: "sqrt",
+        arity: 0..=0,
+        call: |tensor, _, _| Ok(ValueType::Tensor(tensor.sqrt())),
+    },
+    Method {
+        name: "abs",
+        arity: 0..=0,
+        call: |tensor, _, _| Ok(ValueType::Tensor(tensor.abs())),
+    },
+    Method {
+        name: "sin",
+        arity: 0..=0,
+        call: |tensor, _, _| Ok(ValueType::Tensor(tensor.sin())),
+    },
+    Method {
+        name: "cos",
+        arity: 0..=0,
+        call: |tensor, _, _| Ok(ValueType::Tensor(tensor.cos())),
+    },
+    Method {
+        name: "sigmoid",
+        arity: 0..=0,
+        call: |tensor, _, _| Ok(ValueType::Tensor(tensor.sigmoid())),
+    },
+    Method {
+        name: "softplus",
+        arity: 0..=0,
+        call: |tensor, _, _| Ok(ValueType::Tensor(tensor.softplus())),
+    },
+    Method {
+        name: "gelu",
+        arity: 0..=0,
+        call: |tensor, _, _| Ok(ValueType::Tensor(tensor.gelu())),
+    },
+    Method {
+        name: "leaky_relu",
+        arity: 0..=1,
+        call: |tensor, args, _| {
+            let slope = args.first().map_or(Ok(0.01), number)?;
+            Ok(ValueType::Tensor(tensor.leaky_relu(slope)))
+        },
+    },
+    Method {
+        name: "clamp",
+        arity: 2..=2,
+        call: |tensor, args, _| {
+            Ok(ValueType::Tensor(tensor.clamp(number(&args[0])?, number(&args[1])?)?))
+        },
+    },
+    Method {
+        name: "softmax",
+        arity: 0..=1,
+        call: |tensor, args, _| Ok(ValueType::Tensor(tensor.softmax(axis_or_last(args)?)?)),
+    },
+    Method {
+        name: "log_softmax",
+        arity: 0..=1,
+        call: |tensor, args, _| Ok(ValueType::Tensor(tensor.log_softmax(axis_or_last(args)?)?)),
+    },
+    Method {
+        name: "pow",
+        arity: 1..=1,
+        call: |tensor, args, _| {
+            ValueType::Tensor(tensor.clone()).pow(args[0].clone())
+        },
+    },
+    Method {
+        name: "shape",
+        arity: 0..=0,
+        call: |tensor, _, _| {
+            let shape = tensor.shape().into_iter().map(|d| ValueType::Integer(d as i64));
+            Ok(ValueType::List(shape.collect()))
+        },
+    },
+    Method {
+        name: "reshape",
+        arity: 1..=1,
+        call: |tensor, args, _| Ok(ValueType::Tensor(tensor.reshape(&to_shape(&args[0])?)?)),
+    },
+    Method {
+        name: "transpose",
+        arity: 0..=0,
+        call: |tensor, _, _| Ok(ValueType::Tensor(tensor.transpose()?)),
+    },
+    Method {
+        name: "sum",
+        arity: 0..=2,
+        call: |tensor, args, _| reduction(args, |axis, keepdims| tensor.sum(axis, keepdims)),
+    },
+    Method {
+        name: "mean",
+        arity: 0..=2,
+        call: |tensor, args, _| reduction(args, |axis, keepdims| tensor.mean(axis, keepdims)),
+    },
+    Method {
+        name: "prod",
+        arity: 0..=2,
+        call: |tensor, args, _| reduction(args, |axis, keepdims| tensor.prod(axis, keepdims)),
+    },
+    Method {
+        name: "max",
+        arity: 0..=2,
+        call: |tensor, args, _| reduction(args, |axis, keepdims| tensor.max(axis, keepdims)),
+    },
+    Method {
+        name: "min",
+        arity: 0..=2,
+        call: |tensor, args, _| reduction(args, |axis, keepdims| tensor.min(axis, keepdims)),
+    },
+    Method {
+        name: "argmax",
+        arity: 0..=2,
+        call: |tensor, args, _| reduction(args, |axis, keepdims| tensor.argmax(axis, keepdims)),
+    },
+    // `backward(true)` records the gradients in the graph, so they can be differentiated again
+    Method {
+        name: "backward",
+        arity: 0..=1,
+        call: |tensor, args, _| {
+            match args.first() {
+                None | Some(ValueType::Boolean(false)) => tensor.backward(),
+                Some(ValueType::Boolean(true)) => tensor.backward_create_graph()?,
+                Some(value) => {
+                    let type_name = value.type_name();
+                    let message = format!("Expected a bool for create_graph, got '{}'", type_name);
+                    return Err(RuntimeError::TypeMismatch(message));
+                }
+            }
+            Ok(ValueType::Nil)
+        },
+    },
+    Method {
+        name: "detach",
+        arity: 0..=0,
+        call: |tensor, _, _| Ok(ValueType::Tensor(tensor.detach())),
+    },
+    Method {
+        name: "zero_grad",
+        arity: 0..=0,
+        call: |tensor, _, _| {
+            tensor.clear_gradient();
+            Ok(ValueType::Nil)
+        },
+    },
+    Method {
+        name: "grad",
+        arity: 0..=0,
+        call: |tensor, _, _| Ok(ValueType::Tensor(tensor.grad())),
+    },
+];
+
+// duals mirror the differentiable tensor methods, propagating their tangent
+const DUAL_METHODS: &[Method<Dual>] = &[
+    Method {
+        name: "relu",
+        arity: 0..=0,
+        call: |dual, _, _| Ok(ValueType::Dual(dual.relu())),
+    },
+    Method {
+        name: "tanh",
+        arity: 0..=0,
+        call: |dual, _, _| Ok(ValueType::Dual(dual.tanh())),
+    },
+    Method {
+        name: "exp",
+        arity: 0..=0,
+        call: |dual, _, _| Ok(ValueType::Dual(dual.exp())),
+    },
+    Method {
+        name: "log",
+        arity: 0..=0,
+        call: |dual, _, _| Ok(ValueType::Dual(dual.log())),
+    },
+    Method {
+        name: "sqrt",
+        arity: 0..=0,
+        call: |dual, _, _| Ok(ValueType::Dual(dual.sqrt())),
+    },
+    Method {
+        name: "abs",
+        arity: 0..=0,
+        call: |dual, _, _| Ok(ValueType::Dual(dual.abs())),
+    },
+    Method {
+        name: "sin",
+        arity: 0..=0,
+        call: |dual, _, _| Ok(ValueType::Dual(dual.sin())),
+    },
+    Method {
+        name: "cos",
+        arity: 0..=0,
+        call: |dual, _, _| Ok(ValueType::Dual(dual.cos())),
+    },
+    Method {
+        name: "sigmoid",
+        arity: 0..=0,
+        call: |dual, _, _| Ok(ValueType::Dual(dual.sigmoid())),
+    },
+    Method {
+        name: "softplus",
+        arity: 0..=0,
+        call: |dual, _, _| Ok(ValueType::Dual(dual.softplus())),
+    },
+    Method {
+        name: "gelu",
+        arity: 0..=0,
+        call: |dual, _, _| Ok(ValueType::Dual(dual.gelu())),
+    },
+    Method {
+        name: "leaky_relu",
+        arity: 0..=1,
+        call: |dual, args, _| {
+            let slope = args.first().map_or(Ok(0.01), number)?;
+            Ok(ValueType::Dual(dual.leaky_relu(slope)))
+        },
+    },
+    Method {
+        name: "clamp",
+        arity: 2..=2,
+        call: |dual, args, _| {
+            Ok(ValueType::Dual(dual.clamp(number(&args[0])?, number(&args[1])?)?))
+        },
+    },
+    Method {
+        name: "softmax",
+        arity: 0..=1,
+        call: |dual, args, _| Ok(ValueType::Dual(dual.softmax(axis_or_last(args)?)?)),
+    },
+    Method {
+        name: "log_softmax",
+        arity: 0..=1,
+        call: |dual, args, _| Ok(ValueType::Dual(dual.log_softmax(axis_or_last(args)?)?)),
+    },
+    Method {
+        name: "pow",
+        arity: 1..=1,
+        call: |dual, args, _| {
+            ValueType::Dual(dual.clone()).pow(args[0].clone())
+        },
+    },
+    Method {
+        name: "shape",
+        arity: 0..=0,
+        call: |dual, _, _| {
+            let shape = dual.shape().into_iter().map(|d| ValueType::Integer(d as i64));
+            Ok(ValueType::List(shape.collect()))
+        },
+    },
+    Method {
+        name: "reshape",
+        arity: 1..=1,
+        call: |dual, args, _| Ok(ValueType::Dual(dual.reshape(&to_shape(&args[0])?)?)),
+    },
+    Method {
+        name: "transpose",
+        arity: 0..=0,
+        call: |dual, _, _| Ok(ValueType::Dual(dual.transpose()?)),
+    },
+    Method {
+        name: "sum",
+        arity: 0..=2,
+        call: |dual, args, _| reduction(args, |axis, keepdims| dual.sum(axis, keepdims)),
+    },
+    Method {
+        name: "mean",
+        arity: 0..=2,
+        call: |dual, args, _| reduction(args, |axis, keepdims| dual.mean(axis, keepdims)),
+    },
+    Method {
+        name: "prod",
+        arity: 0..=2,
+        call: |dual, args, _| reduction(args, |axis, keepdims| dual.prod(axis, keepdims)),
+    },
+    Method {
+        name: "max",
+        arity: 0..=2,
+        call: |dual, args, _| reduction(args, |axis, keepdims| dual.max(axis, keepdims)),
+    },
+    Method {
+        name: "min",
+        arity: 0..=2,
+        call: |dual, args, _| reduction(args, |axis, keepdims| dual.min(axis, keepdims)),
+    },
+    Method {
+        name: "argmax",
+        arity: 0..=2,
+        call: |dual, args, _| {
+            reduction(args, |axis, keepdims| dual.value.argmax(axis, keepdims))
+        },
+    },
+    Method {
+        name: "value",
+        arity: 0..=0,
+        call: |dual, _, _| Ok(ValueType::Tensor(dual.value.clone())),
+    },
+    Method {
+        name: "tangent",
+        arity: 0..=0,
+        call: |dual, _, _| Ok(ValueType::Tensor(dual.tangent.clone())),
+    },
+];
+
+const OPTIMIZER_METHODS: &[Method<RefCell<dyn Optimizer>>] = &[
+    Method {
+        name: "step",
+        arity: 0..=0,
+        call: |optimizer, _, _| {
+            optimizer.borrow_mut().step();
+            Ok(ValueType::Nil)
+        },
+    },
+    Method {
+        name: "zero_grad",
+        arity: 0..=0,
+        call: |optimizer, _, _| {
+            optimizer.borrow().zero_grad();
+            Ok(ValueType::Nil)
+        },
+    },
+];
+
+const MODULE_METHODS: &[Method<dyn Module>] = &[
+    Method {
+        name: "forward",
+        arity: 1..=1,
+        call: |module, args, _| match &args[0] {
+            ValueType::Tensor(x) => Ok(ValueType::Tensor(module.forward(x)?)),
+            value => Err(RuntimeError::TypeMismatch(format!(
+                "Expected a tensor input, got '{}'",
+                value.type_name()
+            ))),
+        },
+    },
+    Method {
+        name: "parameters",
+        arity: 0..=0,
+        call: |module, _, _| {
+            let params = module.parameters().into_iter().map(ValueType::Tensor);
+            Ok(ValueType::List(params.collect()))
+        },
+    },
+];
+
+const STRING_METHODS: &[Method<str>] = &[
+    Method {
+        name: "len",
+        arity: 0..=0,
+        call: |string, _, _| Ok(ValueType::Integer(string.chars().count() as i64)),
+    },
+    Method {
+        name: "upper",
+        arity: 0..=0,
+        call: |string, _, interner| {
+            Ok(ValueType::String(interner.intern_string(string.to_uppercase())))
+        },
+    },
+    Method {
+        name: "lower",
+        arity: 0..=0,
+        call: |string, _, interner| {
+            Ok(ValueType::String(interner.intern_string(string.to_lowercase())))
+        },
+    },
+];
+
+/// Resolves `name` on the runtime type of `receiver` and calls it with `args`
+pub fn call_method(
+    receiver: &ValueType,
+    name: &str,
+    args: &[ValueType],
+    interner: &mut Interner,
+) -> MethodResult {
+    match receiver {
+        ValueType::Tensor(tensor) => invoke(TENSOR_METHODS, tensor, receiver, name, args, interner),
+        ValueType::Dual(dual) => invoke(DUAL_METHODS, dual, receiver, name, args, interner),
+        ValueType::Optimizer(optimizer) => {
+            invoke(OPTIMIZER_METHODS, optimizer.as_ref(), receiver, name, args, interner)
+        }
+        ValueType::Module(module) => {
+            invoke(MODULE_METHODS, module.as_ref(), receiver, name, args, interner)
+        }
+        ValueType::String(idx) => {
+            let string = interner.lookup(*idx).to_string();
+            invoke(STRING_METHODS, string.as_str(), receiver, name, args, interner)
+        }
+        _ => Err(undefined_method(receiver, name)),
+    }
+}
+
+fn invoke<T: ?Sized>(
+    methods: &[Method<T>],
+    this: &T,
+    receiver: &ValueType,
+    name: &str,
+    args: &[ValueType],
+    interner: &mut Interner,
+) -> MethodResult {
+    let method = methods
+        .iter()
+        .find(|method| method.name == name)
+        .ok_or_else(|| undefined_method(receiver, name))?;
+
+    if !method.arity.contains(&args.len()) {
+        return Err(RuntimeError::MethodArityMismatch {
+            name: name.to_string(),
+            type_name: receiver.type_name(),
+            expected: format_arity(&method.arity),
+            got: args.len(),
+        });
+    }
+
+    (method.call)(this, args, interner)
+}
+
+/// Calls a reduction with the optional `(axis, keepdims)` arguments, a `nil` axis reduces over all elements
+fn reduction<T: Into<ValueType>>(
+    args: &[ValueType],
+    reduce: impl FnOnce(Option<isize>, bool) -> TensorResult<T>,
+) -> MethodResult {
+    let axis = match args.first() {
+        None | Some(ValueType::Nil) => None,
+        Some(ValueType::Integer(axis)) => Some(*axis as isize),
+        Some(value) => {
+            let message = format!("Expected an int axis, got '{}'", value.type_name());
+            return Err(RuntimeError::TypeMismatch(message));
+        }
+    };
+    let keepdims = match args.get(1) {
+        None => false,
+        Some(ValueType::Boolean(keepdims)) => *keepdims,
+        Some(value) => {
+            let message = format!("Expected a bool for keepdims, got '{}'", value.type_name());
+            return Err(RuntimeError::TypeMismatch(message));
+        }
+    };
+
+    Ok(reduce(axis, keepdims)?.into())
+}
+
+/// Reads the optional axis argument of softmax-like methods, defaulting to the last axis
+fn axis_or_last(args: &[ValueType]) -> RuntimeResult<isize> {
+    match args.first() {
+        None => Ok(-1),
+        Some(ValueType::Integer(axis)) => Ok(*axis as isize),
+        Some(value) => Err(RuntimeError::TypeMismatch(format!(
+            "Expected an int axis, got '{}'",
+            value.type_name()
+        ))),
+    }
+}
+
+fn undefined_method(receiver: &ValueType, name: &str) -> RuntimeError {
+    RuntimeError::UndefinedMethod {
+        name: name.to_string(),
+        type_name: receiver.type_name(),
+    }
+}
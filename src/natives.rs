@@ -0,0 +1,436 @@
+//! Built-in functions defined as globals in every VM.
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    nn::{self, Activation, Linear, Mlp},
+    optim::{Adam, Sgd},
+    tensor::{self, Dual, Tensor, TensorResult},
+    transforms::{Transform, TransformKind},
+    value::{NativeFunction, NativeResult, ValueType},
+    vm::{RuntimeError, RuntimeResult, VM},
+};
+
+pub const NATIVES: &[NativeFunction] = &[
+    NativeFunction {
+        name: "tensor",
+        arity: 1..=1,
+        function: |_, args| {
+            let (data, shape) = flatten(&args[0])?;
+            Ok(ValueType::Tensor(Tensor::from_vec(data, &shape)))
+        },
+    },
+    NativeFunction {
+        name: "zeros",
+        arity: 1..=1,
+        function: |_, args| Ok(ValueType::Tensor(Tensor::zeros(&to_shape(&args[0])?))),
+    },
+    NativeFunction {
+        name: "ones",
+        arity: 1..=1,
+        function: |_, args| Ok(ValueType::Tensor(Tensor::ones(&to_shape(&args[0])?))),
+    },
+    NativeFunction {
+        name: "zero_grad",
+        arity: 1..=1,
+        function: |_, args| {
+            tensor::zero_grad(&parameters(&args[0])?);
+            Ok(ValueType::Nil)
+        },
+    },
+    NativeFunction {
+        name: "SGD",
+        arity: 2..=3,
+        function: |_, args| {
+            let momentum = args.get(2).map_or(Ok(0.0), number)?;
+            let sgd = Sgd::new(parameters(&args[0])?, number(&args[1])?, momentum);
+            Ok(ValueType::Optimizer(Rc::new(RefCell::new(sgd))))
+        },
+    },
+    NativeFunction {
+        name: "Adam",
+        arity: 2..=5,
+        function: |_, args| {
+            let (params, lr, betas, eps, weight_decay) = adam_args(args, 0.0)?;
+            let adam = Adam::new(params, lr, betas, eps, weight_decay);
+            Ok(ValueType::Optimizer(Rc::new(RefCell::new(adam))))
+        },
+    },
+    NativeFunction {
+        name: "AdamW",
+        arity: 2..=5,
+        function: |_, args| {
+            let (params, lr, betas, eps, weight_decay) = adam_args(args, 0.01)?;
+            let adamw = Adam::adamw(params, lr, betas, eps, weight_decay);
+            Ok(ValueType::Optimizer(Rc::new(RefCell::new(adamw))))
+        },
+    },
+    NativeFunction {
+        name: "manual_seed",
+        arity: 1..=1,
+        function: |_, args| match &args[0] {
+            ValueType::Integer(seed) => {
+                nn::manual_seed(*seed as u64);
+                Ok(ValueType::Nil)
+            }
+            value => Err(RuntimeError::TypeMismatch(format!(
+                "Expected an int seed, got '{}'",
+                value.type_name()
+            ))),
+        },
+    },
+    NativeFunction {
+        name: "Linear",
+        arity: 2..=2,
+        function: |_, args| {
+            let (inputs, outputs) = (size(&args[0])?, size(&args[1])?);
+            element_count(&[inputs, outputs])?;
+            let linear = Linear::new(inputs, outputs);
+            Ok(ValueType::Module(Rc::new(linear)))
+        },
+    },
+    NativeFunction {
+        name: "MLP",
+        arity: 1..=2,
+        function: |vm, args| {
+            let activation = match args.get(1) {
+                None => Activation::Relu,
+                Some(ValueType::String(name)) => {
+                    let name = vm.interner.lookup(*name);
+                    Activation::from_name(name).ok_or_else(|| {
+                        RuntimeError::InvalidArgument(format!("Unknown activation '{}'", name))
+                    })?
+                }
+                Some(value) => {
+                    return Err(RuntimeError::TypeMismatch(format!(
+                        "Expected an activation name, got '{}'",
+                        value.type_name()
+                    )));
+                }
+            };
+            let mlp = Mlp::new(&layer_sizes(&args[0])?, activation);
+            Ok(ValueType::Module(Rc::new(mlp)))
+        },
+    },
+    NativeFunction {
+        name: "mse_loss",
+        arity: 2..=2,
+        function: |_, args| loss(args, Tensor::mse_loss, Dual::mse_loss),
+    },
+    NativeFunction {
+        name: "l1_loss",
+        arity: 2..=2,
+        function: |_, args| loss(args, Tensor::l1_loss, Dual::l1_loss),
+    },
+    NativeFunction {
+        name: "binary_cross_entropy",
+        arity: 2..=2,
+        function: |_, args| loss(args, Tensor::binary_cross_entropy, Dual::binary_cross_entropy),
+    },
+    NativeFunction {
+        name: "cross_entropy",
+        arity: 2..=2,
+        function: |_, args| loss(args, Tensor::cross_entropy, Dual::cross_entropy),
+    },
+    NativeFunction {
+        name: "hinge_loss",
+        arity: 2..=2,
+        function: |_, args| loss(args, Tensor::hinge_loss, Dual::hinge_loss),
+    },
+    NativeFunction {
+        name: "huber_loss",
+        arity: 2..=3,
+        function: |_, args| {
+            let delta = args.get(2).map_or(Ok(1.0), number)?;
+            loss(
+                args,
+                |prediction, target| prediction.huber_loss(target, delta),
+                |prediction, target| prediction.huber_loss(target, delta),
+            )
+        },
+    },
+    NativeFunction {
+        name: "draw_dot",
+        arity: 1..=1,
+        function: |vm, args| {
+            let tensor = to_tensor(&args[0])?;
+            let dot = tensor.to_dot();
+            vm.record_graph(tensor);
+            Ok(ValueType::String(vm.interner.intern_string(dot)))
+        },
+    },
+    // `gradcheck(f, inputs, eps, tol)` returns true, or fails with the worst mismatch of every input.
+    // `inputs` is one input, or a list of one input per argument of `f`
+    NativeFunction {
+        name: "gradcheck",
+        arity: 2..=4,
+        function: |vm, args| {
+            let f = args[0].clone();
+            let inputs = tensors(&args[1])?;
+            let eps = args.get(2).map_or(Ok(1e-6), number)?;
+            let tol = args.get(3).map_or(Ok(1e-4), number)?;
+
+            let report = tensor::gradcheck(
+                |inputs| {
+                    let args = inputs.iter().cloned().map(ValueType::Tensor).collect::<Vec<_>>();
+                    to_tensor(&vm.call(f.clone(), &args)?)
+                },
+                &inputs,
+                eps,
+                tol,
+            )?;
+
+            if report.passed() {
+                Ok(ValueType::Boolean(true))
+            } else {
+                Err(RuntimeError::Other(format!("Gradcheck failed\n{}", report)))
+            }
+        },
+    },
+    // `jvp(f, x, v)` returns `[f(x), J v]`, the derivative of `f` at `x` along `v` in one forward pass.
+    // `x` and `v` are one input, or lists of one input per argument of `f`
+    NativeFunction {
+        name: "jvp",
+        arity: 3..=3,
+        function: |vm, args| {
+            let f = args[0].clone();
+            let (x, v) = (tensors(&args[1])?, tensors(&args[2])?);
+
+            let (value, tangent) = tensor::jvp(
+                |inputs| {
+                    let args = inputs.iter().cloned().map(ValueType::Dual).collect::<Vec<_>>();
+                    // a result that doesn't depend on the inputs has a zero tangent
+                    to_dual(&vm.call(f.clone(), &args)?)
+                },
+                &x,
+                &v,
+            )?;
+            Ok(ValueType::List(vec![ValueType::Tensor(value), ValueType::Tensor(tangent)]))
+        },
+    },
+    // `grad(f, argnums)` returns a function computing the gradient of `f` with respect to the argument
+    // `argnums`, or a list of gradients for a list of argnums
+    NativeFunction {
+        name: "grad",
+        arity: 1..=2,
+        function: |_, args| transform(TransformKind::Grad, args),
+    },
+    NativeFunction {
+        name: "value_and_grad",
+        arity: 1..=2,
+        function: |_, args| transform(TransformKind::ValueAndGrad, args),
+    },
+    NativeFunction {
+        name: "jacobian",
+        arity: 1..=2,
+        function: |_, args| transform(TransformKind::Jacobian, args),
+    },
+    NativeFunction {
+        name: "hessian",
+        arity: 1..=2,
+        function: |_, args| transform(TransformKind::Hessian, args),
+    },
+];
+
+fn transform(kind: TransformKind, args: &[ValueType]) -> NativeResult {
+    let transform = Transform::new(kind, args[0].clone(), args.get(1))?;
+    Ok(ValueType::Transform(Rc::new(transform)))
+}
+
+/// Calls a loss on tensors, or on duals if either argument is one
+fn loss(
+    args: &[ValueType],
+    on_tensors: impl Fn(&Tensor, &Tensor) -> TensorResult<Tensor>,
+    on_duals: impl Fn(&Dual, &Dual) -> TensorResult<Dual>,
+) -> NativeResult {
+    if matches!(args[0], ValueType::Dual(_)) || matches!(args[1], ValueType::Dual(_)) {
+        let (prediction, target) = (to_dual(&args[0])?, to_dual(&args[1])?);
+        return Ok(ValueType::Dual(on_duals(&prediction, &target)?));
+    }
+    let (prediction, target) = (to_tensor(&args[0])?, to_tensor(&args[1])?);
+    Ok(ValueType::Tensor(on_tensors(&prediction, &target)?))
+}
+
+/// Reads a dual argument, tensors and numbers become duals with a zero tangent
+fn to_dual(value: &ValueType) -> RuntimeResult<Dual> {
+    match value.to_dual() {
+        Some(dual) => Ok(dual),
+        None => Ok(Dual::constant(to_tensor(value)?)),
+    }
+}
+
+/// Reads the inputs of a function, a list holds one input per element and anything else is a single
+/// input, each converted like `tensor(value)` does. `[1, 2]` is two scalars, a vector is `tensor([1, 2])`
+fn tensors(value: &ValueType) -> RuntimeResult<Vec<Tensor>> {
+    match value {
+        ValueType::List(values) => values.iter().map(to_tensor).collect(),
+        value => Ok(vec![to_tensor(value)?]),
+    }
+}
+
+/// Reads a tensor argument, numbers and (nested) lists of numbers are converted like `tensor(value)` does
+pub fn to_tensor(value: &ValueType) -> RuntimeResult<Tensor> {
+    match value {
+        ValueType::Tensor(tensor) => Ok(tensor.clone()),
+        value => {
+            let (data, shape) = flatten(value)?;
+            Ok(Tensor::from_vec(data, &shape))
+        }
+    }
+}
+
+/// Reads a layer size, a non-negative integer
+fn size(value: &ValueType) -> RuntimeResult<usize> {
+    match value {
+        ValueType::Integer(n) if *n >= 0 => Ok(*n as usize),
+        value => Err(RuntimeError::TypeMismatch(format!(
+            "Expected a layer size, got '{}'",
+            value.type_name()
+        ))),
+    }
+}
+
+/// Reads the layer sizes of an MLP, a list of non-negative integers
+fn layer_sizes(value: &ValueType) -> RuntimeResult<Vec<usize>> {
+    let ValueType::List(elements) = value else {
+        return Err(RuntimeError::TypeMismatch(format!(
+            "Expected layer sizes as a list of integers, got '{}'",
+            value.type_name()
+        )));
+    };
+
+    let sizes = elements.iter().map(size).collect::<RuntimeResult<Vec<_>>>()?;
+    for layer in sizes.windows(2) {
+        element_count(layer)?;
+    }
+    Ok(sizes)
+}
+
+type AdamArgs = (Vec<Tensor>, f64, (f64, f64), f64, f64);
+
+/// Reads `(params, lr, betas, eps, weight_decay)`, the last three being optional
+fn adam_args(args: &[ValueType], default_weight_decay: f64) -> RuntimeResult<AdamArgs> {
+    let betas = match args.get(2) {
+        None => (0.9, 0.999),
+        Some(ValueType::List(betas)) if betas.len() == 2 => (number(&betas[0])?, number(&betas[1])?),
+        Some(_) => {
+            let message = "Expected betas as a list of two numbers".to_string();
+            return Err(RuntimeError::TypeMismatch(message));
+        }
+    };
+    let eps = args.get(3).map_or(Ok(1e-8), number)?;
+    let weight_decay = args.get(4).map_or(Ok(default_weight_decay), number)?;
+
+    Ok((parameters(&args[0])?, number(&args[1])?, betas, eps, weight_decay))
+}
+
+/// Reads a number argument, scalar tensors included since float literals evaluate to tensors
+pub fn number(value: &ValueType) -> RuntimeResult<f64> {
+    match value {
+        ValueType::Integer(n) => Ok(*n as f64),
+        ValueType::Float(n) => Ok(*n),
+        ValueType::Tensor(tensor) => tensor.item().ok_or_else(|| {
+            let message = format!("Expected a number, got a tensor of shape {:?}", tensor.shape());
+            RuntimeError::ShapeMismatch(message)
+        }),
+        value => Err(RuntimeError::TypeMismatch(format!(
+            "Expected a number, got '{}'",
+            value.type_name()
+        ))),
+    }
+}
+
+/// Collects the tensors of a tensor or (nested) list of tensors
+pub fn parameters(value: &ValueType) -> RuntimeResult<Vec<Tensor>> {
+    match value {
+        ValueType::Tensor(tensor) => Ok(vec![tensor.clone()]),
+        ValueType::List(elements) => {
+            let mut params = Vec::new();
+            for element in elements {
+                params.extend(parameters(element)?);
+            }
+            Ok(params)
+        }
+        value => Err(RuntimeError::TypeMismatch(format!(
+            "Expected tensors or lists of tensors, got '{}'",
+            value.type_name()
+        ))),
+    }
+}
+
+/// Flattens (nested) lists of numbers into a row-major buffer and its shape
+fn flatten(value: &ValueType) -> RuntimeResult<(Vec<f64>, Vec<usize>)> {
+    match value {
+        ValueType::List(elements) => {
+            let mut data = Vec::new();
+            let mut inner_shape = None;
+            for element in elements {
+                let (element_data, element_shape) = flatten(element)?;
+                if inner_shape.get_or_insert_with(|| element_shape.clone()) != &element_shape {
+                    let message = "Can't create a tensor from ragged nested lists".to_string();
+                    return Err(RuntimeError::ShapeMismatch(message));
+                }
+                data.extend(element_data);
+            }
+
+            let mut shape = vec![elements.len()];
+            shape.extend(inner_shape.unwrap_or_default());
+            Ok((data, shape))
+        }
+        ValueType::Tensor(tensor) => Ok((tensor.data(), tensor.shape())),
+        ValueType::Integer(n) => Ok((vec![*n as f64], vec![])),
+        ValueType::Float(n) => Ok((vec![*n], vec![])),
+        value => Err(RuntimeError::TypeMismatch(format!(
+            "Can't create a tensor from a value of type '{}'",
+            value.type_name()
+        ))),
+    }
+}
+
+/// Most elements a tensor created from a shape can have, so a shape that is too large is an error instead of
+/// an allocation failure aborting the process, or the whole page in the playground
+const MAX_ELEMENTS: usize = 1 << 28;
+
+/// Number of elements of a tensor of shape `shape`, or an error if there are more than `MAX_ELEMENTS`
+fn element_count(shape: &[usize]) -> RuntimeResult<usize> {
+    shape
+        .iter()
+        .try_fold(1usize, |count, &len| count.checked_mul(len))
+        .filter(|count| *count <= MAX_ELEMENTS)
+        .ok_or_else(|| {
+            RuntimeError::InvalidArgument(format!(
+                "A tensor of shape {:?} would have more than {} elements",
+                shape, MAX_ELEMENTS
+            ))
+        })
+}
+
+/// Reads a shape given as a list of non-negative integers, of at most `MAX_ELEMENTS` elements
+pub fn to_shape(value: &ValueType) -> RuntimeResult<Vec<usize>> {
+    let ValueType::List(elements) = value else {
+        return Err(RuntimeError::TypeMismatch(format!(
+            "Expected a shape as a list of integers, got '{}'",
+            value.type_name()
+        )));
+    };
+
+    let shape = elements
+        .iter()
+        .map(|element| match element {
+            ValueType::Integer(n) if *n >= 0 => Ok(*n as usize),
+            _ => {
+                let message = "Expected a shape as a list of non-negative integers".to_string();
+                Err(RuntimeError::TypeMismatch(message))
+            }
+        })
+        .collect::<RuntimeResult<Vec<_>>>()?;
+    element_count(&shape)?;
+    Ok(shape)
+}
+
+/// Defines every native function as a global of `vm`
+pub fn define_natives(vm: &mut VM) {
+    for native in NATIVES {
+        vm.define_global(native.name, ValueType::NativeFunction(native.clone()));
+    }
+}
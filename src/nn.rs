@@ -0,0 +1,194 @@
+//! Neural network layers built from tensor operations, in the spirit of micrograd's `nn` module.
+//!
+//! Parameters are initialized from a small seeded generator, so the same seed always builds the same network.
+
+use std::cell::Cell;
+
+use crate::tensor::{Tensor, TensorResult};
+
+const DEFAULT_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+thread_local! {
+    static RNG_STATE: Cell<u64> = const { Cell::new(DEFAULT_SEED) };
+}
+
+/// Resets the generator used to initialize parameters on this thread
+pub fn manual_seed(seed: u64) {
+    RNG_STATE.with(|state| state.set(seed));
+}
+
+/// Next uniform sample in `[-1, 1)`, using splitmix64
+fn uniform() -> f64 {
+    let mut z = RNG_STATE.with(|state| {
+        let next = state.get().wrapping_add(0x9E37_79B9_7F4A_7C15);
+        state.set(next);
+        next
+    });
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+
+    // the top 53 bits give a float in [0, 1)
+    (z >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0
+}
+
+pub trait Module: std::fmt::Debug {
+    /// Name of the module as it is constructed in grad programs
+    fn name(&self) -> &'static str;
+
+    fn forward(&self, x: &Tensor) -> TensorResult<Tensor>;
+
+    /// Every trainable tensor of the module, in a stable order
+    fn parameters(&self) -> Vec<Tensor>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Activation {
+    Relu,
+    Tanh,
+    Sigmoid,
+    Gelu,
+    Identity,
+}
+
+impl Activation {
+    pub fn from_name(name: &str) -> Option<Activation> {
+        match name {
+            "relu" => Some(Activation::Relu),
+            "tanh" => Some(Activation::Tanh),
+            "sigmoid" => Some(Activation::Sigmoid),
+            "gelu" => Some(Activation::Gelu),
+            "identity" => Some(Activation::Identity),
+            _ => None,
+        }
+    }
+
+    pub fn apply(&self, x: &Tensor) -> Tensor {
+        match self {
+            Activation::Relu => x.relu(),
+            Activation::Tanh => x.tanh(),
+            Activation::Sigmoid => x.sigmoid(),
+            Activation::Gelu => x.gelu(),
+            Activation::Identity => x.clone(),
+        }
+    }
+}
+
+/// Affine layer `x @ weight + bias`, taking `[in]` or `[batch, in]` inputs
+#[derive(Debug)]
+pub struct Linear {
+    weight: Tensor,
+    bias: Tensor,
+}
+
+impl Linear {
+    /// Weights and biases are drawn uniformly from `[-1/sqrt(in), 1/sqrt(in)]`, like PyTorch does
+    pub fn new(inputs: usize, outputs: usize) -> Linear {
+        let bound = 1.0 / (inputs.max(1) as f64).sqrt();
+        let init = |len| (0..len).map(|_| uniform() * bound).collect::<Vec<_>>();
+
+        Linear {
+            weight: Tensor::from_vec(init(inputs * outputs), &[inputs, outputs]),
+            bias: Tensor::from_vec(init(outputs), &[outputs]),
+        }
+    }
+}
+
+impl Module for Linear {
+    fn name(&self) -> &'static str {
+        "Linear"
+    }
+
+    fn forward(&self, x: &Tensor) -> TensorResult<Tensor> {
+        Ok(x.matmul(&self.weight)? + self.bias.clone())
+    }
+
+    fn parameters(&self) -> Vec<Tensor> {
+        vec![self.weight.clone(), self.bias.clone()]
+    }
+}
+
+/// Stack of `Linear` layers of the given sizes, with `activation` applied between them but not after the last one
+#[derive(Debug)]
+pub struct Mlp {
+    layers: Vec<Linear>,
+    activation: Activation,
+}
+
+impl Mlp {
+    pub fn new(sizes: &[usize], activation: Activation) -> Mlp {
+        let layers = sizes
+            .windows(2)
+            .map(|pair| Linear::new(pair[0], pair[1]))
+            .collect();
+        Mlp { layers, activation }
+    }
+}
+
+impl Module for Mlp {
+    fn name(&self) -> &'static str {
+        "MLP"
+    }
+
+    fn forward(&self, x: &Tensor) -> TensorResult<Tensor> {
+        let mut x = x.clone();
+        for (i, layer) in self.layers.iter().enumerate() {
+            x = layer.forward(&x)?;
+            if i + 1 < self.layers.len() {
+                x = self.activation.apply(&x);
+            }
+        }
+        Ok(x)
+    }
+
+    fn parameters(&self) -> Vec<Tensor> {
+        self.layers.iter().flat_map(Linear::parameters).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optim::{Adam, Optimizer};
+
+    #[test]
+    fn test_seeded_initialization() {
+        manual_seed(42);
+        let first = Mlp::new(&[3, 4, 2], Activation::Relu);
+        manual_seed(42);
+        let second = Mlp::new(&[3, 4, 2], Activation::Relu);
+
+        let shapes = first.parameters().iter().map(Tensor::shape).collect::<Vec<_>>();
+        assert_eq!(shapes, vec![vec![3, 4], vec![4], vec![4, 2], vec![2]]);
+        for (a, b) in first.parameters().iter().zip(second.parameters()) {
+            assert_eq!(a.data(), b.data());
+        }
+
+        let bound = 1.0 / 3.0_f64.sqrt();
+        assert!(first.parameters()[0].data().iter().all(|w| w.abs() <= bound));
+
+        let out = first.forward(&Tensor::zeros(&[5, 3])).unwrap();
+        assert_eq!(out.shape(), vec![5, 2]);
+    }
+
+    #[test]
+    fn test_mlp_learns_xor() {
+        manual_seed(0);
+        let model = Mlp::new(&[2, 8, 1], Activation::Tanh);
+        let mut optimizer = Adam::new(model.parameters(), 0.05, (0.9, 0.999), 1e-8, 0.0);
+
+        let x = Tensor::from_vec(vec![0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 1.0, 1.0], &[4, 2]);
+        let y = Tensor::from_vec(vec![0.0, 1.0, 1.0, 0.0], &[4, 1]);
+        let mut loss = f64::INFINITY;
+        for _ in 0..300 {
+            optimizer.zero_grad();
+            let diff = model.forward(&x).unwrap() - y.clone();
+            let mse = (diff.clone() * diff).mean(None, false).unwrap();
+            mse.backward();
+            optimizer.step();
+            loss = mse.item().unwrap();
+        }
+
+        assert!(loss < 0.01, "loss {} did not converge", loss);
+    }
+}
@@ -0,0 +1,210 @@
+//! Optimizers updating parameters in place from their accumulated gradients.
+//!
+//! Each optimizer owns the parameters it was created with, along with any per-parameter state.
+
+use crate::tensor::{self, Tensor};
+
+pub trait Optimizer: std::fmt::Debug {
+    /// Name of the optimizer as it is constructed in grad programs
+    fn name(&self) -> &'static str;
+
+    fn params(&self) -> &[Tensor];
+
+    /// Updates every parameter from its current gradient
+    fn step(&mut self);
+
+    fn zero_grad(&self) {
+        tensor::zero_grad(self.params());
+    }
+}
+
+/// Stochastic gradient descent with momentum, `v = momentum * v + g` and `p -= lr * v`
+#[derive(Debug)]
+pub struct Sgd {
+    params: Vec<Tensor>,
+    lr: f64,
+    momentum: f64,
+    velocity: Vec<Vec<f64>>,
+}
+
+impl Sgd {
+    pub fn new(params: Vec<Tensor>, lr: f64, momentum: f64) -> Sgd {
+        let velocity = params.iter().map(|p| vec![0.0; p.len()]).collect();
+        Sgd {
+            params,
+            lr,
+            momentum,
+            velocity,
+        }
+    }
+}
+
+impl Optimizer for Sgd {
+    fn name(&self) -> &'static str {
+        "SGD"
+    }
+
+    fn params(&self) -> &[Tensor] {
+        &self.params
+    }
+
+    fn step(&mut self) {
+        let (lr, momentum) = (self.lr, self.momentum);
+        for (param, velocity) in self.params.iter().zip(self.velocity.iter_mut()) {
+            param.update(|data, gradient| {
+                for ((p, g), v) in data.iter_mut().zip(gradient).zip(velocity.iter_mut()) {
+                    *v = momentum * *v + g;
+                    *p -= lr * *v;
+                }
+            });
+        }
+    }
+}
+
+/// Adam with bias-corrected moment estimates. Weight decay is either added to the gradient (Adam),
+/// or applied to the parameters directly before the update (AdamW)
+#[derive(Debug)]
+pub struct Adam {
+    params: Vec<Tensor>,
+    lr: f64,
+    betas: (f64, f64),
+    eps: f64,
+    weight_decay: f64,
+    decoupled: bool,
+    steps: i32,
+    first_moment: Vec<Vec<f64>>,
+    second_moment: Vec<Vec<f64>>,
+}
+
+impl Adam {
+    pub fn new(
+        params: Vec<Tensor>,
+        lr: f64,
+        betas: (f64, f64),
+        eps: f64,
+        weight_decay: f64,
+    ) -> Adam {
+        let zeros = params.iter().map(|p| vec![0.0; p.len()]).collect::<Vec<_>>();
+        Adam {
+            params,
+            lr,
+            betas,
+            eps,
+            weight_decay,
+            decoupled: false,
+            steps: 0,
+            first_moment: zeros.clone(),
+            second_moment: zeros,
+        }
+    }
+
+    /// Adam with decoupled weight decay
+    pub fn adamw(
+        params: Vec<Tensor>,
+        lr: f64,
+        betas: (f64, f64),
+        eps: f64,
+        weight_decay: f64,
+    ) -> Adam {
+        Adam {
+            decoupled: true,
+            ..Adam::new(params, lr, betas, eps, weight_decay)
+        }
+    }
+}
+
+impl Optimizer for Adam {
+    fn name(&self) -> &'static str {
+        if self.decoupled {
+            "AdamW"
+        } else {
+            "Adam"
+        }
+    }
+
+    fn params(&self) -> &[Tensor] {
+        &self.params
+    }
+
+    fn step(&mut self) {
+        self.steps += 1;
+        let (beta1, beta2) = self.betas;
+        let (lr, eps, weight_decay) = (self.lr, self.eps, self.weight_decay);
+        let decoupled = self.decoupled;
+        let correction1 = 1.0 - beta1.powi(self.steps);
+        let correction2 = 1.0 - beta2.powi(self.steps);
+
+        let moments = self.first_moment.iter_mut().zip(self.second_moment.iter_mut());
+        for (param, (m, v)) in self.params.iter().zip(moments) {
+            param.update(|data, gradient| {
+                for (i, (p, g)) in data.iter_mut().zip(gradient).enumerate() {
+                    let g = if decoupled {
+                        *p -= lr * weight_decay * *p;
+                        *g
+                    } else {
+                        g + weight_decay * *p
+                    };
+
+                    m[i] = beta1 * m[i] + (1.0 - beta1) * g;
+                    v[i] = beta2 * v[i] + (1.0 - beta2) * g * g;
+                    *p -= lr * (m[i] / correction1) / ((v[i] / correction2).sqrt() + eps);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimizes `sum((p - target)^2)` from zeros and returns the final parameters
+    fn minimize_quadratic(optimizer: &mut dyn Optimizer, steps: usize) -> Vec<f64> {
+        let target = Tensor::from_vec(vec![1.0, -2.0, 0.5], &[3]);
+        for _ in 0..steps {
+            optimizer.zero_grad();
+            let param = optimizer.params()[0].clone();
+            let diff = param - target.clone();
+            (diff.clone() * diff).sum(None, false).unwrap().backward();
+            optimizer.step();
+        }
+        optimizer.params()[0].data()
+    }
+
+    fn assert_close(actual: &[f64], expected: &[f64], tol: f64) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < tol, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_sgd_converges() {
+        let mut sgd = Sgd::new(vec![Tensor::zeros(&[3])], 0.1, 0.0);
+        assert_close(&minimize_quadratic(&mut sgd, 100), &[1.0, -2.0, 0.5], 1e-6);
+
+        let mut momentum = Sgd::new(vec![Tensor::zeros(&[3])], 0.05, 0.9);
+        assert_close(&minimize_quadratic(&mut momentum, 200), &[1.0, -2.0, 0.5], 1e-4);
+    }
+
+    #[test]
+    fn test_adam_converges() {
+        let mut adam = Adam::new(vec![Tensor::zeros(&[3])], 0.1, (0.9, 0.999), 1e-8, 0.0);
+        assert_close(&minimize_quadratic(&mut adam, 500), &[1.0, -2.0, 0.5], 1e-3);
+        assert_eq!(adam.name(), "Adam");
+    }
+
+    #[test]
+    fn test_weight_decay() {
+        // the minimum of (p - t)^2 + wd / 2 * p^2 is t / (1 + wd / 2)
+        let mut adam = Adam::new(vec![Tensor::zeros(&[3])], 0.05, (0.9, 0.999), 1e-8, 1.0);
+        let shrunk = [1.0 / 1.5, -2.0 / 1.5, 0.5 / 1.5];
+        assert_close(&minimize_quadratic(&mut adam, 1000), &shrunk, 1e-3);
+
+        // decoupled decay pulls towards zero at a rate of lr * wd per step, independently of the gradient scale
+        let mut adamw = Adam::adamw(vec![Tensor::zeros(&[3])], 0.05, (0.9, 0.999), 1e-8, 0.01);
+        let params = minimize_quadratic(&mut adamw, 1000);
+        assert_close(&params, &[1.0, -2.0, 0.5], 0.05);
+        assert!(params[0] < 1.0 && params[1] > -2.0);
+        assert_eq!(adamw.name(), "AdamW");
+    }
+}
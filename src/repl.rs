@@ -0,0 +1,227 @@
+//! The interactive session `grad` starts without a script. Every input runs on the same VM, so globals and
+//! interned strings persist from one input to the next, and the value of a trailing expression is printed.
+
+use grad::{
+    ast::{ast_to_ascii, ASTNode, Parser},
+    chunk::Chunk,
+    compiler::Compiler,
+    debug,
+    diagnostics::{self, from_parse_errors, Diagnostic},
+    interner::Interner,
+    scanner::{Lexer, TokenType},
+    source::Source,
+    value::ValueType,
+    vm::{Result, VM},
+};
+use rustyline::{error::ReadlineError, DefaultEditor};
+use std::{io::IsTerminal, rc::Rc};
+
+const HELP: &str = "\
+:ast <code>       show the syntax tree of <code>
+:bytecode <code>  show the bytecode <code> compiles to, without running it
+:tokens <code>    show the tokens of <code>
+:help             show this message
+:quit             leave the REPL, like Ctrl-D";
+
+/// Name the inputs are reported under in error locations
+const SOURCE_NAME: &str = "<repl>";
+
+pub struct Session {
+    vm: VM,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session {
+            vm: VM::init(Chunk::new(), Interner::default()),
+        }
+    }
+
+    /// Runs one complete input. `print` writes to stdout as the input runs, the result holds the value of
+    /// a trailing expression unless it's nil
+    pub fn eval(&mut self, src: &str) -> Result {
+        let ast = match parse(src) {
+            Ok(ast) => ast,
+            Err(errors) => return Result::CompileErr(errors),
+        };
+        let source = Rc::new(Source::new(SOURCE_NAME, src));
+        let mut compiler = Compiler::with_interner(self.vm.interner.clone()).with_source(source);
+        let (chunk, interner) = match compiler.compile_interactive(ast) {
+            Ok(compiled) => compiled,
+            Err(e) => return Result::CompileErr(vec![e]),
+        };
+        self.vm.interner = interner;
+
+        match self.vm.run_chunk(chunk) {
+            Ok((_, ValueType::Nil)) => Result::Ok(Vec::new()),
+            Ok((_, value)) => Result::Ok(vec![value.display(&self.vm.interner)]),
+            Err(e) => Result::RuntimeErr(e),
+        }
+    }
+
+    /// Runs a `:command`, returning the text to show
+    pub fn command(&self, line: &str) -> String {
+        let (command, code) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match command {
+            ":tokens" => {
+                let tokens = Lexer::new(code.to_string()).tokens;
+                let tokens = tokens.iter().rev().map(|token| format!("{:?}", token));
+                tokens.collect::<Vec<_>>().join("\n")
+            }
+            ":ast" => match parse(code) {
+                Ok(ast) => ast.iter().map(|stmt| ast_to_ascii(stmt, 0)).collect(),
+                Err(errors) => diagnostics::render_all(&errors, false),
+            },
+            ":bytecode" => {
+                // compiled against a copy of the interner, the session is left untouched
+                let mut compiler = Compiler::with_interner(self.vm.interner.clone());
+                let compiled = parse(code)
+                    .and_then(|ast| compiler.compile_interactive(ast).map_err(|e| vec![e]));
+                match compiled {
+                    Ok((chunk, interner)) => {
+                        debug::Debug::new("input", chunk, interner).disassemble()
+                    }
+                    Err(errors) => diagnostics::render_all(&errors, false),
+                }
+            }
+            ":help" => HELP.to_string(),
+            _ => format!("Unknown command '{}', see :help", command),
+        }
+    }
+}
+
+fn parse(src: &str) -> std::result::Result<Vec<ASTNode>, Vec<Diagnostic>> {
+    let mut lexer = Lexer::new(src.to_string());
+    Parser::new(&mut lexer)
+        .parse()
+        .map_err(|errors| from_parse_errors(&errors, Rc::new(Source::new(SOURCE_NAME, src))))
+}
+
+/// Whether `input` opens more brackets than it closes, so it goes on over the next line
+fn is_incomplete(input: &str) -> bool {
+    // the code of a `:command` starts after its name
+    let code = match input.strip_prefix(':') {
+        Some(command) => command.split_once(char::is_whitespace).map_or("", |(_, code)| code),
+        None => input,
+    };
+
+    let depth = Lexer::new(code.to_string()).tokens.iter().fold(0, |depth, token| {
+        match token.token_type {
+            TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => depth + 1,
+            TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => depth - 1,
+            _ => depth,
+        }
+    });
+    depth > 0
+}
+
+pub fn run() {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("Can't start the REPL: {}", e);
+            return;
+        }
+    };
+
+    println!("grad {}, type :help for commands", env!("CARGO_PKG_VERSION"));
+    let mut session = Session::new();
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() { ">>> " } else { "... " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                input.push_str(&line);
+                input.push('\n');
+                if is_incomplete(&input) {
+                    continue;
+                }
+
+                let input = std::mem::take(&mut input);
+                let input = input.trim();
+                if input.is_empty() {
+                    continue;
+                }
+                let _ = editor.add_history_entry(input);
+
+                match input {
+                    ":quit" | ":q" => break,
+                    command if command.starts_with(':') => println!("{}", session.command(command)),
+                    code => match session.eval(code) {
+                        Result::Ok(values) => values.iter().for_each(|value| println!("{}", value)),
+                        Result::CompileErr(errors) => {
+                            let color = std::io::stderr().is_terminal();
+                            eprintln!("{}", diagnostics::render_all(&errors, color))
+                        }
+                        Result::RuntimeErr(e) => {
+                            eprintln!("{}", e.render(std::io::stderr().is_terminal()))
+                        }
+                    },
+                }
+            }
+            // Ctrl-C drops the input being typed, Ctrl-D leaves
+            Err(ReadlineError::Interrupted) => input.clear(),
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("{}", e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_globals_persist_across_inputs() {
+        let mut session = Session::new();
+        let ok = |values: &[&str]| Result::Ok(values.iter().map(|v| v.to_string()).collect());
+
+        assert_eq!(session.eval("let a = 2.0;"), ok(&[]));
+        assert_eq!(session.eval("fn double(x) { return x * 2; }"), ok(&[]));
+        assert_eq!(session.eval("double(a) + 1"), ok(&["5"]));
+        assert_eq!(session.eval("a = a * 10;"), ok(&[]));
+        assert_eq!(session.eval("let s = \"grad\";"), ok(&[]));
+        assert_eq!(session.eval("[a, s, ones([2])]"), ok(&["[20, grad, [1, 1]]"]));
+        assert_eq!(session.eval("print(a)"), ok(&[]));
+
+        assert_eq!(
+            session.eval("b + 1").to_string(),
+            "Runtime error : <repl>:1:1: Undefined variable 'b'"
+        );
+        // the session carries on after an error
+        assert_eq!(session.eval("a"), ok(&["20"]));
+    }
+
+    #[test]
+    fn test_multi_line_input() {
+        assert!(is_incomplete("fn f(x) {\n"));
+        assert!(is_incomplete("while (i < 3) {\n  print([i,\n"));
+        assert!(!is_incomplete("fn f(x) {\n  return x;\n}\n"));
+        assert!(is_incomplete(":ast if (true) {"));
+        assert!(!is_incomplete(":help"));
+
+        let mut session = Session::new();
+        let input = "fn f(x) {\n  return x + 1;\n}\nf(1)";
+        assert_eq!(session.eval(input), Result::Ok(vec!["2".to_string()]));
+    }
+
+    #[test]
+    fn test_commands() {
+        let session = Session::new();
+
+        assert!(session.command(":tokens let a = 1;").starts_with("Token { token_type: LET"));
+        assert!(session.command(":ast 1 + 2").contains("IntNumber(1)"));
+        assert!(session.command(":bytecode 1 + 2").contains("OP_ADD"));
+        assert_eq!(session.command(":help"), HELP);
+        assert_eq!(session.command(":run"), "Unknown command ':run', see :help");
+    }
+}
@@ -135,6 +135,9 @@ pub enum TokenType {
     #[token("nil")]
     NIL,
 
+    #[token("no_grad")]
+    NoGrad,
+
     #[token("or")]
     OR,
 
@@ -168,6 +171,12 @@ pub struct Token {
 
 pub struct Lexer {
     pub tokens: Vec<Token>,
+
+    // length of the source, the EOF token sits right after its last byte
+    len: usize,
+
+    // span of the last token returned by `next`, where the node being parsed ends so far
+    previous: std::ops::Range<usize>,
 }
 
 impl Lexer {
@@ -195,29 +204,36 @@ impl Lexer {
 
         tokens.reverse();
 
-        Lexer { tokens }
+        Lexer {
+            tokens,
+            len: source.len(),
+            previous: 0..0,
+        }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Token {
-        self.tokens.pop().unwrap_or(Token {
-            token_type: TokenType::EOF,
-            lexeme: String::new(),
-            // literal: None,
-            span: 0..0,
-        })
+        let token = self.tokens.pop().unwrap_or_else(|| self.eof());
+        self.previous = token.span.clone();
+        token
     }
 
     pub fn peek(&self) -> Token {
-        self.tokens
-            .last()
-            .clone()
-            .unwrap_or(&Token {
-                token_type: TokenType::EOF,
-                lexeme: String::new(),
-                // literal: None,
-                span: 0..0,
-            })
-            .clone()
+        self.tokens.last().cloned().unwrap_or_else(|| self.eof())
+    }
+
+    /// Span of the last token returned by `next`
+    pub fn previous_span(&self) -> std::ops::Range<usize> {
+        self.previous.clone()
+    }
+
+    fn eof(&self) -> Token {
+        Token {
+            token_type: TokenType::EOF,
+            lexeme: String::new(),
+            // literal: None,
+            span: self.len..self.len,
+        }
     }
 
     pub fn peek_n_type(&self, n: usize) -> Vec<TokenType> {
@@ -0,0 +1,64 @@
+use serde::{Deserialize, Serialize};
+
+/// Byte range of a token or a node in its source, as given by the lexer
+pub type Span = std::ops::Range<usize>;
+
+/// A script along with the name it is reported under, e.g. its file path
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Self::new("script", "")
+    }
+}
+
+impl Source {
+    pub fn new(name: impl Into<String>, text: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            text: text.into(),
+        }
+    }
+
+    /// 1-based line and column of the byte at `offset`, columns count characters
+    pub fn line_column(&self, offset: usize) -> (usize, usize) {
+        let before = &self.text[..offset.min(self.text.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+        (line, column)
+    }
+
+    /// Text of the 1-based `line`, without its line break
+    pub fn line(&self, line: usize) -> &str {
+        self.text.lines().nth(line - 1).unwrap_or_default()
+    }
+
+    /// `name:line:column` of the start of `span`
+    pub fn locate(&self, span: &Span) -> String {
+        let (line, column) = self.line_column(span.start);
+        format!("{}:{}:{}", self.name, line, column)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_column() {
+        let source = Source::new("main.grad", "let a = 1;\nlet é = a;\n  print(b)");
+
+        assert_eq!(source.line_column(0), (1, 1));
+        assert_eq!(source.line_column(4), (1, 5));
+        assert_eq!(source.line_column(11), (2, 1));
+        // `é` is two bytes but a single column
+        assert_eq!(source.line_column(18), (2, 7));
+        assert_eq!(source.locate(&(31..32)), "main.grad:3:9");
+        // past the end, e.g. the EOF token
+        assert_eq!(source.line_column(100), (3, 11));
+    }
+}
@@ -3,25 +3,132 @@
 // https://rufflewind.com/2016-12-30/reverse-mode-automatic-differentiation
 // https://tiberiusferreira.github.io/blog/posts/designing_autograd_system_rust_first_steps/
 
+mod dot;
+mod dual;
+mod gradcheck;
+mod higher_order;
+mod loss;
+
+pub use dot::GraphNode;
+pub use dual::{jvp, Dual};
+pub use gradcheck::{gradcheck, GradcheckReport, Mismatch};
+
 use std::{
-    cell::{Ref, RefCell},
-    collections::HashSet,
-    rc::Rc,
+    cell::Cell,
+    cmp::Reverse,
+    collections::{BTreeMap, HashSet},
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
 };
+use thiserror::Error;
+
+/// Errors of tensor operations, the VM raises each kind as the runtime error of the same family
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum TensorError {
+    /// shapes that don't broadcast, or don't fit the operation
+    #[error("{0}")]
+    Shape(String),
+
+    /// an argument outside of the domain of the operation, e.g. `clamp` bounds with min > max
+    #[error("{0}")]
+    InvalidArgument(String),
+
+    /// an operation `backward_create_graph` can't differentiate through
+    #[error("{0}")]
+    Unsupported(String),
+}
+
+pub type TensorResult<T> = Result<T, TensorError>;
+
+thread_local! {
+    /// Number of live `NoGradGuard`s, operations only record the graph while it is 0
+    static NO_GRAD_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Disables graph recording on this thread until dropped, results of operations are plain leaves
+pub struct NoGradGuard(());
+
+pub fn no_grad() -> NoGradGuard {
+    NO_GRAD_DEPTH.with(|depth| depth.set(depth.get() + 1));
+    NoGradGuard(())
+}
+
+impl Drop for NoGradGuard {
+    fn drop(&mut self) {
+        NO_GRAD_DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+/// Records the graph again inside `no_grad` until dropped, for code that needs it to compute gradients
+pub struct EnableGradGuard {
+    // no_grad depth to restore
+    depth: usize,
+}
+
+pub fn enable_grad() -> EnableGradGuard {
+    EnableGradGuard {
+        depth: NO_GRAD_DEPTH.with(|depth| depth.replace(0)),
+    }
+}
+
+impl Drop for EnableGradGuard {
+    fn drop(&mut self) {
+        // `no_grad` guards entered meanwhile and not dropped yet still leave theirs once dropped
+        NO_GRAD_DEPTH.with(|depth| depth.set(self.depth + depth.get()));
+    }
+}
+
+pub fn is_grad_enabled() -> bool {
+    NO_GRAD_DEPTH.with(|depth| depth.get() == 0)
+}
+
+/// Clears the gradients of every tensor in `params`
+pub fn zero_grad(params: &[Tensor]) {
+    for param in params {
+        param.clear_gradient();
+    }
+}
 
-#[derive(Clone, Eq, PartialEq)]
-pub struct Tensor(Rc<RefCell<TensorInternal>>);
+/// A node of the graph, held as the tape it was recorded on and its position there.
+/// Tapes are shared with `Arc` and locked with a `Mutex`, so tensors can be sent to other threads
+#[derive(Clone)]
+pub struct Tensor {
+    tape: Arc<Tape>,
+    index: usize,
+}
 
 impl std::fmt::Display for Tensor {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.borrow().data)
+        let tensor = self.node();
+        write_nested(f, &tensor.data, &tensor.shape)
     }
 }
 
 // debug print
 impl std::fmt::Debug for Tensor {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.borrow().data)
+        write!(f, "{}", self)
+    }
+}
+
+/// Writes a row-major buffer as nested brackets, scalars are written as plain numbers
+fn write_nested(f: &mut std::fmt::Formatter, data: &[f64], shape: &[usize]) -> std::fmt::Result {
+    match shape.split_first() {
+        None => write!(f, "{}", data[0]),
+        Some((&len, inner)) => {
+            let step = inner.iter().product::<usize>();
+            write!(f, "[")?;
+            for i in 0..len {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_nested(f, &data[i * step..(i + 1) * step], inner)?;
+            }
+            write!(f, "]")
+        }
     }
 }
 
@@ -33,168 +140,902 @@ impl Tensor {
         t.into()
     }
 
-    pub fn new(tensor: TensorInternal) -> Self {
-        Tensor(Rc::new(RefCell::new(tensor)))
+    /// Creates a leaf tensor from a row-major buffer, `data` must hold exactly one value per element of `shape`
+    pub fn from_vec(data: Vec<f64>, shape: &[usize]) -> Tensor {
+        assert_eq!(
+            data.len(),
+            shape.iter().product::<usize>(),
+            "data does not match the shape {:?}",
+            shape
+        );
+        // leaves get a tape of their own, so parameters don't keep the graphs computed from them alive
+        let node = Node::new(data, shape.to_vec(), None);
+        Tensor {
+            tape: Tape::leaf(node),
+            index: 0,
+        }
+    }
+
+    pub fn zeros(shape: &[usize]) -> Tensor {
+        Tensor::from_vec(vec![0.0; shape.iter().product()], shape)
+    }
+
+    pub fn ones(shape: &[usize]) -> Tensor {
+        Tensor::from_vec(vec![1.0; shape.iter().product()], shape)
+    }
+
+    /// Creates the result of an operation, recording its inputs and how to propagate gradients to them.
+    /// Nothing is recorded while gradients are disabled, the result is then a new leaf
+    fn from_op(
+        data: Vec<f64>,
+        shape: Vec<usize>,
+        op: &'static str,
+        inputs: &[&Tensor],
+        propagate: PropagateFn,
+    ) -> Tensor {
+        Tensor::from_op_with_param(data, shape, op, inputs, 0.0, propagate)
+    }
+
+    /// Like `from_op`, for operations with a constant `param` their `PropagateFn` needs, e.g. a slope.
+    /// Propagate functions are plain `fn` pointers that can't capture anything, so the param is recorded
+    /// with the operation and read back from `Propagation::param`
+    fn from_op_with_param(
+        data: Vec<f64>,
+        shape: Vec<usize>,
+        op: &'static str,
+        inputs: &[&Tensor],
+        param: f64,
+        propagate: PropagateFn,
+    ) -> Tensor {
+        if !is_grad_enabled() {
+            return Tensor::from_vec(data, &shape);
+        }
+
+        let tape = tape_for(inputs);
+        let mut recorded = [None, None];
+        for (slot, input) in recorded.iter_mut().zip(inputs) {
+            *slot = Some(if Arc::ptr_eq(&input.tape, &tape) {
+                Input::Local(input.index)
+            } else {
+                Input::External((*input).clone())
+            });
+        }
+        let op = Op {
+            name: op,
+            inputs: recorded,
+            param,
+            propagate,
+        };
+
+        let index = {
+            let mut nodes = tape.lock();
+            nodes.push(Node::new(data, shape, Some(op)));
+            nodes.len() - 1
+        };
+        Tensor { tape, index }
+    }
+
+    /// Updates the data in place from the accumulated gradient, outside of the graph
+    pub fn update(&self, f: impl FnOnce(&mut [f64], &[f64])) {
+        let mut tensor = self.node_mut();
+        tensor.materialize_gradient();
+        let Node { data, gradient, .. } = &mut *tensor;
+        f(data, gradient);
     }
 
     pub fn adjust(&self, factor: f64) {
-        let mut value = self.borrow_mut();
-        value.data += factor * value.gradient;
+        let mut value = self.node_mut();
+        let Node { data, gradient, .. } = &mut *value;
+        for (d, g) in data.iter_mut().zip(gradient.iter()) {
+            *d += factor * g;
+        }
     }
 
+    /// Panics if the shapes don't broadcast, `checked_pow` returns an error instead
     pub fn pow(&self, other: &Tensor) -> Tensor {
-        let result = self.borrow().data.powf(other.borrow().data);
+        self.checked_pow(other).unwrap_or_else(|e| panic!("{}", e))
+    }
 
+    pub fn checked_pow(&self, other: &Tensor) -> TensorResult<Tensor> {
         let prop_fn: PropagateFn = |value| {
-            let mut base = value.previous[0].borrow_mut();
-            let power = value.previous[1].borrow();
-            base.gradient += power.data * (base.data.powf(power.data - 1.0)) * value.gradient;
+            propagate_binary(value, |b, p, result| {
+                // d/dp b^p = b^p * ln(b), only defined for positive bases
+                let power_grad = if b > 0.0 { result * b.ln() } else { 0.0 };
+                (p * b.powf(p - 1.0), power_grad)
+            })
         };
 
-        Tensor::new(TensorInternal::new(
-            result,
-            None,
-            Some("^".to_string()),
-            vec![self.clone(), other.clone()],
-            Some(prop_fn),
-        ))
+        binary_op(self, other, "^", f64::powf, prop_fn)
+    }
+
+    /// Element-wise comparison with broadcasting, giving 1 where `f` holds and 0 elsewhere.
+    /// The result is a new leaf, comparisons are not differentiable
+    pub fn compare(&self, other: &Tensor, f: fn(f64, f64) -> bool) -> TensorResult<Tensor> {
+        let (data, shape) = broadcast_zip(self, other, |x, y| f(x, y) as i32 as f64)?;
+        Ok(Tensor::from_vec(data, &shape))
+    }
+
+    /// `self + other`, or an error if the shapes don't broadcast
+    pub fn checked_add(&self, other: &Tensor) -> TensorResult<Tensor> {
+        add(self, other)
+    }
+
+    /// `self - other`, or an error if the shapes don't broadcast
+    pub fn checked_sub(&self, other: &Tensor) -> TensorResult<Tensor> {
+        sub(self, other)
+    }
+
+    /// `self * other`, or an error if the shapes don't broadcast
+    pub fn checked_mul(&self, other: &Tensor) -> TensorResult<Tensor> {
+        mul(self, other)
+    }
+
+    /// `self / other`, or an error if the shapes don't broadcast
+    pub fn checked_div(&self, other: &Tensor) -> TensorResult<Tensor> {
+        div(self, other)
     }
 
     pub fn tanh(&self) -> Tensor {
-        let result = self.borrow().data.tanh();
+        let prop_fn: PropagateFn = |value| propagate_unary(value, |_, t| 1.0 - t.powf(2.0));
+        self.unary_op("tanh", f64::tanh, prop_fn)
+    }
+
+    pub fn relu(&self) -> Tensor {
+        let prop_fn: PropagateFn = |value| propagate_unary(value, |x, _| (x > 0.0) as i32 as f64);
+        self.unary_op("relu", |x| x.max(0.0), prop_fn)
+    }
+
+    pub fn exp(&self) -> Tensor {
+        let prop_fn: PropagateFn = |value| propagate_unary(value, |_, e| e);
+        self.unary_op("exp", f64::exp, prop_fn)
+    }
+
+    pub fn log(&self) -> Tensor {
+        let prop_fn: PropagateFn = |value| propagate_unary(value, |x, _| 1.0 / x);
+        self.unary_op("log", f64::ln, prop_fn)
+    }
 
+    pub fn sqrt(&self) -> Tensor {
+        let prop_fn: PropagateFn = |value| propagate_unary(value, |_, s| 0.5 / s);
+        self.unary_op("sqrt", f64::sqrt, prop_fn)
+    }
+
+    pub fn abs(&self) -> Tensor {
+        // the subgradient at 0 is taken to be 0
         let prop_fn: PropagateFn = |value| {
-            let mut previous = value.previous[0].borrow_mut();
-            previous.gradient += (1.0 - value.data.powf(2.0)) * value.gradient;
+            propagate_unary(value, |x, _| if x == 0.0 { 0.0 } else { x.signum() })
         };
+        self.unary_op("abs", f64::abs, prop_fn)
+    }
 
-        Tensor::new(TensorInternal::new(
-            result,
-            None,
-            Some("tanh".to_string()),
-            vec![self.clone()],
-            Some(prop_fn),
-        ))
+    pub fn sin(&self) -> Tensor {
+        let prop_fn: PropagateFn = |value| propagate_unary(value, |x, _| x.cos());
+        self.unary_op("sin", f64::sin, prop_fn)
     }
 
-    pub fn relu(&self) -> Tensor {
-        let result = self.borrow().data.max(0.0);
+    pub fn cos(&self) -> Tensor {
+        let prop_fn: PropagateFn = |value| propagate_unary(value, |x, _| -x.sin());
+        self.unary_op("cos", f64::cos, prop_fn)
+    }
+
+    pub fn sigmoid(&self) -> Tensor {
+        let prop_fn: PropagateFn = |value| propagate_unary(value, |_, s| s * (1.0 - s));
+        self.unary_op("sigmoid", sigmoid, prop_fn)
+    }
+
+    /// `ln(1 + e^x)`, written as `max(x, 0) + ln(1 + e^-|x|)` so large inputs don't overflow
+    pub fn softplus(&self) -> Tensor {
+        let prop_fn: PropagateFn = |value| propagate_unary(value, |x, _| sigmoid(x));
+        self.unary_op("softplus", |x| x.max(0.0) + (-x.abs()).exp().ln_1p(), prop_fn)
+    }
+
+    /// GELU using the tanh approximation `0.5 x (1 + tanh(sqrt(2 / pi) (x + 0.044715 x^3)))`
+    pub fn gelu(&self) -> Tensor {
+        let prop_fn: PropagateFn = |value| {
+            propagate_unary(value, |x, _| {
+                let t = gelu_inner(x).tanh();
+                0.5 * (1.0 + t) + 0.5 * x * (1.0 - t * t) * GELU_C * (1.0 + 3.0 * 0.044715 * x * x)
+            })
+        };
+        self.unary_op("gelu", |x| 0.5 * x * (1.0 + gelu_inner(x).tanh()), prop_fn)
+    }
+
+    pub fn leaky_relu(&self, slope: f64) -> Tensor {
+        let data = self.map(|x| if x > 0.0 { x } else { slope * x });
+
+        let prop_fn: PropagateFn = |value| {
+            let slope = value.param;
+            let grad = value
+                .input(0)
+                .data
+                .iter()
+                .zip(value.gradient)
+                .map(|(x, g)| if *x > 0.0 { *g } else { slope * g })
+                .collect();
+            [Some(grad), None]
+        };
+
+        Tensor::from_op_with_param(data, self.shape(), "leaky_relu", &[self], slope, prop_fn)
+    }
+
+    /// Limits every element to `[min, max]`, the gradient only flows through elements that were not clamped
+    pub fn clamp(&self, min: f64, max: f64) -> TensorResult<Tensor> {
+        // `f64::clamp` panics on these
+        if min.is_nan() || max.is_nan() || min > max {
+            let message = format!("clamp expects min <= max, got {} and {}", min, max);
+            return Err(TensorError::InvalidArgument(message));
+        }
+        let data = self.map(|x| x.clamp(min, max));
+
+        let prop_fn: PropagateFn = |value| propagate_unary(value, |x, c| (x == c) as i32 as f64);
+        Ok(Tensor::from_op(data, self.shape(), "clamp", &[self], prop_fn))
+    }
+
+    /// Softmax along `axis`, shifted by the maximum so exponentials can't overflow
+    pub fn softmax(&self, axis: isize) -> TensorResult<Tensor> {
+        let exp = self.shifted_by_max(axis)?.exp();
+        Ok(exp.clone() / exp.sum(Some(axis), true)?)
+    }
+
+    /// Logarithm of `softmax` along `axis`, computed as `x - max - log(sum(exp(x - max)))`
+    pub fn log_softmax(&self, axis: isize) -> TensorResult<Tensor> {
+        let shifted = self.shifted_by_max(axis)?;
+        let log_sum = shifted.exp().sum(Some(axis), true)?.log();
+        Ok(shifted - log_sum)
+    }
+
+    /// `self` minus its maximum along `axis`, the maximum is a constant so no gradient flows into it
+    fn shifted_by_max(&self, axis: isize) -> TensorResult<Tensor> {
+        let max = self.max(Some(axis), true)?;
+        let max = Tensor::from_vec(max.data(), &max.shape());
+        Ok(self.clone() - max)
+    }
+
+    /// Matrix product of vectors and matrices, following numpy's `@` for 1-D and 2-D operands:
+    /// vectors are treated as a row on the left and as a column on the right, and that axis is dropped again
+    pub fn matmul(&self, other: &Tensor) -> TensorResult<Tensor> {
+        let (a_shape, b_shape) = (self.shape(), other.shape());
+        let (m, n, p) = matmul_dims(&a_shape, &b_shape).ok_or_else(|| {
+            TensorError::Shape(format!(
+                "Shape mismatch for matrix multiplication: {:?} @ {:?}",
+                a_shape, b_shape
+            ))
+        })?;
+
+        let result = with_nodes(self, other, |a, b| {
+            matmul_buffers(&a.data, &b.data, m, n, p, false, false)
+        });
+
+        let mut shape = Vec::new();
+        if a_shape.len() == 2 {
+            shape.push(m);
+        }
+        if b_shape.len() == 2 {
+            shape.push(p);
+        }
+
+        let prop_fn: PropagateFn = |value| {
+            let (a, b) = (value.input(0), value.input(1));
+            let (m, n, p) = matmul_dims(&a.shape, &b.shape).expect("checked in the forward pass");
+            // dA = dC @ B^T, dB = A^T @ dC
+            let a_grad = matmul_buffers(value.gradient, &b.data, m, p, n, false, true);
+            let b_grad = matmul_buffers(&a.data, value.gradient, n, m, p, true, false);
+            [Some(a_grad), Some(b_grad)]
+        };
+
+        Ok(Tensor::from_op(result, shape, "@", &[self, other], prop_fn))
+    }
+
+    /// Swaps the two axes of a matrix
+    pub fn transpose(&self) -> TensorResult<Tensor> {
+        let shape = self.shape();
+        let [rows, cols] = shape[..] else {
+            let message = format!("Can only transpose matrices, got a tensor of shape {:?}", shape);
+            return Err(TensorError::Shape(message));
+        };
+
+        let prop_fn: PropagateFn = |value| {
+            [Some(transposed(value.gradient, value.shape[0], value.shape[1])), None]
+        };
+
+        let data = transposed(&self.node().data, rows, cols);
+        Ok(Tensor::from_op(data, vec![cols, rows], "transpose", &[self], prop_fn))
+    }
+
+    /// Same elements in a new shape holding the same number of elements
+    pub fn reshape(&self, shape: &[usize]) -> TensorResult<Tensor> {
+        if shape.iter().product::<usize>() != self.len() {
+            return Err(TensorError::Shape(format!(
+                "Can't reshape a tensor of shape {:?} into {:?}",
+                self.shape(),
+                shape
+            )));
+        }
 
+        let prop_fn: PropagateFn = |value| [Some(value.gradient.to_vec()), None];
+        Ok(Tensor::from_op(self.data(), shape.to_vec(), "reshape", &[self], prop_fn))
+    }
+
+    pub fn sum(&self, axis: Option<isize>, keepdims: bool) -> TensorResult<Tensor> {
+        let prop_fn: PropagateFn = |value| {
+            let grad = broadcast_indices(value.shape, &value.input(0).shape)
+                .into_iter()
+                .map(|j| value.gradient[j])
+                .collect();
+            [Some(grad), None]
+        };
+
+        self.reduce(axis, keepdims, "sum", 0.0, |acc, x| acc + x, prop_fn)
+    }
+
+    pub fn mean(&self, axis: Option<isize>, keepdims: bool) -> TensorResult<Tensor> {
+        let sum = self.sum(axis, keepdims)?;
+        let count = self.len() / sum.len().max(1);
+        Ok(sum / Tensor::from(count as f64))
+    }
+
+    pub fn prod(&self, axis: Option<isize>, keepdims: bool) -> TensorResult<Tensor> {
         let prop_fn: PropagateFn = |value| {
-            let mut previous = value.previous[0].borrow_mut();
-            previous.gradient += (value.data > 0.0) as i32 as f64 * value.gradient;
+            let input = value.input(0);
+            let indices = broadcast_indices(value.shape, &input.shape);
+            let grad = indices
+                .iter()
+                .enumerate()
+                .map(|(i, j)| {
+                    // the product of the other elements, recomputed when dividing by this one is undefined
+                    let others = if input.data[i] != 0.0 {
+                        value.data[*j] / input.data[i]
+                    } else {
+                        (0..indices.len())
+                            .filter(|k| *k != i && indices[*k] == *j)
+                            .map(|k| input.data[k])
+                            .product()
+                    };
+                    others * value.gradient[*j]
+                })
+                .collect();
+            [Some(grad), None]
+        };
+
+        self.reduce(axis, keepdims, "prod", 1.0, |acc, x| acc * x, prop_fn)
+    }
+
+    pub fn max(&self, axis: Option<isize>, keepdims: bool) -> TensorResult<Tensor> {
+        self.reduce(axis, keepdims, "max", f64::NEG_INFINITY, f64::max, propagate_extremum)
+    }
+
+    pub fn min(&self, axis: Option<isize>, keepdims: bool) -> TensorResult<Tensor> {
+        self.reduce(axis, keepdims, "min", f64::INFINITY, f64::min, propagate_extremum)
+    }
+
+    /// Index of the first largest element along `axis`, or into the flattened tensor if `None`.
+    /// Indices are not differentiable, so the result is a new leaf
+    pub fn argmax(&self, axis: Option<isize>, keepdims: bool) -> TensorResult<Tensor> {
+        let (reduced, shape) = reduced_shapes(&self.shape(), axis, keepdims)?;
+
+        let tensor = self.node();
+        let mut max = vec![f64::NEG_INFINITY; reduced.iter().product()];
+        let mut argmax = vec![0.0; max.len()];
+        // elements of a group are visited in order along the reduced axis
+        let mut position = vec![0; max.len()];
+        for (x, j) in tensor.data.iter().zip(broadcast_indices(&reduced, &tensor.shape)) {
+            if *x > max[j] {
+                max[j] = *x;
+                argmax[j] = position[j] as f64;
+            }
+            position[j] += 1;
+        }
+
+        Ok(Tensor::from_vec(argmax, &shape))
+    }
+
+    /// Folds the elements along `axis`, or all of them if `None`, with `f` starting from `init`.
+    /// The reduction itself keeps the reduced axes with size 1 so `propagate` can broadcast the gradient back,
+    /// they are dropped afterwards unless `keepdims` is set
+    fn reduce(
+        &self,
+        axis: Option<isize>,
+        keepdims: bool,
+        op: &'static str,
+        init: f64,
+        f: fn(f64, f64) -> f64,
+        propagate: PropagateFn,
+    ) -> TensorResult<Tensor> {
+        let (reduced, shape) = reduced_shapes(&self.shape(), axis, keepdims)?;
+
+        let data = {
+            let tensor = self.node();
+            let mut data = vec![init; reduced.iter().product()];
+            for (x, j) in tensor.data.iter().zip(broadcast_indices(&reduced, &tensor.shape)) {
+                data[j] = f(data[j], *x);
+            }
+            data
         };
 
-        Tensor::new(TensorInternal::new(
-            result,
-            None,
-            Some("relu".to_string()),
-            vec![self.clone()],
-            Some(prop_fn),
-        ))
+        let result = Tensor::from_op(data, reduced, op, &[self], propagate);
+        if keepdims {
+            Ok(result)
+        } else {
+            result.reshape(&shape)
+        }
+    }
+
+    pub fn shape(&self) -> Vec<usize> {
+        self.node().shape.clone()
+    }
+
+    pub fn strides(&self) -> Vec<usize> {
+        contiguous_strides(&self.node().shape)
+    }
+
+    /// Number of elements
+    pub fn len(&self) -> usize {
+        self.node().data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn data(&self) -> Vec<f64> {
+        self.node().data.clone()
     }
 
-    pub fn gradient(&self) -> f64 {
-        self.borrow().gradient
+    /// The value of a single-element tensor
+    pub fn item(&self) -> Option<f64> {
+        let tensor = self.node();
+        (tensor.data.len() == 1).then(|| tensor.data[0])
+    }
+
+    pub fn gradient(&self) -> Vec<f64> {
+        self.node().gradient_or_zeros()
+    }
+
+    /// The gradient as a new leaf tensor of the same shape
+    pub fn grad(&self) -> Tensor {
+        if let Some(gradient) = &self.node().tracked_gradient {
+            return gradient.clone();
+        }
+        Tensor::from_vec(self.gradient(), &self.shape())
+    }
+
+    /// A new leaf holding the same data, cut from the graph that computed `self`
+    pub fn detach(&self) -> Tensor {
+        let tensor = self.node();
+        Tensor::from_vec(tensor.data.clone(), &tensor.shape)
     }
 
     pub fn clear_gradient(&self) {
-        self.borrow_mut().gradient = 0.0;
+        let mut tensor = self.node_mut();
+        tensor.gradient.fill(0.0);
+        tensor.tracked_gradient = None;
     }
 
+    /// Computes the gradient of every node this tensor was computed from, including itself. Every gradient
+    /// accumulates over several passes until it is cleared, like the gradients of leaves
     pub fn backward(&self) {
-        let mut visited: HashSet<Tensor> = HashSet::new();
+        higher_order::release_tracked_gradients();
+        let ones = vec![1.0; self.len()];
+        if self.is_leaf() {
+            self.accumulate_gradient(&ones);
+            return;
+        }
 
-        self.borrow_mut().gradient = 1.0;
-        self.backward_internal(&mut visited, self);
-    }
+        let mut gradients = vec![None; self.index + 1];
+        gradients[self.index] = Some(ones);
+        let mut pending = PendingTapes::new();
+        pending.insert(self.tape.rank, (self.tape.clone(), gradients));
 
-    fn backward_internal(&self, visited: &mut HashSet<Tensor>, tensor: &Tensor) {
-        if !visited.contains(&tensor) {
-            visited.insert(tensor.clone());
+        // a tape only refers to tapes of a lower rank besides leaves, so once the tape of highest rank is
+        // reached, every gradient flowing into it has arrived
+        while let Some((_, (tape, mut gradients))) = pending.pop_last() {
+            propagate_tape(&tape, &mut gradients, &mut pending);
 
-            let borrowed_value = tensor.borrow();
-            if let Some(prop_fn) = borrowed_value.propagate {
-                prop_fn(&borrowed_value);
+            // the graph is complete, operations on its nodes now start a new tape instead of growing this one
+            tape.sealed.store(true, Ordering::Relaxed);
+            for (node, gradient) in tape.lock().iter_mut().zip(gradients) {
+                if let Some(gradient) = gradient {
+                    node.accumulate(&gradient);
+                }
             }
+        }
+    }
 
-            for child_id in &tensor.borrow().previous {
-                self.backward_internal(visited, child_id);
+    /// Every node reachable from `self`, each one placed after all of its inputs: leaves first in the order
+    /// they were created, then the results of operations by the rank of their tape and their position on it
+    fn topological_order(&self) -> Vec<Tensor> {
+        let mut visited = HashSet::new();
+        let mut topo = Vec::new();
+        let mut stack = vec![self.clone()];
+        while let Some(tensor) = stack.pop() {
+            if visited.insert(tensor.key()) {
+                stack.extend(tensor.inputs());
+                topo.push(tensor);
             }
         }
+
+        topo.sort_by_cached_key(|tensor| (!tensor.is_leaf(), tensor.tape.rank, tensor.index));
+        topo
+    }
+
+    /// Identifies the node, for as long as a tensor holds on to its tape
+    fn key(&self) -> (*const Tape, usize) {
+        (Arc::as_ptr(&self.tape), self.index)
+    }
+
+    /// The node, with its tape locked until the guard is dropped
+    fn node(&self) -> NodeGuard<'_> {
+        NodeGuard {
+            nodes: self.tape.lock(),
+            index: self.index,
+        }
+    }
+
+    fn node_mut(&self) -> NodeGuard<'_> {
+        self.node()
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.node().op.is_none()
+    }
+
+    /// Name of the operation that computed this tensor, `None` for leaves
+    fn operation(&self) -> Option<&'static str> {
+        self.node().op.as_ref().map(|op| op.name)
+    }
+
+    /// The constant recorded with the operation that computed this tensor, see `from_op_with_param`
+    fn param(&self) -> f64 {
+        self.node().op.as_ref().map_or(0.0, |op| op.param)
+    }
+
+    /// Inputs of the operation that computed this tensor, none for leaves
+    fn inputs(&self) -> Vec<Tensor> {
+        let tensor = self.node();
+        let Some(op) = &tensor.op else {
+            return Vec::new();
+        };
+        let inputs = op.inputs.iter().flatten().map(|input| match input {
+            Input::Local(index) => Tensor {
+                tape: self.tape.clone(),
+                index: *index,
+            },
+            Input::External(tensor) => tensor.clone(),
+        });
+        inputs.collect()
+    }
+
+    /// Adds `gradient` element-wise to the accumulated gradient
+    fn accumulate_gradient(&self, gradient: &[f64]) {
+        self.node_mut().accumulate(gradient);
+    }
+
+    fn map(&self, f: impl Fn(f64) -> f64) -> Vec<f64> {
+        self.node().data.iter().map(|x| f(*x)).collect()
+    }
+
+    /// A new leaf computed element-wise, for derivatives that are constant almost everywhere
+    fn map_leaf(&self, f: impl Fn(f64) -> f64) -> Tensor {
+        Tensor::from_vec(self.map(f), &self.shape())
+    }
+
+    fn unary_op(&self, op: &'static str, f: fn(f64) -> f64, propagate: PropagateFn) -> Tensor {
+        Tensor::from_op(self.map(f), self.shape(), op, &[self], propagate)
     }
 }
 
-fn add(a: &Tensor, b: &Tensor) -> Tensor {
-    let result = a.borrow().data + b.borrow().data;
+/// The `cols x rows` transpose of a row-major `rows x cols` buffer
+fn transposed(data: &[f64], rows: usize, cols: usize) -> Vec<f64> {
+    (0..rows * cols).map(|k| data[(k % rows) * cols + k / rows]).collect()
+}
+
+/// `(m, n, p)` of the `m x n` by `n x p` product, with a vector on the left as `1 x n` and on the right as `n x 1`
+fn matmul_dims(a: &[usize], b: &[usize]) -> Option<(usize, usize, usize)> {
+    let (m, n) = match a {
+        [n] => (1, *n),
+        [m, n] => (*m, *n),
+        _ => return None,
+    };
+    let (k, p) = match b {
+        [k] => (*k, 1),
+        [k, p] => (*k, *p),
+        _ => return None,
+    };
+    (n == k).then_some((m, n, p))
+}
 
-    let prop_fn: PropagateFn = |value| {
-        let mut first = value.previous[0].borrow_mut();
-        let mut second = value.previous[1].borrow_mut();
+/// Multiplies row-major `m x n` and `n x p` buffers, reading either of them transposed when asked to,
+/// i.e. `a` is stored as `n x m` when `transpose_a` is set
+fn matmul_buffers(
+    a: &[f64],
+    b: &[f64],
+    m: usize,
+    n: usize,
+    p: usize,
+    transpose_a: bool,
+    transpose_b: bool,
+) -> Vec<f64> {
+    let mut out = vec![0.0; m * p];
+    for i in 0..m {
+        for k in 0..n {
+            let a_ik = if transpose_a { a[k * m + i] } else { a[i * n + k] };
+            for j in 0..p {
+                let b_kj = if transpose_b { b[j * n + k] } else { b[k * p + j] };
+                out[i * p + j] += a_ik * b_kj;
+            }
+        }
+    }
+    out
+}
 
-        first.gradient += value.gradient;
-        second.gradient += value.gradient;
+/// Shapes of reducing `shape` along `axis`, or along every axis if `None`: with the reduced axes kept
+/// with size 1, and the shape of the result. Negative axes count from the last one
+fn reduced_shapes(
+    shape: &[usize],
+    axis: Option<isize>,
+    keepdims: bool,
+) -> TensorResult<(Vec<usize>, Vec<usize>)> {
+    let Some(axis) = axis else {
+        let reduced = vec![1; shape.len()];
+        let result = if keepdims { reduced.clone() } else { Vec::new() };
+        return Ok((reduced, result));
     };
 
-    Tensor::new(TensorInternal::new(
-        result,
-        None,
-        Some("+".to_string()),
-        vec![a.clone(), b.clone()],
-        Some(prop_fn),
-    ))
+    let ndim = shape.len() as isize;
+    let normalized = if axis < 0 { axis + ndim } else { axis };
+    if !(0..ndim).contains(&normalized) {
+        return Err(TensorError::Shape(format!(
+            "Axis {} is out of bounds for a tensor of shape {:?}",
+            axis, shape
+        )));
+    }
+
+    let mut reduced = shape.to_vec();
+    reduced[normalized as usize] = 1;
+    let mut result = shape.to_vec();
+    if keepdims {
+        result[normalized as usize] = 1;
+    } else {
+        result.remove(normalized as usize);
+    }
+    Ok((reduced, result))
+}
+
+/// Propagates the gradient of an element-wise operation, `derivative` is computed from `(x, result)`
+fn propagate_unary(value: &Propagation, derivative: impl Fn(f64, f64) -> f64) -> InputGradients {
+    let grad = value
+        .input(0)
+        .data
+        .iter()
+        .zip(value.data)
+        .zip(value.gradient)
+        .map(|((x, y), g)| derivative(*x, *y) * g)
+        .collect();
+    [Some(grad), None]
+}
+
+/// Logistic function, split at 0 so the exponential never overflows
+fn sigmoid(x: f64) -> f64 {
+    if x >= 0.0 {
+        1.0 / (1.0 + (-x).exp())
+    } else {
+        let e = x.exp();
+        e / (1.0 + e)
+    }
+}
+
+/// `sqrt(2 / pi)`
+const GELU_C: f64 = 0.7978845608028654;
+
+fn gelu_inner(x: f64) -> f64 {
+    GELU_C * (x + 0.044715 * x.powi(3))
+}
+
+/// Derivative of `gelu` written with tensor operations, for the derivatives that are tensors themselves
+fn gelu_derivative(x: &Tensor) -> Tensor {
+    let c = |value: f64| Tensor::from(value);
+    let square = x.clone() * x.clone();
+    let t = (c(GELU_C) * (x.clone() + c(0.044715) * square.clone() * x.clone())).tanh();
+    let slope = c(GELU_C) * (c(1.0) + c(3.0 * 0.044715) * square);
+    c(0.5) * (c(1.0) + t.clone()) + c(0.5) * x.clone() * (c(1.0) - t.clone() * t) * slope
 }
 
-fn mul(a: &Tensor, b: &Tensor) -> Tensor {
-    let result = a.borrow().data * b.borrow().data;
+/// 1 at the first element of each group of `x` equal to the `extremum` of that group, which keeps the reduced axes
+fn first_extremum_mask(x: &Tensor, extremum: &Tensor) -> Tensor {
+    let (mask, shape) = with_nodes(x, extremum, |input, result| {
+        let mut routed = vec![false; result.data.len()];
+        let mask = input
+            .data
+            .iter()
+            .zip(broadcast_indices(&result.shape, &input.shape))
+            .map(|(x, j)| {
+                let first = !routed[j] && *x == result.data[j];
+                routed[j] |= first;
+                first as i32 as f64
+            })
+            .collect::<Vec<_>>();
+        (mask, input.shape.clone())
+    });
+    Tensor::from_vec(mask, &shape)
+}
 
-    let prop_fn: PropagateFn = |value| {
-        let mut first = value.previous[0].borrow_mut();
-        let mut second = value.previous[1].borrow_mut();
+/// Propagates the gradient of `max` and `min` to the first element of each group equal to the result
+fn propagate_extremum(value: &Propagation) -> InputGradients {
+    let input = value.input(0);
+    let mut routed = vec![false; value.data.len()];
+    let grad = input
+        .data
+        .iter()
+        .zip(broadcast_indices(value.shape, &input.shape))
+        .map(|(x, j)| {
+            if !routed[j] && *x == value.data[j] {
+                routed[j] = true;
+                value.gradient[j]
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    [Some(grad), None]
+}
 
-        first.gradient += second.data * value.gradient;
-        second.gradient += first.data * value.gradient;
+/// Shape both operands of an element-wise operation are broadcast to, following numpy's rules:
+/// shapes are aligned at their last axis and each pair of sizes must either match or contain a 1
+pub fn broadcast_shape(a: &[usize], b: &[usize]) -> TensorResult<Vec<usize>> {
+    let len = a.len().max(b.len());
+    let size = |shape: &[usize], axis: usize| {
+        (axis + shape.len())
+            .checked_sub(len)
+            .map_or(1, |axis| shape[axis])
     };
 
-    Tensor::new(TensorInternal::new(
-        result,
-        None,
-        Some("*".to_string()),
-        vec![a.clone(), b.clone()],
-        Some(prop_fn),
-    ))
+    (0..len)
+        .map(|axis| match (size(a, axis), size(b, axis)) {
+            (x, y) if x == y || y == 1 => Ok(x),
+            (1, y) => Ok(y),
+            _ => Err(TensorError::Shape(format!("Can't broadcast shapes {:?} and {:?}", a, b))),
+        })
+        .collect()
+}
+
+/// For every element of a `target` shaped buffer, the index of the element of a `shape` shaped
+/// buffer broadcast onto it
+fn broadcast_indices(shape: &[usize], target: &[usize]) -> Vec<usize> {
+    let offset = target.len() - shape.len();
+    let contiguous = contiguous_strides(shape);
+    // a stride of 0 repeats the same elements along the axes being broadcast
+    let strides = (0..target.len())
+        .map(|axis| match axis.checked_sub(offset) {
+            Some(axis) if shape[axis] != 1 => contiguous[axis],
+            _ => 0,
+        })
+        .collect::<Vec<_>>();
+
+    let len = target.iter().product::<usize>();
+    let mut indices = Vec::with_capacity(len);
+    let mut position = vec![0; target.len()];
+    for _ in 0..len {
+        indices.push(position.iter().zip(&strides).map(|(p, s)| p * s).sum());
+        for axis in (0..target.len()).rev() {
+            position[axis] += 1;
+            if position[axis] < target[axis] {
+                break;
+            }
+            position[axis] = 0;
+        }
+    }
+    indices
+}
+
+/// Applies `f` to every pair of elements of the operands broadcast to their common shape
+fn broadcast_zip(
+    a: &Tensor,
+    b: &Tensor,
+    f: impl Fn(f64, f64) -> f64,
+) -> TensorResult<(Vec<f64>, Vec<usize>)> {
+    with_nodes(a, b, |a, b| {
+        let shape = broadcast_shape(&a.shape, &b.shape)?;
+        let (a_indices, b_indices) = (
+            broadcast_indices(&a.shape, &shape),
+            broadcast_indices(&b.shape, &shape),
+        );
+        let data = a_indices
+            .iter()
+            .zip(&b_indices)
+            .map(|(i, j)| f(a.data[*i], b.data[*j]))
+            .collect();
+        Ok((data, shape))
+    })
+}
+
+/// Creates the result of a broadcasting element-wise operation
+fn binary_op(
+    a: &Tensor,
+    b: &Tensor,
+    op: &'static str,
+    f: fn(f64, f64) -> f64,
+    propagate: PropagateFn,
+) -> TensorResult<Tensor> {
+    let (data, shape) = broadcast_zip(a, b, f)?;
+    Ok(Tensor::from_op(data, shape, op, &[a, b], propagate))
+}
+
+/// Propagates the gradient of a binary operation, `partials` gives the derivatives with respect to both inputs
+/// from `(x, y, result)`. An input that was broadcast sums the gradient over every element it was repeated to
+fn propagate_binary(
+    value: &Propagation,
+    partials: impl Fn(f64, f64, f64) -> (f64, f64),
+) -> InputGradients {
+    let (a, b) = (value.input(0), value.input(1));
+    let (a_indices, b_indices) = (
+        broadcast_indices(&a.shape, value.shape),
+        broadcast_indices(&b.shape, value.shape),
+    );
+
+    let mut a_grad = vec![0.0; a.data.len()];
+    let mut b_grad = vec![0.0; b.data.len()];
+    for (k, (i, j)) in a_indices.into_iter().zip(b_indices).enumerate() {
+        let (da, db) = partials(a.data[i], b.data[j], value.data[k]);
+        a_grad[i] += da * value.gradient[k];
+        b_grad[j] += db * value.gradient[k];
+    }
+    [Some(a_grad), Some(b_grad)]
+}
+
+fn add(a: &Tensor, b: &Tensor) -> TensorResult<Tensor> {
+    let prop_fn: PropagateFn = |value| propagate_binary(value, |_, _, _| (1.0, 1.0));
+    binary_op(a, b, "+", |x, y| x + y, prop_fn)
+}
+
+fn sub(a: &Tensor, b: &Tensor) -> TensorResult<Tensor> {
+    let prop_fn: PropagateFn = |value| propagate_binary(value, |_, _, _| (1.0, -1.0));
+    binary_op(a, b, "-", |x, y| x - y, prop_fn)
+}
+
+fn mul(a: &Tensor, b: &Tensor) -> TensorResult<Tensor> {
+    let prop_fn: PropagateFn = |value| propagate_binary(value, |x, y, _| (y, x));
+    binary_op(a, b, "*", |x, y| x * y, prop_fn)
 }
 
+fn div(a: &Tensor, b: &Tensor) -> TensorResult<Tensor> {
+    // d/dy x/y = -(x/y) / y
+    let prop_fn: PropagateFn = |value| propagate_binary(value, |_, y, q| (1.0 / y, -q / y));
+    binary_op(a, b, "/", |x, y| x / y, prop_fn)
+}
+
+/// Panics if the shapes don't broadcast, `checked_add` returns an error instead
 impl std::ops::Add for Tensor {
     type Output = Self;
 
     fn add(self, other: Self) -> Self {
-        add(&self, &other)
+        add(&self, &other).unwrap_or_else(|e| panic!("{}", e))
     }
 }
 
+/// Panics if the shapes don't broadcast, `checked_sub` returns an error instead
 impl std::ops::Sub for Tensor {
     type Output = Self;
 
     fn sub(self, other: Self) -> Self {
-        add(&self, &(-other))
+        sub(&self, &other).unwrap_or_else(|e| panic!("{}", e))
     }
 }
 
+/// Panics if the shapes don't broadcast, `checked_mul` returns an error instead
 impl std::ops::Mul for Tensor {
     type Output = Self;
 
     fn mul(self, other: Self) -> Self {
-        mul(&self, &other)
+        mul(&self, &other).unwrap_or_else(|e| panic!("{}", e))
     }
 }
 
+/// Panics if the shapes don't broadcast, `checked_div` returns an error instead
 impl std::ops::Div for Tensor {
     type Output = Self;
 
     fn div(self, other: Self) -> Self {
-        // TODO: does this work?
-        mul(&self, &other.pow(&Tensor::from(-1)))
+        div(&self, &other).unwrap_or_else(|e| panic!("{}", e))
     }
 }
 
@@ -202,105 +1043,674 @@ impl std::ops::Neg for Tensor {
     type Output = Self;
 
     fn neg(self) -> Self {
-        mul(&self, &Tensor::from(-1))
+        let result = self.map(|x| -x);
+
+        let prop_fn: PropagateFn = |value| [Some(value.gradient.iter().map(|g| -g).collect()), None];
+        Tensor::from_op(result, self.shape(), "neg", &[&self], prop_fn)
     }
 }
 
-// impl PartialEq for Tensor {
-//     fn eq(&self, other: &Self) -> bool {
-//         self.data == other.data
-//     }
-// }
+/// Tensors are graph nodes, two tensors are the same only if they are the same node
+impl PartialEq for Tensor {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.tape, &other.tape) && self.index == other.index
+    }
+}
 
-// impl PartialOrd for Tensor {
-//     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-//         self.data.partial_cmp(&other.data)
-//     }
-// }
+impl Eq for Tensor {}
 
-impl std::hash::Hash for Tensor {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.0.borrow().hash(state);
+impl<T: Into<f64>> From<T> for Tensor {
+    fn from(t: T) -> Tensor {
+        Tensor::from_vec(vec![t.into()], &[])
     }
 }
 
-impl std::ops::Deref for Tensor {
-    type Target = Rc<RefCell<TensorInternal>>;
+////////////////////////////////////////////////////
+////////////////////////////////////////////////////
+
+/// Gradients of the inputs of an operation from the gradient of its result, `None` for inputs nothing flows into
+type PropagateFn = fn(value: &Propagation) -> InputGradients;
+
+/// One gradient per input of an operation, operations have at most two inputs
+type InputGradients = [Option<Vec<f64>>; 2];
+
+/// Rank of the next tape started because the tape of the inputs was sealed or full, above every tape so far
+static NEXT_RANK: AtomicI64 = AtomicI64::new(0);
+/// Rank of the next tape started by an operation on leaves only, below every tape so far
+static NEXT_LOW_RANK: AtomicI64 = AtomicI64::new(-1);
+/// Rank of the next tape of a leaf, increasing from below every other tape
+static NEXT_LEAF_RANK: AtomicI64 = AtomicI64::new(i64::MIN);
+
+/// Nodes recorded on a tape before operations move on to a new one, so a graph that stays alive without
+/// ever calling `backward`, e.g. an output kept in a global, doesn't grow a single tape without bound
+const MAX_TAPE_LEN: usize = 1 << 16;
+
+/// An arena of nodes in the order they were computed, the inputs of a node always come before it on its tape
+/// or are on a tape of lower rank, so `backward` walks tapes by rank instead of sorting the graph.
+///
+/// Every leaf gets a tape of its own, an operation on leaves only starts a new tape, and any other operation is
+/// appended to the tape of highest rank among its inputs. Unrelated graphs never share a tape, so a whole tape is
+/// freed at once when no tensor of its graph is left
+struct Tape {
+    rank: i64,
+    /// Holds a single leaf, operations on it are never appended to this tape
+    leaf: bool,
+    /// Set once `backward` walked the tape, later operations on its nodes start a new tape
+    sealed: AtomicBool,
+    nodes: Mutex<Vec<Node>>,
+}
+
+impl Tape {
+    /// A tape for operations, of the rank taken from `next`
+    fn new(next: &AtomicI64, step: i64) -> Arc<Tape> {
+        Arc::new(Tape {
+            rank: next.fetch_add(step, Ordering::Relaxed),
+            leaf: false,
+            sealed: AtomicBool::new(false),
+            nodes: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn leaf(node: Node) -> Arc<Tape> {
+        Arc::new(Tape {
+            rank: NEXT_LEAF_RANK.fetch_add(1, Ordering::Relaxed),
+            leaf: true,
+            sealed: AtomicBool::new(false),
+            nodes: Mutex::new(vec![node]),
+        })
+    }
+
+    /// A panic while a tape was locked leaves its nodes usable, so poisoning is ignored
+    fn lock(&self) -> MutexGuard<'_, Vec<Node>> {
+        self.nodes.lock().unwrap_or_else(PoisonError::into_inner)
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    /// Tapes are locked by decreasing rank, then by address, so threads locking the same tapes can't deadlock
+    fn locks_before(&self, other: &Tape) -> bool {
+        (Reverse(self.rank), self as *const Tape) < (Reverse(other.rank), other as *const Tape)
     }
 }
 
-impl<T: Into<f64>> From<T> for Tensor {
-    fn from(t: T) -> Tensor {
-        Tensor::new(TensorInternal::new(t.into(), None, None, Vec::new(), None))
+impl Drop for Tape {
+    /// Frees the tapes only this one refers to in a loop, so a long chain of tapes doesn't overflow the stack
+    fn drop(&mut self) {
+        let mut nodes = std::mem::take(self.nodes.get_mut().unwrap_or_else(PoisonError::into_inner));
+        while let Some(node) = nodes.pop() {
+            let inputs = node.op.into_iter().flat_map(|op| op.inputs).flatten();
+            let tensors = inputs
+                .filter_map(|input| match input {
+                    Input::External(tensor) => Some(tensor),
+                    Input::Local(_) => None,
+                })
+                .chain(node.tracked_gradient);
+            for tensor in tensors {
+                if let Ok(mut tape) = Arc::try_unwrap(tensor.tape) {
+                    nodes.append(tape.nodes.get_mut().unwrap_or_else(PoisonError::into_inner));
+                }
+            }
+        }
     }
 }
 
-////////////////////////////////////////////////////
-/// ////////////////////////////////////////////////
+/// The tape to record an operation on `inputs` on
+fn tape_for(inputs: &[&Tensor]) -> Arc<Tape> {
+    let highest = inputs
+        .iter()
+        .map(|input| &input.tape)
+        .filter(|tape| !tape.leaf)
+        .max_by_key(|tape| tape.rank);
+    match highest {
+        None => Tape::new(&NEXT_LOW_RANK, -1),
+        Some(tape) if tape.sealed.load(Ordering::Relaxed) || tape.lock().len() >= MAX_TAPE_LEN => {
+            Tape::new(&NEXT_RANK, 1)
+        }
+        Some(tape) => tape.clone(),
+    }
+}
+
+/// A node, with its tape locked while the guard is alive
+struct NodeGuard<'a> {
+    nodes: MutexGuard<'a, Vec<Node>>,
+    index: usize,
+}
+
+impl Deref for NodeGuard<'_> {
+    type Target = Node;
+
+    fn deref(&self) -> &Node {
+        &self.nodes[self.index]
+    }
+}
+
+impl DerefMut for NodeGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Node {
+        &mut self.nodes[self.index]
+    }
+}
+
+/// Locks two tapes in order, a tape given twice is locked once and the second guard is then `None`
+fn lock_both<'a>(a: &'a Tape, b: &'a Tape) -> [Option<MutexGuard<'a, Vec<Node>>>; 2] {
+    if std::ptr::eq(a, b) {
+        [Some(a.lock()), None]
+    } else if a.locks_before(b) {
+        let a = a.lock();
+        [Some(a), Some(b.lock())]
+    } else {
+        let b = b.lock();
+        [Some(a.lock()), Some(b)]
+    }
+}
+
+/// Calls `f` with the nodes of `a` and `b`, which may be on the same tape
+fn with_nodes<R>(a: &Tensor, b: &Tensor, f: impl FnOnce(&Node, &Node) -> R) -> R {
+    let [first, second] = lock_both(&a.tape, &b.tape);
+    let first = first.expect("the first tape is always locked");
+    let second = second.as_ref().unwrap_or(&first);
+    f(&first[a.index], &second[b.index])
+}
+
+/// Gradients flowing into the tapes `backward` still has to walk, by tape id
+type PendingTapes = BTreeMap<i64, (Arc<Tape>, Vec<Option<Vec<f64>>>)>;
+
+/// Propagates `gradients`, indexed like the nodes of `tape`, from its last node back to its first.
+/// Gradients of inputs on other tapes are handed to leaves right away, and to `pending` otherwise
+fn propagate_tape(tape: &Tape, gradients: &mut [Option<Vec<f64>>], pending: &mut PendingTapes) {
+    let nodes = tape.lock();
+    for index in (0..gradients.len()).rev() {
+        let (earlier, rest) = gradients.split_at_mut(index);
+        let (Some(gradient), Some(op)) = (&rest[0], &nodes[index].op) else {
+            continue;
+        };
+
+        let input_gradients = {
+            // inputs on other tapes have a lower rank, so they are locked after this tape
+            let external = op.inputs.each_ref().map(|input| match input {
+                Some(Input::External(tensor)) => Some(tensor),
+                _ => None,
+            });
+            let external = match external {
+                [Some(a), Some(b)] => lock_both(&a.tape, &b.tape),
+                [a, b] => [a.map(|a| a.tape.lock()), b.map(|b| b.tape.lock())],
+            };
+            let inputs = [0, 1].map(|i| match &op.inputs[i] {
+                Some(Input::Local(j)) => Some(&nodes[*j]),
+                Some(Input::External(tensor)) => {
+                    let tape = external[i].as_ref().or(external[0].as_ref());
+                    Some(&tape.expect("external inputs are locked")[tensor.index])
+                }
+                None => None,
+            });
+            (op.propagate)(&Propagation {
+                data: &nodes[index].data,
+                shape: &nodes[index].shape,
+                gradient,
+                param: op.param,
+                inputs,
+            })
+        };
+
+        for (input, gradient) in op.inputs.iter().zip(input_gradients) {
+            let (Some(input), Some(gradient)) = (input, gradient) else {
+                continue;
+            };
+            match input {
+                Input::Local(j) => add_gradient(&mut earlier[*j], gradient),
+                Input::External(tensor) if tensor.is_leaf() => tensor.accumulate_gradient(&gradient),
+                Input::External(tensor) => {
+                    let (_, gradients) = pending
+                        .entry(tensor.tape.rank)
+                        .or_insert_with(|| (tensor.tape.clone(), Vec::new()));
+                    if gradients.len() <= tensor.index {
+                        gradients.resize(tensor.index + 1, None);
+                    }
+                    add_gradient(&mut gradients[tensor.index], gradient);
+                }
+            }
+        }
+    }
+}
 
-type PropagateFn = fn(value: &Ref<TensorInternal>);
+/// Adds `gradient` to a total that stays `None` until some gradient flows into it
+fn add_gradient(total: &mut Option<Vec<f64>>, gradient: Vec<f64>) {
+    match total {
+        Some(total) => total.iter_mut().zip(&gradient).for_each(|(t, g)| *t += g),
+        None => *total = Some(gradient),
+    }
+}
 
-pub struct TensorInternal {
-    data: f64,
-    gradient: f64,
+/// A tensor recorded on a tape
+struct Node {
+    /// Elements in row-major order
+    data: Vec<f64>,
+    shape: Vec<usize>,
+    /// Left empty until a gradient flows into the node, standing for zeros
+    gradient: Vec<f64>,
     label: Option<String>,
-    operation: Option<String>,
-    previous: Vec<Tensor>,
-    propagate: Option<PropagateFn>,
-}
-
-impl TensorInternal {
-    fn new(
-        data: f64,
-        label: Option<String>,
-        op: Option<String>,
-        prev: Vec<Tensor>,
-        propagate: Option<PropagateFn>,
-    ) -> TensorInternal {
-        TensorInternal {
+    /// The operation that computed the node, `None` for leaves
+    op: Option<Op>,
+    /// The gradient as a graph-tracked tensor, only set on leaves by `backward_create_graph`
+    tracked_gradient: Option<Tensor>,
+}
+
+impl Node {
+    fn new(data: Vec<f64>, shape: Vec<usize>, op: Option<Op>) -> Node {
+        Node {
             data,
-            gradient: 0.0,
-            label,
-            operation: op,
-            previous: prev,
-            propagate,
+            shape,
+            gradient: Vec::new(),
+            label: None,
+            op,
+            tracked_gradient: None,
         }
     }
-}
 
-impl PartialEq for TensorInternal {
-    fn eq(&self, other: &Self) -> bool {
-        self.data == other.data
-            && self.gradient == other.gradient
-            && self.label == other.label
-            && self.operation == other.operation
-            && self.previous == other.previous
+    fn gradient_or_zeros(&self) -> Vec<f64> {
+        if self.gradient.is_empty() {
+            vec![0.0; self.data.len()]
+        } else {
+            self.gradient.clone()
+        }
+    }
+
+    fn materialize_gradient(&mut self) {
+        if self.gradient.is_empty() {
+            self.gradient = vec![0.0; self.data.len()];
+        }
+    }
+
+    fn accumulate(&mut self, gradient: &[f64]) {
+        self.materialize_gradient();
+        for (acc, g) in self.gradient.iter_mut().zip(gradient) {
+            *acc += g;
+        }
     }
 }
 
-impl Eq for TensorInternal {}
+/// How a node was computed
+struct Op {
+    name: &'static str,
+    inputs: [Option<Input>; 2],
+    /// A constant of the operation, e.g. the slope of `leaky_relu`
+    param: f64,
+    propagate: PropagateFn,
+}
+
+/// An input of an operation, by position when it's on the same tape, so a tape never refers to itself
+enum Input {
+    Local(usize),
+    External(Tensor),
+}
+
+/// What a `PropagateFn` sees of a node: its result, the gradient flowing into it and its inputs
+struct Propagation<'a> {
+    data: &'a [f64],
+    shape: &'a [usize],
+    gradient: &'a [f64],
+    param: f64,
+    inputs: [Option<&'a Node>; 2],
+}
+
+impl<'a> Propagation<'a> {
+    fn input(&self, i: usize) -> &'a Node {
+        self.inputs[i].expect("the operation was recorded with this input")
+    }
+}
 
-impl std::hash::Hash for TensorInternal {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.data.to_bits().hash(state);
-        self.gradient.to_bits().hash(state);
-        self.label.hash(state);
-        self.operation.hash(state);
-        self.previous.hash(state);
+/// Strides of a contiguous row-major buffer, the last axis is contiguous
+pub fn contiguous_strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
     }
+    strides
 }
 
-impl std::fmt::Debug for TensorInternal {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("ValueInternal")
-            .field("data", &self.data)
-            .field("gradient", &self.gradient)
-            .field("label", &self.label)
-            .field("operation", &self.operation)
-            .field("previous", &self.previous)
-            .finish()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unrelated_graphs_get_their_own_tape() {
+        let x = Tensor::from_vec(vec![2.0], &[1]);
+        let kept = x.clone() * x.clone();
+        let unrelated = x.clone() + x.clone();
+        assert!(!Arc::ptr_eq(&unrelated.tape, &kept.tape));
+        assert_eq!(kept.tape.lock().len(), 1);
+
+        // dropping the kept tensor frees its graph, whatever else was computed since
+        let tape = Arc::downgrade(&kept.tape);
+        drop(kept);
+        assert!(tape.upgrade().is_none());
+
+        // a graph moves on to a new tape once its tape is full
+        let mut total = unrelated;
+        let first = Arc::downgrade(&total.tape);
+        for _ in 0..MAX_TAPE_LEN {
+            total = total + x.clone();
+        }
+        assert_eq!(first.upgrade().map(|tape| tape.lock().len()), Some(MAX_TAPE_LEN));
+        assert!(!Arc::ptr_eq(&first.upgrade().unwrap(), &total.tape));
+        total.backward();
+        assert_eq!(x.gradient(), vec![MAX_TAPE_LEN as f64 + 2.0]);
+    }
+
+    #[test]
+    fn test_tensors_can_be_sent_to_other_threads() {
+        fn assert_send<T: Send + Sync>() {}
+        assert_send::<Tensor>();
+
+        let x = Tensor::from(3.0);
+        let y = x.clone() * x.clone();
+        let value = std::thread::spawn(move || {
+            y.backward();
+            y.item()
+        });
+        assert_eq!(value.join().unwrap(), Some(9.0));
+        assert_eq!(x.gradient(), vec![6.0]);
+    }
+
+    #[test]
+    fn test_elementwise_ops() {
+        let a = Tensor::from_vec(vec![1.0, 2.0, 3.0, 4.0], &[2, 2]);
+        let b = Tensor::from_vec(vec![2.0, 2.0, 0.5, -1.0], &[2, 2]);
+
+        let c = (a.clone() * b.clone() + a.clone()) / b.clone() - a.pow(&b);
+        assert_eq!(c.shape(), vec![2, 2]);
+        assert_eq!(c.strides(), vec![2, 1]);
+        assert_eq!(format!("{}", c), "[[0.5, -1], [7.267949192431123, -0.25]]");
+
+        c.backward();
+        // dc/da = b/b + 1/b - b * a^(b-1)
+        let expected = [1.5 - 2.0, 1.5 - 4.0, 3.0 - 0.5 / 3.0_f64.sqrt(), 1.0 / 16.0];
+        for (g, e) in a.gradient().iter().zip(expected) {
+            assert!((g - e).abs() < 1e-12, "{} != {}", g, e);
+        }
+        assert_eq!(b.gradient().len(), 4);
+    }
+
+    #[test]
+    fn test_matmul() {
+        let a = Tensor::from_vec(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3]);
+        let b = Tensor::from_vec(vec![1.0, 0.0, -1.0, 2.0, 0.5, 1.0], &[3, 2]);
+        let v = Tensor::from_vec(vec![1.0, -1.0, 2.0], &[3]);
+
+        assert_eq!(format!("{}", v.matmul(&v).unwrap()), "6");
+        assert_eq!(format!("{}", a.matmul(&v).unwrap()), "[5, 11]");
+        assert_eq!(format!("{}", v.matmul(&b).unwrap()), "[3, 0]");
+
+        let c = a.matmul(&b).unwrap();
+        assert_eq!(format!("{}", c), "[[0.5, 7], [2, 16]]");
+
+        c.backward();
+        // dA = ones(2, 2) @ B^T, dB = A^T @ ones(2, 2)
+        assert_eq!(a.gradient(), vec![1.0, 1.0, 1.5, 1.0, 1.0, 1.5]);
+        assert_eq!(b.gradient(), vec![5.0, 5.0, 7.0, 7.0, 9.0, 9.0]);
+
+        assert_eq!(
+            a.matmul(&a).unwrap_err(),
+            TensorError::Shape(
+                "Shape mismatch for matrix multiplication: [2, 3] @ [2, 3]".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_broadcasting() {
+        let x = Tensor::from_vec(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3]);
+        let bias = Tensor::from_vec(vec![10.0, 20.0, 30.0], &[3]);
+        let scale = Tensor::from_vec(vec![2.0, -1.0], &[2, 1]);
+
+        let y = (x.clone() + bias.clone()) * scale.clone() / Tensor::from(2.0);
+        assert_eq!(y.shape(), vec![2, 3]);
+        assert_eq!(format!("{}", y), "[[11, 22, 33], [-7, -12.5, -18]]");
+
+        y.backward();
+        // gradients are summed over the axes an input was broadcast along
+        assert_eq!(x.gradient(), vec![1.0, 1.0, 1.0, -0.5, -0.5, -0.5]);
+        assert_eq!(bias.gradient(), vec![0.5, 0.5, 0.5]);
+        assert_eq!(scale.gradient(), vec![33.0, 37.5]);
+
+        let outer = scale.pow(&Tensor::from_vec(vec![1.0, 2.0], &[1, 2]));
+        assert_eq!(format!("{}", outer), "[[2, 4], [-1, 1]]");
+
+        let mask = x.compare(&Tensor::from(3.0), |a, b| a > b).unwrap();
+        assert_eq!(format!("{}", mask), "[[0, 0, 0], [1, 1, 1]]");
+
+        assert_eq!(broadcast_shape(&[5, 1, 3], &[4, 1]), Ok(vec![5, 4, 3]));
+        assert_eq!(
+            x.compare(&Tensor::zeros(&[2]), |a, b| a == b).unwrap_err(),
+            TensorError::Shape("Can't broadcast shapes [2, 3] and [2]".to_string())
+        );
+
+        // arithmetic on incompatible shapes is an error rather than a panic
+        let (a, b) = (Tensor::zeros(&[3]), Tensor::zeros(&[2]));
+        let error = TensorError::Shape("Can't broadcast shapes [3] and [2]".to_string());
+        assert_eq!(a.checked_add(&b).unwrap_err(), error.clone());
+        assert_eq!(a.checked_sub(&b).unwrap_err(), error.clone());
+        assert_eq!(a.checked_mul(&b).unwrap_err(), error.clone());
+        assert_eq!(a.checked_div(&b).unwrap_err(), error.clone());
+        assert_eq!(a.checked_pow(&b).unwrap_err(), error);
+        assert_eq!(a.checked_add(&Tensor::from(1.0)).unwrap().shape(), vec![3]);
+    }
+
+    #[test]
+    fn test_reductions() {
+        let x = Tensor::from_vec(vec![1.0, 5.0, 3.0, 4.0, 2.0, 6.0], &[2, 3]);
+
+        let show = |t: TensorResult<Tensor>| format!("{}", t.unwrap());
+        assert_eq!(show(x.sum(None, false)), "21");
+        assert_eq!(show(x.sum(Some(0), false)), "[5, 7, 9]");
+        assert_eq!(show(x.sum(Some(-1), true)), "[[9], [12]]");
+        assert_eq!(show(x.mean(None, true)), "[[3.5]]");
+        assert_eq!(show(x.max(Some(1), false)), "[5, 6]");
+        assert_eq!(show(x.min(Some(0), false)), "[1, 2, 3]");
+        assert_eq!(show(x.prod(Some(0), false)), "[4, 10, 18]");
+        assert_eq!(show(x.argmax(Some(1), false)), "[1, 2]");
+        assert_eq!(show(x.argmax(None, false)), "5");
+
+        let loss = x.max(Some(1), false).unwrap().sum(None, false).unwrap()
+            + x.prod(Some(0), false).unwrap().sum(None, false).unwrap()
+            + x.mean(None, false).unwrap();
+        loss.backward();
+        let expected = [4.0, 3.0, 6.0, 1.0, 5.0, 4.0].map(|g| g + 1.0 / 6.0);
+        for (g, e) in x.gradient().iter().zip(expected) {
+            assert!((g - e).abs() < 1e-12, "{} != {}", g, e);
+        }
+
+        // zeros and ties
+        let y = Tensor::from_vec(vec![0.0, 2.0, 3.0, 3.0], &[4]);
+        y.prod(None, false).unwrap().backward();
+        assert_eq!(y.gradient(), vec![18.0, 0.0, 0.0, 0.0]);
+        y.clear_gradient();
+        y.max(None, false).unwrap().backward();
+        assert_eq!(y.gradient(), vec![0.0, 0.0, 1.0, 0.0]);
+
+        assert_eq!(
+            x.sum(Some(2), false).unwrap_err(),
+            TensorError::Shape("Axis 2 is out of bounds for a tensor of shape [2, 3]".to_string())
+        );
+    }
+
+    /// Compares the gradient of `sum(f(x) * weights)` against central finite differences
+    fn assert_gradient_matches(f: impl Fn(&Tensor) -> Tensor, data: &[f64], shape: &[usize]) {
+        let x = Tensor::from_vec(data.to_vec(), shape);
+        let report = gradcheck(|x| Ok::<_, TensorError>(f(&x[0])), &[x], 1e-6, 1e-5).unwrap();
+        assert!(report.passed(), "{}", report);
+    }
+
+    #[test]
+    fn test_activation_gradients() {
+        let data = [-1.3, -0.2, 0.4, 2.1, 0.7, -2.5];
+        let positive = [0.3, 1.2, 4.0, 0.05, 2.5, 9.0];
+        let shape = [2, 3];
+
+        assert_gradient_matches(|x| x.exp(), &data, &shape);
+        assert_gradient_matches(|x| x.log(), &positive, &shape);
+        assert_gradient_matches(|x| x.sqrt(), &positive, &shape);
+        assert_gradient_matches(|x| x.abs(), &data, &shape);
+        assert_gradient_matches(|x| x.sigmoid(), &data, &shape);
+        assert_gradient_matches(|x| x.softplus(), &data, &shape);
+        assert_gradient_matches(|x| x.gelu(), &data, &shape);
+        assert_gradient_matches(|x| x.leaky_relu(0.1), &data, &shape);
+        assert_gradient_matches(|x| x.sin(), &data, &shape);
+        assert_gradient_matches(|x| x.cos(), &data, &shape);
+        assert_gradient_matches(|x| x.clamp(-1.0, 1.0).unwrap(), &data, &shape);
+        assert_gradient_matches(|x| x.softmax(-1).unwrap(), &data, &shape);
+        assert_gradient_matches(|x| x.softmax(0).unwrap(), &data, &shape);
+        assert_gradient_matches(|x| x.log_softmax(1).unwrap(), &data, &shape);
+    }
+
+    #[test]
+    fn test_activations_are_numerically_stable() {
+        let x = Tensor::from_vec(vec![1000.0, 1000.0, -1000.0], &[3]);
+
+        assert_eq!(format!("{}", x.sigmoid()), "[1, 1, 0]");
+        assert_eq!(format!("{}", x.softplus()), "[1000, 1000, 0]");
+        assert_eq!(format!("{}", x.softmax(0).unwrap()), "[0.5, 0.5, 0]");
+        assert_eq!(
+            format!("{}", x.log_softmax(0).unwrap()),
+            format!("[{}, {}, -2000.69314718056]", -2.0_f64.ln(), -2.0_f64.ln())
+        );
+        assert_eq!(format!("{}", x.clamp(-1.0, 1.0).unwrap()), "[1, 1, -1]");
+        let error = "clamp expects min <= max, got 1 and -1".to_string();
+        assert_eq!(x.clamp(1.0, -1.0).unwrap_err(), TensorError::InvalidArgument(error));
+        assert!(x.clamp(f64::NAN, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_backward_on_diamond_graphs() {
+        let a = Tensor::from(3.0);
+        let b = a.clone() * Tensor::from(2.0);
+        let c = a.clone() + Tensor::from(1.0);
+        // b and c both depend on a, and b is used twice
+        let d = b.clone() * c.clone() + b.clone();
+        d.backward();
+
+        // d = 2a (a + 1) + 2a, dd/da = 4a + 4
+        assert_eq!(a.gradient(), vec![16.0]);
+        assert_eq!(b.gradient(), vec![5.0]);
+        assert_eq!(c.gradient(), vec![6.0]);
+
+        let order = d.topological_order();
+        assert_eq!(order.len(), 7);
+        let position = |t: &Tensor| order.iter().position(|o| o == t).unwrap();
+        assert!(position(&a) < position(&b) && position(&b) < position(&d));
+        assert!(position(&a) < position(&c) && position(&c) < position(&d));
+    }
+
+    #[test]
+    fn test_backward_twice() {
+        let x = Tensor::from(2.0);
+        let y = x.clone() * Tensor::from(3.0);
+        let z = y.clone() * y.clone();
+        z.backward();
+        z.backward();
+
+        // every gradient accumulates over both passes, including the one of the root
+        assert_eq!(x.gradient(), vec![72.0]);
+        assert_eq!(y.gradient(), vec![24.0]);
+        assert_eq!(z.gradient(), vec![2.0]);
+
+        // a leaf's own pass adds to its gradient as well
+        let w = Tensor::from_vec(vec![1.0, 2.0], &[2]);
+        w.backward();
+        w.backward();
+        assert_eq!(w.gradient(), vec![2.0, 2.0]);
+    }
+
+    #[test]
+    fn test_backward_on_long_graphs() {
+        let x = Tensor::from(1.0);
+        let mut loss = Tensor::from(0.0);
+        for _ in 0..10_000 {
+            loss = loss + x.clone() * x.clone();
+        }
+        loss.backward();
+
+        assert_eq!(x.gradient(), vec![20_000.0]);
+    }
+
+    #[test]
+    fn test_no_grad_and_detach() {
+        let w = Tensor::from_vec(vec![1.0, 2.0], &[2]);
+
+        let updated = {
+            let _guard = no_grad();
+            assert!(!is_grad_enabled());
+            w.clone() * Tensor::from(3.0) + w.clone()
+        };
+        assert!(is_grad_enabled());
+        assert!(updated.is_leaf());
+
+        // enable_grad records again inside no_grad, and a no_grad still open when it ends stays in effect
+        let outer = no_grad();
+        let enabled = enable_grad();
+        assert!(is_grad_enabled());
+        let inner = no_grad();
+        drop(enabled);
+        assert!(!is_grad_enabled());
+        drop((inner, outer));
+        assert!(is_grad_enabled());
+
+        // gradients don't flow through detached values
+        let y = w.clone() * w.detach();
+        y.sum(None, false).unwrap().backward();
+        assert_eq!(w.gradient(), vec![1.0, 2.0]);
+
+        let b = Tensor::from(1.0);
+        (b.clone() * w.clone()).sum(None, false).unwrap().backward();
+        assert_eq!(w.gradient(), vec![2.0, 3.0]);
+        zero_grad(&[w.clone(), b.clone()]);
+        assert_eq!((w.gradient(), b.gradient()), (vec![0.0, 0.0], vec![0.0]));
+    }
+
+    #[test]
+    fn test_graphs_are_freed_with_their_tape() {
+        let w = Tensor::from_vec(vec![1.0, 2.0], &[2]);
+        let squared = w.clone() * w.clone();
+        let loss = squared.sum(None, false).unwrap();
+        // operations are appended to the same tape, after their inputs
+        assert!(Arc::ptr_eq(&squared.tape, &loss.tape) && squared.index < loss.index);
+        assert!(!Arc::ptr_eq(&w.tape, &loss.tape));
+
+        loss.backward();
+        let tape = Arc::downgrade(&loss.tape);
+        drop((squared, loss));
+        // the parameter doesn't keep the graph alive
+        assert!(tape.upgrade().is_none());
+        assert_eq!(w.gradient(), vec![2.0, 4.0]);
+    }
+
+    #[test]
+    fn test_backward_across_tapes() {
+        let x = Tensor::from(2.0);
+        let y = x.clone() * x.clone();
+        y.backward();
+
+        // the tape of `y` was sealed by `backward`, so `z` is recorded on a new tape referring back to it
+        let z = y.clone() * Tensor::from(3.0) + x.clone();
+        assert!(!Arc::ptr_eq(&y.tape, &z.tape));
+        x.clear_gradient();
+        y.clear_gradient();
+        z.backward();
+
+        assert_eq!(x.gradient(), vec![13.0]);
+        assert_eq!(y.gradient(), vec![3.0]);
+        assert_eq!(z.topological_order().len(), 5);
+    }
+
+    #[test]
+    fn test_graph_scales_with_operations() {
+        let a = Tensor::from_vec((0..1000).map(|x| x as f64 - 500.0).collect(), &[10, 100]);
+        let b = (a.clone() * a.clone()).relu().tanh();
+
+        // one node per operation, not per element
+        assert_eq!(b.inputs().len(), 1);
+        assert_eq!(b.len(), 1000);
+
+        b.backward();
+        let gradient = a.gradient();
+        assert_eq!(gradient[500], 0.0);
+        assert!((gradient[501] - 2.0 * (1.0 - 1.0_f64.tanh().powi(2))).abs() < 1e-12);
     }
 }
@@ -0,0 +1,161 @@
+//! Exports the recorded computation graph, as plain nodes or as a Graphviz DOT graph like micrograd's `draw_dot`.
+
+use std::{collections::HashMap, fmt::Write};
+
+use super::Tensor;
+
+/// Tensors with more elements than this are summarized by their shape
+const MAX_SHOWN_ELEMENTS: usize = 8;
+
+/// A tensor of the graph, with the operation that produced it
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphNode {
+    pub label: Option<String>,
+    pub operation: Option<String>,
+    pub shape: Vec<usize>,
+    pub data: Vec<f64>,
+    pub gradient: Vec<f64>,
+    /// Indices of the operation's inputs in the node list
+    pub inputs: Vec<usize>,
+}
+
+impl GraphNode {
+    pub fn data_summary(&self) -> String {
+        summarize(&self.data, &self.shape)
+    }
+
+    pub fn gradient_summary(&self) -> String {
+        summarize(&self.gradient, &self.shape)
+    }
+}
+
+impl Tensor {
+    /// Every tensor this one was computed from, inputs come before the operations using them
+    pub fn graph(&self) -> Vec<GraphNode> {
+        let order = self.topological_order();
+        let index = order
+            .iter()
+            .enumerate()
+            .map(|(i, tensor)| (tensor.key(), i))
+            .collect::<HashMap<_, _>>();
+
+        order
+            .iter()
+            .map(|tensor| {
+                let inputs = tensor.inputs().iter().map(|input| index[&input.key()]).collect();
+                let node = tensor.node();
+                GraphNode {
+                    label: node.label.clone(),
+                    operation: node.op.as_ref().map(|op| op.name.to_string()),
+                    shape: node.shape.clone(),
+                    data: node.data.clone(),
+                    gradient: node.gradient_or_zeros(),
+                    inputs,
+                }
+            })
+            .collect()
+    }
+
+    /// The graph as Graphviz DOT, one record per tensor with its data and gradient, and one ellipse per operation
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph {\n    rankdir=LR;\n    node [shape=record];\n");
+
+        for (i, node) in self.graph().iter().enumerate() {
+            let mut fields = node.label.iter().map(|label| escape(label)).collect::<Vec<_>>();
+            fields.push(format!("data {}", escape(&node.data_summary())));
+            fields.push(format!("grad {}", escape(&node.gradient_summary())));
+            writeln!(dot, "    n{} [label=\"{{ {} }}\"];", i, fields.join(" | ")).unwrap();
+
+            if let Some(op) = &node.operation {
+                writeln!(dot, "    n{}op [label=\"{}\", shape=ellipse];", i, escape(op)).unwrap();
+                writeln!(dot, "    n{}op -> n{};", i, i).unwrap();
+                for input in &node.inputs {
+                    writeln!(dot, "    n{} -> n{}op;", input, i).unwrap();
+                }
+            }
+        }
+
+        dot.push('}');
+        dot
+    }
+}
+
+/// Scalars and small tensors are written out with 4 decimals, larger ones only by their shape
+fn summarize(values: &[f64], shape: &[usize]) -> String {
+    if values.len() > MAX_SHOWN_ELEMENTS {
+        return format!("shape {:?}", shape);
+    }
+
+    match shape.split_first() {
+        None => format!("{:.4}", values[0]),
+        Some((&len, inner)) => {
+            let step = inner.iter().product::<usize>();
+            let rows = (0..len).map(|i| summarize(&values[i * step..(i + 1) * step], inner));
+            format!("[{}]", rows.collect::<Vec<_>>().join(", "))
+        }
+    }
+}
+
+/// Escapes the characters that have a meaning inside DOT record labels
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '"' | '\\' | '{' | '}' | '|' | '<' | '>') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_dot() {
+        let a = Tensor::from(2.0);
+        let b = Tensor::from_vec(vec![1.0, -3.0], &[2]);
+        let c = (a * b.clone()).sum(None, false).unwrap();
+        c.backward();
+
+        let graph = c.graph();
+        // the sum keeps the reduced axis, then drops it with a reshape
+        assert_eq!(graph.len(), 5);
+        assert_eq!(graph[2].operation.as_deref(), Some("*"));
+        assert_eq!(graph[2].inputs, vec![0, 1]);
+        assert_eq!(graph[4].operation.as_deref(), Some("reshape"));
+        assert_eq!(graph[4].inputs, vec![3]);
+
+        assert_eq!(
+            c.to_dot(),
+            "digraph {
+    rankdir=LR;
+    node [shape=record];
+    n0 [label=\"{ data 2.0000 | grad -2.0000 }\"];
+    n1 [label=\"{ data [1.0000, -3.0000] | grad [2.0000, 2.0000] }\"];
+    n2 [label=\"{ data [2.0000, -6.0000] | grad [1.0000, 1.0000] }\"];
+    n2op [label=\"*\", shape=ellipse];
+    n2op -> n2;
+    n0 -> n2op;
+    n1 -> n2op;
+    n3 [label=\"{ data [-4.0000] | grad [1.0000] }\"];
+    n3op [label=\"sum\", shape=ellipse];
+    n3op -> n3;
+    n2 -> n3op;
+    n4 [label=\"{ data -4.0000 | grad 1.0000 }\"];
+    n4op [label=\"reshape\", shape=ellipse];
+    n4op -> n4;
+    n3 -> n4op;
+}"
+        );
+    }
+
+    #[test]
+    fn test_large_tensors_are_summarized() {
+        let x = Tensor::zeros(&[3, 4]);
+        let node = &x.graph()[0];
+        assert_eq!(node.data_summary(), "shape [3, 4]");
+        assert_eq!(escape("a|b{c}"), "a\\|b\\{c\\}");
+    }
+}
@@ -0,0 +1,485 @@
+//! Forward-mode differentiation with dual numbers. Every value carries its tangent, the derivative along
+//! the direction the inputs were seeded with, and each operation propagates it alongside the value.
+//!
+//! One forward pass gives the derivative of every output along one input direction, which is cheaper than
+//! reverse mode for functions with few inputs and many outputs.
+
+use super::{
+    broadcast_indices, first_extremum_mask, gelu_derivative, reduced_shapes, Tensor, TensorError,
+    TensorResult,
+};
+
+#[derive(Debug, Clone)]
+pub struct Dual {
+    pub value: Tensor,
+    pub tangent: Tensor,
+}
+
+/// Value of `f` at `x` and its derivative along `v`, the Jacobian-vector product, in a single forward pass.
+/// Errors of `f` are passed on as they are
+pub fn jvp<E: From<TensorError>>(
+    f: impl FnOnce(&[Dual]) -> Result<Dual, E>,
+    x: &[Tensor],
+    v: &[Tensor],
+) -> Result<(Tensor, Tensor), E> {
+    if x.len() != v.len() {
+        let message = format!("Expected one tangent per input, got {} for {}", v.len(), x.len());
+        return Err(TensorError::InvalidArgument(message).into());
+    }
+
+    let inputs = x
+        .iter()
+        .zip(v)
+        .map(|(x, v)| Dual::new(x.clone(), v.clone()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let output = f(&inputs)?;
+    Ok((output.value, output.tangent))
+}
+
+impl Dual {
+    pub fn new(value: Tensor, tangent: Tensor) -> TensorResult<Dual> {
+        if value.shape() != tangent.shape() {
+            return Err(TensorError::Shape(format!(
+                "A tangent of shape {:?} doesn't match a value of shape {:?}",
+                tangent.shape(),
+                value.shape()
+            )));
+        }
+        Ok(Dual { value, tangent })
+    }
+
+    /// A value that doesn't depend on the inputs, so its tangent is zero
+    pub fn constant(value: Tensor) -> Dual {
+        let tangent = Tensor::zeros(&value.shape());
+        Dual { value, tangent }
+    }
+
+    pub fn shape(&self) -> Vec<usize> {
+        self.value.shape()
+    }
+
+    /// Result of an element-wise operation, whose derivative at each element is `derivative`
+    fn chain(&self, value: Tensor, derivative: Tensor) -> Dual {
+        let tangent = self.tangent.clone() * derivative;
+        Dual { value, tangent }
+    }
+
+    /// Applies the same linear operation, such as a reshape or a sum, to the value and the tangent
+    fn linear(&self, f: impl Fn(&Tensor) -> TensorResult<Tensor>) -> TensorResult<Dual> {
+        Ok(Dual {
+            value: f(&self.value)?,
+            tangent: f(&self.tangent)?,
+        })
+    }
+
+    pub fn pow(&self, other: &Dual) -> Dual {
+        let (b, p) = (&self.value, &other.value);
+        let value = b.pow(p);
+
+        // like in reverse mode, d/dp is only defined for positive bases
+        let positive = b.map_leaf(|x| (x > 0.0) as i32 as f64);
+        let log = b.map_leaf(|x| if x > 0.0 { x.ln() } else { 0.0 });
+        let base_derivative = p.clone() * b.pow(&(p.clone() - Tensor::from(1.0)));
+        let tangent = self.tangent.clone() * base_derivative
+            + other.tangent.clone() * value.clone() * log * positive;
+        Dual { value, tangent }
+    }
+
+    pub fn tanh(&self) -> Dual {
+        let y = self.value.tanh();
+        self.chain(y.clone(), Tensor::from(1.0) - y.clone() * y)
+    }
+
+    pub fn relu(&self) -> Dual {
+        let derivative = self.value.map_leaf(|x| (x > 0.0) as i32 as f64);
+        self.chain(self.value.relu(), derivative)
+    }
+
+    pub fn exp(&self) -> Dual {
+        let y = self.value.exp();
+        self.chain(y.clone(), y)
+    }
+
+    pub fn log(&self) -> Dual {
+        self.chain(self.value.log(), Tensor::from(1.0) / self.value.clone())
+    }
+
+    pub fn sqrt(&self) -> Dual {
+        let y = self.value.sqrt();
+        self.chain(y.clone(), Tensor::from(0.5) / y)
+    }
+
+    pub fn abs(&self) -> Dual {
+        let derivative = self.value.map_leaf(|x| if x == 0.0 { 0.0 } else { x.signum() });
+        self.chain(self.value.abs(), derivative)
+    }
+
+    pub fn sin(&self) -> Dual {
+        self.chain(self.value.sin(), self.value.cos())
+    }
+
+    pub fn cos(&self) -> Dual {
+        self.chain(self.value.cos(), -self.value.sin())
+    }
+
+    pub fn sigmoid(&self) -> Dual {
+        let y = self.value.sigmoid();
+        self.chain(y.clone(), y.clone() * (Tensor::from(1.0) - y))
+    }
+
+    pub fn softplus(&self) -> Dual {
+        self.chain(self.value.softplus(), self.value.sigmoid())
+    }
+
+    pub fn gelu(&self) -> Dual {
+        self.chain(self.value.gelu(), gelu_derivative(&self.value))
+    }
+
+    pub fn leaky_relu(&self, slope: f64) -> Dual {
+        let derivative = self.value.map_leaf(|x| if x > 0.0 { 1.0 } else { slope });
+        self.chain(self.value.leaky_relu(slope), derivative)
+    }
+
+    pub fn clamp(&self, min: f64, max: f64) -> TensorResult<Dual> {
+        let value = self.value.clamp(min, max)?;
+        let derivative = self.value.map_leaf(|x| (min <= x && x <= max) as i32 as f64);
+        Ok(self.chain(value, derivative))
+    }
+
+    pub fn softmax(&self, axis: isize) -> TensorResult<Dual> {
+        let exp = self.shifted_by_max(axis)?.exp();
+        Ok(exp.clone() / exp.sum(Some(axis), true)?)
+    }
+
+    pub fn log_softmax(&self, axis: isize) -> TensorResult<Dual> {
+        let shifted = self.shifted_by_max(axis)?;
+        let log_sum = shifted.exp().sum(Some(axis), true)?.log();
+        Ok(shifted - log_sum)
+    }
+
+    fn shifted_by_max(&self, axis: isize) -> TensorResult<Dual> {
+        let max = self.value.max(Some(axis), true)?.detach();
+        Ok(self.clone() - Dual::constant(max))
+    }
+
+    pub fn matmul(&self, other: &Dual) -> TensorResult<Dual> {
+        let value = self.value.matmul(&other.value)?;
+        let tangent = self.tangent.matmul(&other.value)? + self.value.matmul(&other.tangent)?;
+        Ok(Dual { value, tangent })
+    }
+
+    pub fn transpose(&self) -> TensorResult<Dual> {
+        self.linear(Tensor::transpose)
+    }
+
+    pub fn reshape(&self, shape: &[usize]) -> TensorResult<Dual> {
+        self.linear(|x| x.reshape(shape))
+    }
+
+    pub fn sum(&self, axis: Option<isize>, keepdims: bool) -> TensorResult<Dual> {
+        self.linear(|x| x.sum(axis, keepdims))
+    }
+
+    pub fn mean(&self, axis: Option<isize>, keepdims: bool) -> TensorResult<Dual> {
+        self.linear(|x| x.mean(axis, keepdims))
+    }
+
+    pub fn prod(&self, axis: Option<isize>, keepdims: bool) -> TensorResult<Dual> {
+        let (reduced, _) = reduced_shapes(&self.shape(), axis, keepdims)?;
+        let (data, product) = (self.value.data(), self.value.prod(axis, true)?.data());
+        let groups = broadcast_indices(&reduced, &self.shape());
+
+        // product of the other elements of the group, recomputed when this one is zero
+        let others = (0..data.len())
+            .map(|i| {
+                if data[i] != 0.0 {
+                    product[groups[i]] / data[i]
+                } else {
+                    (0..data.len())
+                        .filter(|k| *k != i && groups[*k] == groups[i])
+                        .map(|k| data[k])
+                        .product()
+                }
+            })
+            .collect();
+
+        let tangent = self.tangent.clone() * Tensor::from_vec(others, &self.shape());
+        Ok(Dual {
+            value: self.value.prod(axis, keepdims)?,
+            tangent: tangent.sum(axis, keepdims)?,
+        })
+    }
+
+    pub fn max(&self, axis: Option<isize>, keepdims: bool) -> TensorResult<Dual> {
+        self.extremum(axis, keepdims, Tensor::max)
+    }
+
+    pub fn min(&self, axis: Option<isize>, keepdims: bool) -> TensorResult<Dual> {
+        self.extremum(axis, keepdims, Tensor::min)
+    }
+
+    /// The tangent of `max` and `min` is the one of the first element of each group equal to the result
+    fn extremum(
+        &self,
+        axis: Option<isize>,
+        keepdims: bool,
+        reduce: fn(&Tensor, Option<isize>, bool) -> TensorResult<Tensor>,
+    ) -> TensorResult<Dual> {
+        let mask = first_extremum_mask(&self.value, &reduce(&self.value, axis, true)?);
+        Ok(Dual {
+            value: reduce(&self.value, axis, keepdims)?,
+            tangent: (self.tangent.clone() * mask).sum(axis, keepdims)?,
+        })
+    }
+
+    pub fn mse_loss(&self, target: &Dual) -> TensorResult<Dual> {
+        let value = self.value.mse_loss(&target.value)?;
+        self.pairwise_loss(target, value, |p, t| (2.0 * (p - t), -2.0 * (p - t)))
+    }
+
+    pub fn l1_loss(&self, target: &Dual) -> TensorResult<Dual> {
+        let value = self.value.l1_loss(&target.value)?;
+        self.pairwise_loss(target, value, |p, t| {
+            let sign = if p == t { 0.0 } else { (p - t).signum() };
+            (sign, -sign)
+        })
+    }
+
+    pub fn huber_loss(&self, target: &Dual, delta: f64) -> TensorResult<Dual> {
+        let value = self.value.huber_loss(&target.value, delta)?;
+        self.pairwise_loss(target, value, |p, t| {
+            let d = (p - t).clamp(-delta, delta);
+            (d, -d)
+        })
+    }
+
+    pub fn binary_cross_entropy(&self, target: &Dual) -> TensorResult<Dual> {
+        let value = self.value.binary_cross_entropy(&target.value)?;
+        let clamped_log = |x: f64| x.ln().max(-100.0);
+        self.pairwise_loss(target, value, |p, t| {
+            let dp = (p - t) / ((1.0 - p) * p).max(1e-12);
+            (dp, clamped_log(1.0 - p) - clamped_log(p))
+        })
+    }
+
+    pub fn hinge_loss(&self, target: &Dual) -> TensorResult<Dual> {
+        let value = self.value.hinge_loss(&target.value)?;
+        self.pairwise_loss(target, value, |s, t| {
+            let active = (1.0 - t * s > 0.0) as i32 as f64;
+            (-t * active, -s * active)
+        })
+    }
+
+    pub fn cross_entropy(&self, target: &Dual) -> TensorResult<Dual> {
+        let value = self.value.cross_entropy(&target.value)?;
+
+        // softmax minus the one-hot target, class indices have no tangent
+        let classes = *self.shape().last().expect("checked by the loss");
+        let mut one_hot = vec![0.0; self.value.len()];
+        for (row, t) in target.value.data().into_iter().enumerate() {
+            one_hot[row * classes + t as usize] = 1.0;
+        }
+        let probabilities = self.value.softmax(-1)?;
+        let derivative = probabilities - Tensor::from_vec(one_hot, &self.shape());
+        let batch = (self.value.len() / classes) as f64;
+        let tangent = (derivative * self.tangent.clone()).sum(None, false)?;
+        let tangent = tangent / Tensor::from(batch);
+        Ok(Dual { value, tangent })
+    }
+
+    /// Tangent of the mean of a pairwise loss, whose partial derivatives at each element are `partials(p, t)`
+    fn pairwise_loss(
+        &self,
+        target: &Dual,
+        value: Tensor,
+        partials: impl Fn(f64, f64) -> (f64, f64),
+    ) -> TensorResult<Dual> {
+        let (p, t) = (self.value.data(), target.value.data());
+        let (p_tangent, t_tangent) = (self.tangent.data(), target.tangent.data());
+        let total = (0..p.len())
+            .map(|i| {
+                let (dp, dt) = partials(p[i], t[i]);
+                dp * p_tangent[i] + dt * t_tangent[i]
+            })
+            .sum::<f64>();
+        let tangent = Tensor::from(total / p.len().max(1) as f64);
+        Ok(Dual { value, tangent })
+    }
+}
+
+impl std::ops::Add for Dual {
+    type Output = Dual;
+
+    fn add(self, other: Dual) -> Dual {
+        Dual {
+            value: self.value + other.value,
+            tangent: self.tangent + other.tangent,
+        }
+    }
+}
+
+impl std::ops::Sub for Dual {
+    type Output = Dual;
+
+    fn sub(self, other: Dual) -> Dual {
+        Dual {
+            value: self.value - other.value,
+            tangent: self.tangent - other.tangent,
+        }
+    }
+}
+
+impl std::ops::Mul for Dual {
+    type Output = Dual;
+
+    fn mul(self, other: Dual) -> Dual {
+        Dual {
+            value: self.value.clone() * other.value.clone(),
+            tangent: self.tangent * other.value + self.value * other.tangent,
+        }
+    }
+}
+
+impl std::ops::Div for Dual {
+    type Output = Dual;
+
+    fn div(self, other: Dual) -> Dual {
+        let value = self.value / other.value.clone();
+        let tangent = (self.tangent - value.clone() * other.tangent) / other.value;
+        Dual { value, tangent }
+    }
+}
+
+impl std::ops::Neg for Dual {
+    type Output = Dual;
+
+    fn neg(self) -> Dual {
+        Dual {
+            value: -self.value,
+            tangent: -self.tangent,
+        }
+    }
+}
+
+impl std::fmt::Display for Dual {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "dual({}, {})", self.value, self.tangent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks `w . (J v)` from forward mode against `(w^T J) . v` from reverse mode, for a function
+    /// written once with the methods `Tensor` and `Dual` share
+    macro_rules! assert_jvp_matches_reverse {
+        ($x:expr, $v:expr, |$arg:ident| $body:expr) => {{
+            let (x, v): ([Tensor; 1], [Tensor; 1]) = ([$x], [$v]);
+            let f = |inputs: &[Dual]| {
+                let $arg = &inputs[0];
+                Ok::<_, TensorError>($body)
+            };
+            let (value, tangent) = jvp(f, &x, &v).unwrap();
+
+            let leaf = x[0].detach();
+            let output = {
+                let $arg = &leaf;
+                $body
+            };
+            assert_eq!(output.data(), value.data(), "{}", stringify!($body));
+            assert_eq!(output.shape(), tangent.shape(), "{}", stringify!($body));
+
+            let weights = (1..=output.len()).map(|w| w as f64).collect::<Vec<_>>();
+            let weighted = output * Tensor::from_vec(weights.clone(), &value.shape());
+            weighted.sum(None, false).unwrap().backward();
+
+            let forward = tangent.data().iter().zip(&weights).map(|(t, w)| t * w).sum::<f64>();
+            let reverse = leaf.gradient().iter().zip(v[0].data()).map(|(g, v)| g * v).sum::<f64>();
+            assert!(
+                (forward - reverse).abs() < 1e-9 * (1.0 + reverse.abs()),
+                "{}: {} != {}",
+                stringify!($body),
+                forward,
+                reverse
+            );
+        }};
+    }
+
+    #[test]
+    fn test_jvp_matches_reverse_mode() {
+        let mixed = || Tensor::from_vec(vec![-1.3, 0.4, 2.1, -0.6, 0.0, 1.7], &[2, 3]);
+        let positive = || Tensor::from_vec(vec![0.3, 1.2, 2.5, 0.7, 0.1, 4.0], &[2, 3]);
+        let v = || Tensor::from_vec(vec![0.5, -1.0, 0.25, 2.0, 1.5, -0.75], &[2, 3]);
+
+        assert_jvp_matches_reverse!(mixed(), v(), |x| {
+            x.clone() * x.clone() - x.clone() / (x.exp() + x.clone())
+        });
+        assert_jvp_matches_reverse!(positive(), v(), |x| x.pow(x).log() + x.sqrt());
+        assert_jvp_matches_reverse!(mixed(), v(), |x| {
+            -x.tanh() * x.relu() + x.abs() * x.sigmoid()
+        });
+        assert_jvp_matches_reverse!(mixed(), v(), |x| {
+            x.sin() * x.cos() + x.softplus() + x.gelu()
+        });
+        assert_jvp_matches_reverse!(mixed(), v(), |x| x.leaky_relu(0.1) * x.clamp(-1.0, 1.0).unwrap());
+        assert_jvp_matches_reverse!(mixed(), v(), |x| {
+            x.softmax(-1).unwrap() + x.log_softmax(0).unwrap()
+        });
+        assert_jvp_matches_reverse!(mixed(), v(), |x| {
+            x.matmul(&x.transpose().unwrap()).unwrap()
+        });
+        assert_jvp_matches_reverse!(mixed(), v(), |x| {
+            x.reshape(&[3, 2]).unwrap().sum(Some(0), true).unwrap()
+        });
+        assert_jvp_matches_reverse!(mixed(), v(), |x| {
+            x.mean(Some(1), false).unwrap() * x.prod(None, false).unwrap()
+        });
+        assert_jvp_matches_reverse!(mixed(), v(), |x| {
+            x.max(Some(0), false).unwrap() - x.min(None, false).unwrap()
+        });
+        assert_jvp_matches_reverse!(mixed(), v(), |x| {
+            x.mse_loss(&x.tanh()).unwrap() + x.l1_loss(&x.sin()).unwrap()
+        });
+        assert_jvp_matches_reverse!(mixed(), v(), |x| {
+            x.huber_loss(&x.cos(), 0.5).unwrap() + x.hinge_loss(&x.exp()).unwrap()
+        });
+        assert_jvp_matches_reverse!(mixed(), v(), |x| {
+            let target = x.cos().abs().clamp(0.0, 1.0).unwrap();
+            x.sigmoid().binary_cross_entropy(&target).unwrap()
+        });
+    }
+
+    #[test]
+    fn test_jvp_of_cross_entropy() {
+        let logits = Tensor::from_vec(vec![1.0, 2.0, 0.5, -1.0, 0.0, 3.0], &[2, 3]);
+        let v = Tensor::from_vec(vec![0.5, -1.0, 0.25, 2.0, 1.5, -0.75], &[2, 3]);
+        let target = Tensor::from_vec(vec![2.0, 0.0], &[2]);
+
+        let (value, tangent) = jvp(
+            |x| x[0].cross_entropy(&Dual::constant(target.clone())),
+            std::slice::from_ref(&logits),
+            std::slice::from_ref(&v),
+        )
+        .unwrap();
+
+        let loss = logits.cross_entropy(&target).unwrap();
+        loss.backward();
+        let reverse = logits.gradient().iter().zip(v.data()).map(|(g, v)| g * v).sum::<f64>();
+        assert_eq!(value.data(), loss.data());
+        assert!((tangent.item().unwrap() - reverse).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_jvp_seeds_each_input() {
+        // f(a, b) = a * b^2 along (1, 0) and (0, 1)
+        let x = [Tensor::from(3.0), Tensor::from(2.0)];
+        let f = |x: &[Dual]| Ok::<_, TensorError>(x[0].clone() * x[1].clone() * x[1].clone());
+
+        let (value, da) = jvp(f, &x, &[Tensor::from(1.0), Tensor::from(0.0)]).unwrap();
+        let (_, db) = jvp(f, &x, &[Tensor::from(0.0), Tensor::from(1.0)]).unwrap();
+        assert_eq!((value.item(), da.item(), db.item()), (Some(12.0), Some(4.0), Some(12.0)));
+
+        assert!(jvp(f, &x, &[Tensor::from(1.0)]).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+//! Checks the gradients computed by `backward()` against central finite differences.
+
+use super::{zero_grad, Tensor, TensorError};
+
+/// The element of one input where the analytic and numerical gradients disagree the most
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub index: usize,
+    pub analytic: f64,
+    pub numerical: f64,
+}
+
+impl Mismatch {
+    /// Absolute difference for small gradients, relative difference for large ones
+    pub fn error(&self) -> f64 {
+        (self.analytic - self.numerical).abs() / (1.0 + self.numerical.abs())
+    }
+}
+
+/// Worst mismatch of every input, in the order the inputs were given
+#[derive(Debug, Clone, PartialEq)]
+pub struct GradcheckReport {
+    pub worst: Vec<Mismatch>,
+    pub tol: f64,
+}
+
+impl GradcheckReport {
+    pub fn passed(&self) -> bool {
+        self.worst.iter().all(|mismatch| mismatch.error() <= self.tol)
+    }
+}
+
+impl std::fmt::Display for GradcheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, mismatch) in self.worst.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "input {}: worst mismatch at element {}, analytic {} vs numerical {} (error {:e}, tolerance {:e})",
+                i,
+                mismatch.index,
+                mismatch.analytic,
+                mismatch.numerical,
+                mismatch.error(),
+                self.tol
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares the gradients `backward()` computes for `f` at `inputs` with central differences of step `eps`.
+///
+/// Non-scalar outputs are reduced with a weighted sum so every output element contributes differently.
+/// The inputs are copied, their own gradients are left untouched. Errors of `f` are passed on as they are.
+pub fn gradcheck<E: From<TensorError>>(
+    mut f: impl FnMut(&[Tensor]) -> Result<Tensor, E>,
+    inputs: &[Tensor],
+    eps: f64,
+    tol: f64,
+) -> Result<GradcheckReport, E> {
+    let mut objective = |inputs: &[Tensor]| -> Result<Tensor, E> {
+        let output = f(inputs)?;
+        let weights = (1..=output.len()).map(|w| w as f64).collect();
+        let weights = Tensor::from_vec(weights, &output.shape());
+        Ok((output * weights).sum(None, false)?)
+    };
+
+    // `f` may differentiate its inputs itself, only the gradients of the final backward are compared
+    let leaves = inputs.iter().map(Tensor::detach).collect::<Vec<_>>();
+    let output = objective(&leaves)?;
+    zero_grad(&leaves);
+    output.backward();
+
+    // the graph is still recorded, `f` may rely on it to compute its result
+    let mut worst = Vec::with_capacity(leaves.len());
+    for (i, leaf) in leaves.iter().enumerate() {
+        let (data, shape) = (leaf.data(), leaf.shape());
+        let mut shifted = |index: usize, delta: f64| -> Result<f64, E> {
+            let mut data = data.clone();
+            data[index] += delta;
+            let mut inputs = leaves.clone();
+            inputs[i] = Tensor::from_vec(data, &shape);
+            Ok(objective(&inputs)?.item().expect("a full sum is a scalar"))
+        };
+
+        let mut input_worst: Option<Mismatch> = None;
+        for (index, analytic) in leaf.gradient().into_iter().enumerate() {
+            let numerical = (shifted(index, eps)? - shifted(index, -eps)?) / (2.0 * eps);
+            let mismatch = Mismatch {
+                index,
+                analytic,
+                numerical,
+            };
+            if input_worst.as_ref().is_none_or(|w| mismatch.error() > w.error()) {
+                input_worst = Some(mismatch);
+            }
+        }
+
+        // an empty input has nothing to disagree on
+        worst.push(input_worst.unwrap_or(Mismatch {
+            index: 0,
+            analytic: 0.0,
+            numerical: 0.0,
+        }));
+    }
+
+    Ok(GradcheckReport { worst, tol })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gradcheck_reports_each_input() {
+        let a = Tensor::from_vec(vec![0.5, -1.0, 2.0], &[3]);
+        let b = Tensor::from_vec(vec![1.5, 0.3, -0.7], &[3]);
+        let report = gradcheck(
+            |x| Ok::<_, TensorError>((x[0].clone() * x[1].clone()).tanh()),
+            &[a.clone(), b],
+            1e-6,
+            1e-6,
+        )
+        .unwrap();
+
+        assert_eq!(report.worst.len(), 2);
+        assert!(report.passed(), "{}", report);
+        // the inputs are left without gradients
+        assert_eq!(a.gradient(), vec![0.0; 3]);
+    }
+
+    #[test]
+    fn test_gradcheck_catches_wrong_gradients() {
+        // detaching hides the dependency on x from backward(), but not from finite differences
+        let x = Tensor::from_vec(vec![1.0, 2.0], &[2]);
+        let report = gradcheck(
+            |x| Ok::<_, TensorError>(x[0].clone() * x[0].detach()),
+            &[x],
+            1e-6,
+            1e-6,
+        )
+        .unwrap();
+
+        assert!(!report.passed());
+        let worst = &report.worst[0];
+        assert_eq!(worst.index, 1);
+        assert!((worst.analytic - 4.0).abs() < 1e-9);
+        assert!((worst.numerical - 8.0).abs() < 1e-4);
+    }
+}
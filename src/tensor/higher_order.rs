@@ -0,0 +1,386 @@
+//! Backward passes that record the gradients themselves in the graph, so they can be differentiated again.
+//!
+//! Each operation's vector-Jacobian product is written with tensor operations, looked up by the operation
+//! name recorded on the node.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    sync::{Arc, Weak},
+};
+
+use super::{first_extremum_mask, gelu_derivative, Tape, Tensor, TensorError, TensorResult};
+
+type InputGradients = TensorResult<Vec<Option<Tensor>>>;
+
+thread_local! {
+    /// Leaves given a tracked gradient by the last `backward_create_graph`, by their tape and index
+    static TRACKED_LEAVES: RefCell<Vec<(Weak<Tape>, usize)>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+/// Drops the tracked gradients handed out by the last `backward_create_graph`. The graph of a tracked gradient
+/// refers back to its leaf, so the leaf and the graph would keep each other alive forever otherwise. Their
+/// values stay in the plain gradients
+pub(super) fn release_tracked_gradients() {
+    for (tape, index) in TRACKED_LEAVES.with(|leaves| leaves.take()) {
+        let Some(tape) = tape.upgrade() else {
+            continue;
+        };
+        // dropped once the tape is unlocked, freeing the graph may free other tapes
+        let gradient = tape.lock()[index].tracked_gradient.take();
+        drop(gradient);
+    }
+}
+
+impl Tensor {
+    /// Like `backward`, but the gradients are computed with tensor operations: `grad()` of the leaves returns
+    /// a tensor that is part of the graph and can be differentiated again, e.g. for second derivatives.
+    ///
+    /// The leaves keep their tracked gradients until the next backward pass, after that `grad()` gives their
+    /// values only
+    pub fn backward_create_graph(&self) -> TensorResult<()> {
+        release_tracked_gradients();
+        for tensor in &self.topological_order() {
+            if !tensor.is_leaf() {
+                tensor.clear_gradient();
+            }
+        }
+
+        let mut tracked = Vec::new();
+        let result = self.propagate_tracked(|tensor, gradient| {
+            if tensor.is_leaf() {
+                // gradients of earlier passes are added as constants, their graphs were released
+                let total = if tensor.gradient().iter().any(|g| *g != 0.0) {
+                    tensor.grad() + gradient.clone()
+                } else {
+                    gradient.clone()
+                };
+                tensor.node_mut().tracked_gradient = Some(total);
+                tracked.push((Arc::downgrade(&tensor.tape), tensor.index));
+            }
+            tensor.accumulate_gradient(&gradient.data());
+            true
+        });
+
+        TRACKED_LEAVES.with(|leaves| leaves.borrow_mut().extend(tracked));
+        result
+    }
+
+    /// Gradients of this tensor with respect to each of `inputs`, as tensors that are part of the graph like
+    /// with `backward_create_graph`. Nothing is accumulated, the gradients of every tensor are left untouched,
+    /// and inputs this tensor doesn't depend on get a zero gradient
+    pub fn gradients(&self, inputs: &[Tensor]) -> TensorResult<Vec<Tensor>> {
+        let mut found = HashMap::new();
+        self.propagate_tracked(|tensor, gradient| {
+            // the graph before an input doesn't change its gradient
+            if !inputs.contains(tensor) {
+                return true;
+            }
+            found.insert(tensor.key(), gradient.clone());
+            false
+        })?;
+
+        let gradients = inputs.iter().map(|input| match found.get(&input.key()) {
+            Some(gradient) => gradient.clone(),
+            None => Tensor::zeros(&input.shape()),
+        });
+        Ok(gradients.collect())
+    }
+
+    /// Walks the graph from this tensor back to its leaves, handing every tensor its total gradient as a tensor.
+    /// `visit` returns whether the gradient flows on into the tensor's inputs
+    fn propagate_tracked(
+        &self,
+        mut visit: impl FnMut(&Tensor, &Tensor) -> bool,
+    ) -> TensorResult<()> {
+        let topo = self.topological_order();
+        let mut gradients = HashMap::new();
+        gradients.insert(self.key(), Tensor::ones(&self.shape()));
+
+        for tensor in topo.iter().rev() {
+            // nothing flows into inputs of non-differentiable operations
+            let Some(gradient) = gradients.remove(&tensor.key()) else {
+                continue;
+            };
+            if !visit(tensor, &gradient) {
+                continue;
+            }
+
+            let Some(operation) = tensor.operation() else {
+                continue;
+            };
+            let inputs = tensor.inputs();
+
+            let input_gradients = vjp(operation, &inputs, tensor, gradient)?;
+            for (input, input_gradient) in inputs.iter().zip(input_gradients) {
+                let Some(input_gradient) = input_gradient else {
+                    continue;
+                };
+                let total = match gradients.remove(&input.key()) {
+                    Some(previous) => previous + input_gradient,
+                    None => input_gradient,
+                };
+                gradients.insert(input.key(), total);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Gradients of the inputs of `operation` given the gradient `g` of its `output`, `None` for inputs nothing
+/// flows into
+fn vjp(operation: &str, inputs: &[Tensor], output: &Tensor, g: Tensor) -> InputGradients {
+    let (x, y) = (inputs[0].clone(), output.clone());
+    let c = |value: f64| Tensor::from(value);
+    let unary = |derivative: Tensor| -> InputGradients { Ok(vec![Some(g.clone() * derivative)]) };
+
+    match operation {
+        "+" => binary(inputs, g.clone(), g),
+        "-" => binary(inputs, g.clone(), -g),
+        "*" => binary(inputs, g.clone() * inputs[1].clone(), g * x),
+        "/" => {
+            let b = inputs[1].clone();
+            binary(inputs, g.clone() / b.clone(), -g * x / (b.clone() * b))
+        }
+        "^" => {
+            let p = inputs[1].clone();
+            let base = g.clone() * p.clone() * x.pow(&(p - c(1.0)));
+            // like the first-order gradient, d/dp is only defined for positive bases
+            let positive = x.map_leaf(|x| (x > 0.0) as i32 as f64);
+            let safe_base = x * positive.clone() + (c(1.0) - positive.clone());
+            binary(inputs, base, g * y * safe_base.log() * positive)
+        }
+        "neg" => Ok(vec![Some(-g)]),
+        "tanh" => unary(c(1.0) - y.clone() * y),
+        "relu" => unary(x.map_leaf(|x| (x > 0.0) as i32 as f64)),
+        "exp" => unary(y),
+        "log" => unary(c(1.0) / x),
+        "sqrt" => unary(c(0.5) / y),
+        "abs" => unary(x.map_leaf(|x| if x == 0.0 { 0.0 } else { x.signum() })),
+        "sin" => unary(x.cos()),
+        "cos" => unary(-x.sin()),
+        "sigmoid" => unary(y.clone() * (c(1.0) - y)),
+        "softplus" => unary(x.sigmoid()),
+        "gelu" => unary(gelu_derivative(&x)),
+        "leaky_relu" => {
+            let slope = output.param();
+            let derivative = x.map_leaf(|x| if x > 0.0 { 1.0 } else { slope });
+            unary(derivative)
+        }
+        "clamp" => unary(x.compare(&y, |x, c| x == c)?),
+        "reshape" => Ok(vec![Some(g.reshape(&x.shape())?)]),
+        "transpose" => Ok(vec![Some(g.transpose()?)]),
+        // the gradient of a reduction keeps the reduced axes, so it broadcasts back over the input
+        "sum" => unary(Tensor::ones(&x.shape())),
+        "prod" => {
+            if x.data().contains(&0.0) {
+                let message = "create_graph through 'prod' is only supported without zero elements";
+                return Err(TensorError::Unsupported(message.to_string()));
+            }
+            unary(y / x)
+        }
+        // routed to the first extremum of each group, like the first-order gradient
+        "max" | "min" => unary(first_extremum_mask(&x, &y)),
+        "@" => {
+            // vectors are promoted to a row on the left and a column on the right, like in the forward pass
+            let (a, b) = (x, inputs[1].clone());
+            let (a_shape, b_shape) = (a.shape(), b.shape());
+            let a2 = if a_shape.len() == 1 { a.reshape(&[1, a_shape[0]])? } else { a };
+            let b2 = if b_shape.len() == 1 { b.reshape(&[b_shape[0], 1])? } else { b };
+            let g2 = g.reshape(&[a2.shape()[0], b2.shape()[1]])?;
+
+            let a_grad = g2.matmul(&b2.transpose()?)?.reshape(&a_shape)?;
+            let b_grad = a2.transpose()?.matmul(&g2)?.reshape(&b_shape)?;
+            Ok(vec![Some(a_grad), Some(b_grad)])
+        }
+        // losses are means over the elements, with the partial derivatives their propagate functions use
+        "mse_loss" | "l1_loss" | "huber_loss" => {
+            let n = c(x.len().max(1) as f64);
+            let difference = x - inputs[1].clone();
+            let derivative = match operation {
+                "mse_loss" => difference * c(2.0),
+                "l1_loss" => difference.map_leaf(|d| if d == 0.0 { 0.0 } else { d.signum() }),
+                _ => difference.clamp(-output.param(), output.param())?,
+            };
+            let p_grad = g * derivative / n;
+            Ok(vec![Some(p_grad.clone()), Some(-p_grad)])
+        }
+        "binary_cross_entropy" => {
+            let (t, n) = (inputs[1].clone(), c(x.len().max(1) as f64));
+            let clamped_log = |x: Tensor| x.log().clamp(-100.0, f64::INFINITY);
+            let one_minus = c(1.0) - x.clone();
+            let variance = (one_minus.clone() * x.clone()).clamp(1e-12, f64::INFINITY)?;
+            let p_grad = g.clone() * (x.clone() - t) / variance / n.clone();
+            let t_grad = g * (clamped_log(one_minus)? - clamped_log(x)?) / n;
+            Ok(vec![Some(p_grad), Some(t_grad)])
+        }
+        "hinge_loss" => {
+            let (t, n) = (inputs[1].clone(), c(x.len().max(1) as f64));
+            let active = (c(1.0) - t.clone() * x.clone()).map_leaf(|m| (m > 0.0) as i32 as f64);
+            let scale = -g * active / n;
+            Ok(vec![Some(scale.clone() * t), Some(scale * x)])
+        }
+        // softmax minus the one-hot target, the class indices have no gradient
+        "cross_entropy" => {
+            let classes = *x.shape().last().expect("checked by the loss");
+            let mut one_hot = vec![0.0; x.len()];
+            for (row, t) in inputs[1].data().iter().enumerate() {
+                one_hot[row * classes + *t as usize] = 1.0;
+            }
+            let batch = c((x.len() / classes) as f64);
+            let derivative = x.softmax(-1)? - Tensor::from_vec(one_hot, &x.shape());
+            Ok(vec![Some(g * derivative / batch), None])
+        }
+        op => {
+            let message = format!("create_graph is not supported through '{}'", op);
+            Err(TensorError::Unsupported(message))
+        }
+    }
+}
+
+/// Gradients of a broadcasting binary operation, each summed back to the shape of its input
+fn binary(inputs: &[Tensor], a_grad: Tensor, b_grad: Tensor) -> InputGradients {
+    Ok(vec![
+        Some(unbroadcast(a_grad, &inputs[0].shape())?),
+        Some(unbroadcast(b_grad, &inputs[1].shape())?),
+    ])
+}
+
+/// Sums `gradient` over the axes `shape` was broadcast along
+fn unbroadcast(mut gradient: Tensor, shape: &[usize]) -> TensorResult<Tensor> {
+    while gradient.shape().len() > shape.len() {
+        gradient = gradient.sum(Some(0), false)?;
+    }
+    for (axis, &size) in shape.iter().enumerate() {
+        if size == 1 && gradient.shape()[axis] != 1 {
+            gradient = gradient.sum(Some(axis as isize), true)?;
+        }
+    }
+    Ok(gradient)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tensor::gradcheck;
+
+    /// Gradient of `f` at `x` as a tracked tensor, leaving `x` without gradients
+    fn tracked_grad(f: impl Fn(&Tensor) -> Tensor, x: &Tensor) -> Tensor {
+        f(x).backward_create_graph().unwrap();
+        let grad = x.grad();
+        x.clear_gradient();
+        grad
+    }
+
+    #[test]
+    fn test_polynomial_derivatives() {
+        // f = x^4 - 2x^3 + x, f' = 4x^3 - 6x^2 + 1, f'' = 12x^2 - 12x, f''' = 24x - 12
+        let f = |x: &Tensor| {
+            x.pow(&Tensor::from(4.0)) - Tensor::from(2.0) * x.pow(&Tensor::from(3.0)) + x.clone()
+        };
+        let x = Tensor::from_vec(vec![-1.0, 0.5, 2.0], &[3]);
+
+        let first = tracked_grad(|x| f(x).sum(None, false).unwrap(), &x);
+        assert_eq!(first.data(), vec![-9.0, 0.0, 9.0]);
+
+        let second = tracked_grad(|_| first.sum(None, false).unwrap(), &x);
+        assert_eq!(second.data(), vec![24.0, -3.0, 24.0]);
+
+        second.sum(None, false).unwrap().backward();
+        assert_eq!(x.gradient(), vec![-36.0, 0.0, 36.0]);
+    }
+
+    #[test]
+    fn test_second_derivatives_of_every_operation() {
+        let positive = Tensor::from_vec(vec![0.3, 1.2, 2.5, 0.7], &[2, 2]);
+        let mixed = Tensor::from_vec(vec![-1.3, 0.4, 2.1, -0.6], &[2, 2]);
+        let row = Tensor::from_vec(vec![0.5, -1.5], &[2]);
+
+        type Case = fn(&[Tensor]) -> Tensor;
+        let cases: [(Case, &Tensor); 13] = [
+            (|x| (x[0].clone() * x[0].clone() + x[0].clone()).exp() / x[0].clone(), &mixed),
+            (|x| x[0].log() * x[0].sqrt() - x[0].pow(&x[0]), &positive),
+            (|x| x[0].tanh() + x[0].sigmoid() + x[0].softplus() + x[0].gelu(), &mixed),
+            (|x| x[0].sin() * x[0].cos() + -x[0].abs() * x[0].relu(), &mixed),
+            (|x| x[0].softmax(-1).unwrap() * x[0].clone(), &mixed),
+            (|x| x[0].log_softmax(0).unwrap().pow(&Tensor::from(2.0)), &mixed),
+            (|x| x[0].matmul(&x[0].tanh()).unwrap().sigmoid(), &mixed),
+            (|x| x[0].pow(&Tensor::from(2.0)).prod(Some(1), true).unwrap() * x[0].clone(), &positive),
+            (|x| x[0].transpose().unwrap().max(Some(0), false).unwrap().exp(), &mixed),
+            (|x| x[0].mse_loss(&x[0].tanh()).unwrap() + x[0].l1_loss(&x[0].sin()).unwrap(), &mixed),
+            (
+                |x| {
+                    let huber = x[0].huber_loss(&x[0].cos(), 0.5).unwrap();
+                    huber * x[0].hinge_loss(&x[0].exp()).unwrap()
+                },
+                &mixed,
+            ),
+            (|x| x[0].sigmoid().binary_cross_entropy(&x[0].cos().abs()).unwrap(), &mixed),
+            (|x| x[0].cross_entropy(&Tensor::from_vec(vec![1.0, 0.0], &[2])).unwrap(), &mixed),
+        ];
+
+        for (i, (f, input)) in cases.iter().enumerate() {
+            let objective = |x: &[Tensor]| (f(x) * (row.clone() + x[0].clone())).sum(None, false);
+
+            // the tracked gradient has the values backward() computes
+            let x = [input.detach()];
+            objective(&x).unwrap().backward();
+            let expected = x[0].gradient();
+            x[0].clear_gradient();
+            objective(&x).unwrap().backward_create_graph().unwrap();
+            for (a, e) in x[0].grad().data().iter().zip(&expected) {
+                assert!((a - e).abs() < 1e-12, "case {}: {} != {}", i, a, e);
+            }
+
+            // and differentiating it again matches finite differences
+            let report = gradcheck(
+                |x| {
+                    objective(x)?.backward_create_graph()?;
+                    let grad = x[0].grad();
+                    x[0].clear_gradient();
+                    Ok::<_, TensorError>(grad)
+                },
+                &[(*input).clone()],
+                1e-6,
+                1e-5,
+            )
+            .unwrap();
+            assert!(report.passed(), "case {}: {}", i, report);
+        }
+    }
+
+    #[test]
+    fn test_gradients_leave_the_graph_untouched() {
+        // f = a^2 b, df/da = 2ab, df/db = a^2
+        let (a, b) = (Tensor::from(3.0), Tensor::from(2.0));
+        let f = a.pow(&Tensor::from(2.0)) * b.clone();
+
+        let gradients = f.gradients(&[a.clone(), b.clone(), Tensor::from(1.0)]).unwrap();
+        let values = gradients.iter().map(|g| g.item().unwrap()).collect::<Vec<_>>();
+        assert_eq!(values, vec![12.0, 9.0, 0.0]);
+        assert_eq!((a.gradient(), b.gradient(), f.gradient()), (vec![0.0], vec![0.0], vec![0.0]));
+
+        // the gradients can be differentiated again, d2f/da2 = 2b
+        let second = gradients[0].gradients(&[a]).unwrap();
+        assert_eq!(second[0].item(), Some(4.0));
+    }
+
+    #[test]
+    fn test_tracked_gradients_are_released() {
+        let x = Tensor::from(3.0);
+        (x.clone() * x.clone()).backward_create_graph().unwrap();
+        assert!(x.grad().operation().is_some());
+
+        // the next backward pass releases the graph of the tracked gradient, keeping its value
+        Tensor::from(1.0).exp().backward();
+        assert_eq!(x.grad().operation(), None);
+        assert_eq!(x.grad().item(), Some(6.0));
+
+        // so the leaf is freed once dropped, rather than kept alive through its own gradient
+        let tape = Arc::downgrade(&x.tape);
+        drop(x);
+        assert!(tape.upgrade().is_none());
+    }
+}
@@ -0,0 +1,289 @@
+//! Loss functions reducing a prediction and its target to a scalar mean, each one a single graph node
+//! with its own `PropagateFn`.
+
+use super::{InputGradients, PropagateFn, Propagation, Tensor, TensorError, TensorResult};
+
+/// `log` as used by `binary_cross_entropy`, bounded below like PyTorch so saturated probabilities stay finite
+fn clamped_log(x: f64) -> f64 {
+    x.ln().max(-100.0)
+}
+
+impl Tensor {
+    pub fn mse_loss(&self, target: &Tensor) -> TensorResult<Tensor> {
+        let prop_fn: PropagateFn = |value| propagate_pairwise(value, |p, t, _| 2.0 * (p - t));
+
+        self.pairwise_loss(target, "mse_loss", 0.0, |p, t, _| (p - t).powi(2), prop_fn)
+    }
+
+    pub fn l1_loss(&self, target: &Tensor) -> TensorResult<Tensor> {
+        let prop_fn: PropagateFn = |value| {
+            propagate_pairwise(value, |p, t, _| if p == t { 0.0 } else { (p - t).signum() })
+        };
+
+        self.pairwise_loss(target, "l1_loss", 0.0, |p, t, _| (p - t).abs(), prop_fn)
+    }
+
+    /// Squared error below `delta`, absolute error above it
+    pub fn huber_loss(&self, target: &Tensor, delta: f64) -> TensorResult<Tensor> {
+        if delta.is_nan() || delta <= 0.0 {
+            let message = format!("huber_loss expects a positive delta, got {}", delta);
+            return Err(TensorError::InvalidArgument(message));
+        }
+        let prop_fn: PropagateFn = |value| {
+            propagate_pairwise(value, |p, t, delta| (p - t).clamp(-delta, delta))
+        };
+
+        let huber = |p: f64, t: f64, delta: f64| {
+            let d = (p - t).abs();
+            if d <= delta {
+                0.5 * d * d
+            } else {
+                delta * (d - 0.5 * delta)
+            }
+        };
+        self.pairwise_loss(target, "huber_loss", delta, huber, prop_fn)
+    }
+
+    /// Cross entropy of probabilities in `[0, 1]` against binary targets
+    pub fn binary_cross_entropy(&self, target: &Tensor) -> TensorResult<Tensor> {
+        let prop_fn: PropagateFn = |value| {
+            let (p, t) = (value.input(0), value.input(1));
+            let (n, g) = (p.data.len() as f64, value.gradient[0]);
+            let mut p_grad = Vec::with_capacity(p.data.len());
+            let mut t_grad = Vec::with_capacity(p.data.len());
+            for (p, t) in p.data.iter().zip(&t.data) {
+                p_grad.push((p - t) / ((1.0 - p) * p).max(1e-12) / n * g);
+                t_grad.push((clamped_log(1.0 - p) - clamped_log(*p)) / n * g);
+            }
+            [Some(p_grad), Some(t_grad)]
+        };
+
+        // adding 0 turns the -0 of exact predictions into 0
+        let bce =
+            |p: f64, t: f64, _| -(t * clamped_log(p) + (1.0 - t) * clamped_log(1.0 - p)) + 0.0;
+        self.pairwise_loss(target, "binary_cross_entropy", 0.0, bce, prop_fn)
+    }
+
+    /// Hinge loss `max(0, 1 - t * s)` of scores against targets of -1 or 1
+    pub fn hinge_loss(&self, target: &Tensor) -> TensorResult<Tensor> {
+        let prop_fn: PropagateFn = |value| {
+            let (s, t) = (value.input(0), value.input(1));
+            let (n, g) = (s.data.len() as f64, value.gradient[0]);
+            let mut s_grad = Vec::with_capacity(s.data.len());
+            let mut t_grad = Vec::with_capacity(s.data.len());
+            for (s, t) in s.data.iter().zip(&t.data) {
+                let active = (1.0 - t * s > 0.0) as i32 as f64;
+                s_grad.push(-t * active / n * g);
+                t_grad.push(-s * active / n * g);
+            }
+            [Some(s_grad), Some(t_grad)]
+        };
+
+        let hinge = |s: f64, t: f64, _| (1.0 - t * s).max(0.0);
+        self.pairwise_loss(target, "hinge_loss", 0.0, hinge, prop_fn)
+    }
+
+    /// Cross entropy of `[batch, classes]` logits (or `[classes]` for a single sample) against the
+    /// class index of each sample, computed through a shifted log-sum-exp so large logits can't overflow
+    pub fn cross_entropy(&self, target: &Tensor) -> TensorResult<Tensor> {
+        let (logits_shape, target_shape) = (self.shape(), target.shape());
+        let classes = match (logits_shape.as_slice(), target_shape.as_slice()) {
+            ([batch, classes], [targets]) if batch == targets => *classes,
+            ([classes], []) => *classes,
+            _ => {
+                return Err(TensorError::Shape(format!(
+                    "cross_entropy expects [batch, classes] logits and [batch] targets, got {:?} and {:?}",
+                    logits_shape, target_shape
+                )));
+            }
+        };
+        if classes == 0 {
+            let message = "cross_entropy expects at least one class".to_string();
+            return Err(TensorError::InvalidArgument(message));
+        }
+        if let Some(t) = target
+            .data()
+            .into_iter()
+            .find(|t| t.fract() != 0.0 || *t < 0.0 || *t >= classes as f64)
+        {
+            let message = format!("Invalid class index {} for {} classes", t, classes);
+            return Err(TensorError::InvalidArgument(message));
+        }
+
+        let logits = self.data();
+        let rows = logits.chunks(classes).zip(target.data());
+        let total = rows
+            .map(|(row, t)| log_sum_exp(row) - row[t as usize])
+            .sum::<f64>();
+        let loss = total / (logits.len() / classes) as f64;
+
+        let prop_fn: PropagateFn = |value| {
+            let (logits, target) = (value.input(0), value.input(1));
+            let classes = *logits.shape.last().expect("checked in the forward pass");
+            let batch = logits.data.len() / classes;
+            let g = value.gradient[0];
+
+            // softmax minus the one-hot target
+            let mut grad = Vec::with_capacity(logits.data.len());
+            for (row, t) in logits.data.chunks(classes).zip(&target.data) {
+                let lse = log_sum_exp(row);
+                for (c, x) in row.iter().enumerate() {
+                    let one_hot = (c == *t as usize) as i32 as f64;
+                    grad.push(((x - lse).exp() - one_hot) / batch as f64 * g);
+                }
+            }
+            // the class indices have no gradient
+            [Some(grad), None]
+        };
+
+        Ok(Tensor::from_op(vec![loss], vec![], "cross_entropy", &[self, target], prop_fn))
+    }
+
+    /// Mean of `f(prediction, target, param)` over the elements of two tensors of the same shape,
+    /// `param` is recorded with the operation, e.g. the `delta` of `huber_loss`
+    fn pairwise_loss(
+        &self,
+        target: &Tensor,
+        op: &'static str,
+        param: f64,
+        f: impl Fn(f64, f64, f64) -> f64,
+        propagate: PropagateFn,
+    ) -> TensorResult<Tensor> {
+        if self.shape() != target.shape() {
+            return Err(TensorError::Shape(format!(
+                "{} expects a prediction and target of the same shape, got {:?} and {:?}",
+                op,
+                self.shape(),
+                target.shape()
+            )));
+        }
+
+        let (prediction, target_data) = (self.data(), target.data());
+        let total = prediction
+            .iter()
+            .zip(&target_data)
+            .map(|(p, t)| f(*p, *t, param))
+            .sum::<f64>();
+        let loss = total / prediction.len().max(1) as f64;
+
+        let inputs = [self, target];
+        Ok(Tensor::from_op_with_param(vec![loss], vec![], op, &inputs, param, propagate))
+    }
+}
+
+/// Propagates a pairwise loss whose derivative with respect to the prediction is `derivative(p, t, param)`
+/// and the negation of it with respect to the target
+fn propagate_pairwise(value: &Propagation, derivative: fn(f64, f64, f64) -> f64) -> InputGradients {
+    let (p, t) = (value.input(0), value.input(1));
+    let scale = value.gradient[0] / p.data.len().max(1) as f64;
+    let p_grad = p
+        .data
+        .iter()
+        .zip(&t.data)
+        .map(|(p, t)| derivative(*p, *t, value.param) * scale)
+        .collect::<Vec<_>>();
+
+    let t_grad = p_grad.iter().map(|g| -g).collect();
+    [Some(p_grad), Some(t_grad)]
+}
+
+fn log_sum_exp(row: &[f64]) -> f64 {
+    let max = row.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    max + row.iter().map(|x| (x - max).exp()).sum::<f64>().ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-12, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    type LossFn = fn(&Tensor, &Tensor) -> Tensor;
+
+    #[test]
+    fn test_regression_losses() {
+        let target = Tensor::from_vec(vec![1.0, 0.0, 5.0], &[3]);
+        let third = 1.0 / 3.0;
+
+        let cases: [(LossFn, f64, [f64; 3]); 3] = [
+            (|p, t| p.mse_loss(t).unwrap(), 8.0 / 3.0, [0.0, 4.0 * third, -4.0 * third]),
+            (|p, t| p.l1_loss(t).unwrap(), 4.0 / 3.0, [0.0, third, -third]),
+            (|p, t| p.huber_loss(t, 1.0).unwrap(), 1.0, [0.0, third, -third]),
+        ];
+        for (loss_fn, expected_loss, expected_grad) in cases {
+            let prediction = Tensor::from_vec(vec![1.0, 2.0, 3.0], &[3]);
+            target.clear_gradient();
+
+            let loss = loss_fn(&prediction, &target);
+            assert!((loss.item().unwrap() - expected_loss).abs() < 1e-12);
+            loss.backward();
+
+            assert_close(&prediction.gradient(), &expected_grad);
+            assert_close(&target.gradient(), &expected_grad.map(|g| -g));
+        }
+
+        assert_eq!(
+            target.mse_loss(&Tensor::zeros(&[3, 1])).unwrap_err(),
+            TensorError::Shape(
+                "mse_loss expects a prediction and target of the same shape, got [3] and [3, 1]"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            target.huber_loss(&target, -1.0).unwrap_err(),
+            TensorError::InvalidArgument("huber_loss expects a positive delta, got -1".to_string())
+        );
+        assert!(target.huber_loss(&target, f64::NAN).is_err());
+    }
+
+    #[test]
+    fn test_classification_losses() {
+        let p = Tensor::from_vec(vec![0.8, 0.3], &[2]);
+        let bce = p.binary_cross_entropy(&Tensor::from_vec(vec![1.0, 0.0], &[2])).unwrap();
+        assert!((bce.item().unwrap() + (0.8_f64.ln() + 0.7_f64.ln()) / 2.0).abs() < 1e-12);
+        bce.backward();
+        assert_close(&p.gradient(), &[-0.2 / 0.16 / 2.0, 0.3 / 0.21 / 2.0]);
+
+        // saturated probabilities stay finite
+        let saturated = Tensor::from(1.0).binary_cross_entropy(&Tensor::from(0.0)).unwrap();
+        assert_eq!(saturated.item(), Some(100.0));
+
+        let scores = Tensor::from_vec(vec![2.0, 0.5, -1.0], &[3]);
+        let hinge = scores.hinge_loss(&Tensor::ones(&[3])).unwrap();
+        assert!((hinge.item().unwrap() - 2.5 / 3.0).abs() < 1e-12);
+        hinge.backward();
+        assert_close(&scores.gradient(), &[0.0, -1.0 / 3.0, -1.0 / 3.0]);
+    }
+
+    #[test]
+    fn test_cross_entropy() {
+        let logits = Tensor::from_vec(vec![1.0, 2.0, 3.0, 1000.0, 0.0, 0.0], &[2, 3]);
+        let targets = Tensor::from_vec(vec![2.0, 0.0], &[2]);
+
+        let loss = logits.cross_entropy(&targets).unwrap();
+        let sum = 1.0 + (-1.0_f64).exp() + (-2.0_f64).exp();
+        assert!((loss.item().unwrap() - sum.ln() / 2.0).abs() < 1e-12);
+
+        loss.backward();
+        let softmax = [(-2.0_f64).exp() / sum, (-1.0_f64).exp() / sum, 1.0 / sum];
+        let expected = [softmax[0], softmax[1], softmax[2] - 1.0, 0.0, 0.0, 0.0].map(|g| g / 2.0);
+        assert_close(&logits.gradient(), &expected);
+
+        let single = Tensor::from_vec(vec![0.0, 0.0], &[2]).cross_entropy(&Tensor::from(1.0));
+        assert!((single.unwrap().item().unwrap() - 2.0_f64.ln()).abs() < 1e-12);
+
+        assert_eq!(
+            logits.cross_entropy(&Tensor::from_vec(vec![0.0, 3.0], &[2])).unwrap_err(),
+            TensorError::InvalidArgument("Invalid class index 3 for 3 classes".to_string())
+        );
+        assert_eq!(
+            Tensor::zeros(&[0, 0]).cross_entropy(&Tensor::zeros(&[0])).unwrap_err(),
+            TensorError::InvalidArgument("cross_entropy expects at least one class".to_string())
+        );
+    }
+}
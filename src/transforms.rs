@@ -0,0 +1,251 @@
+//! Function transformations in the style of JAX: `grad(f)`, `value_and_grad(f)`, `jacobian(f)` and
+//! `hessian(f)` return a new function computing derivatives of `f`.
+//!
+//! The derivatives are tensors recorded in the graph, so transformed functions can be transformed again,
+//! e.g. `grad(grad(f))`.
+
+use crate::{
+    natives::to_tensor,
+    tensor::{self, Tensor, TensorResult},
+    value::ValueType,
+    vm::{RuntimeError, RuntimeResult, VM},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransformKind {
+    Grad,
+    ValueAndGrad,
+    Jacobian,
+    Hessian,
+}
+
+impl TransformKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransformKind::Grad => "grad",
+            TransformKind::ValueAndGrad => "value_and_grad",
+            TransformKind::Jacobian => "jacobian",
+            TransformKind::Hessian => "hessian",
+        }
+    }
+}
+
+/// The arguments a transform differentiates with respect to, a list gives a list of derivatives
+#[derive(Debug, Clone, PartialEq)]
+pub enum Argnums {
+    Single(usize),
+    Many(Vec<usize>),
+}
+
+/// A function of the language wrapped by a transformation, callable like the function itself
+#[derive(Debug)]
+pub struct Transform {
+    pub kind: TransformKind,
+    pub function: ValueType,
+    pub argnums: Argnums,
+}
+
+impl Transform {
+    /// Wraps `function`, differentiating with respect to `argnums`, an int or a list of ints defaulting to 0
+    pub fn new(
+        kind: TransformKind,
+        function: ValueType,
+        argnums: Option<&ValueType>,
+    ) -> RuntimeResult<Transform> {
+        if !matches!(
+            function,
+            ValueType::Function(_)
+                | ValueType::NativeFunction(_)
+                | ValueType::Module(_)
+                | ValueType::Transform(_)
+        ) {
+            return Err(RuntimeError::TypeMismatch(format!(
+                "{}() expects a function, got '{}'",
+                kind.name(),
+                function.type_name()
+            )));
+        }
+
+        let index = |value: &ValueType| match value {
+            ValueType::Integer(i) if *i >= 0 => Ok(*i as usize),
+            ValueType::Integer(i) => Err(RuntimeError::InvalidArgument(format!(
+                "argnum must be non-negative, got {}",
+                i
+            ))),
+            value => Err(RuntimeError::TypeMismatch(format!(
+                "Expected an int argnum, got '{}'",
+                value.type_name()
+            ))),
+        };
+        let argnums = match argnums {
+            None => Argnums::Single(0),
+            Some(ValueType::List(values)) => {
+                Argnums::Many(values.iter().map(index).collect::<Result<_, _>>()?)
+            }
+            Some(value) => Argnums::Single(index(value)?),
+        };
+        if kind == TransformKind::Hessian && matches!(argnums, Argnums::Many(_)) {
+            let message = "hessian() differentiates with respect to a single argument".to_string();
+            return Err(RuntimeError::InvalidArgument(message));
+        }
+
+        Ok(Transform {
+            kind,
+            function,
+            argnums,
+        })
+    }
+
+    /// Name used when printing the function and in errors, e.g. `grad(f)`
+    pub fn name(&self) -> String {
+        let function = match &self.function {
+            ValueType::Function(function) => function.name.clone(),
+            ValueType::NativeFunction(native) => native.name.to_string(),
+            ValueType::Module(module) => module.name().to_string(),
+            ValueType::Transform(transform) => transform.name(),
+            value => value.type_name().to_string(),
+        };
+        format!("{}({})", self.kind.name(), function)
+    }
+
+    pub fn call(&self, vm: &mut VM, args: &[ValueType]) -> RuntimeResult<ValueType> {
+        // the derivatives are reverse-mode graphs of tensors, which can't carry the tangents of `jvp`
+        if args.iter().any(|arg| matches!(arg, ValueType::Dual(_))) {
+            return Err(RuntimeError::TypeMismatch(format!(
+                "'{}' can't take dual inputs, so it can't be called inside jvp()",
+                self.name()
+            )));
+        }
+
+        let indices = match &self.argnums {
+            Argnums::Single(i) => vec![*i],
+            Argnums::Many(indices) => indices.clone(),
+        };
+        if let Some(i) = indices.iter().find(|i| **i >= args.len()) {
+            return Err(RuntimeError::ArityMismatch {
+                expected: format!("at least {}", i + 1),
+                got: args.len(),
+                name: self.name(),
+            });
+        }
+
+        // the gradients come from the graph, so it's recorded even inside a `no_grad` block
+        let _grad = tensor::enable_grad();
+
+        // each differentiated argument gets its own node, so passing the same tensor twice still gives
+        // the derivative of each argument, while the node stays part of any enclosing transformation.
+        // An argnum listed twice is differentiated once, `positions` maps each argnum to its input
+        let mut args = args.to_vec();
+        let mut inputs = Vec::with_capacity(indices.len());
+        let mut differentiated = Vec::with_capacity(indices.len());
+        let mut positions = Vec::with_capacity(indices.len());
+        for i in indices {
+            if let Some(position) = differentiated.iter().position(|j| *j == i) {
+                positions.push(position);
+                continue;
+            }
+            let tensor = to_tensor(&args[i])?;
+            let input = tensor.reshape(&tensor.shape())?;
+            args[i] = ValueType::Tensor(input.clone());
+            positions.push(inputs.len());
+            inputs.push(input);
+            differentiated.push(i);
+        }
+        let pack = |derivatives: Vec<Tensor>| {
+            self.pack(positions.iter().map(|p| derivatives[*p].clone()).collect())
+        };
+
+        match self.kind {
+            TransformKind::Grad | TransformKind::ValueAndGrad => {
+                let output = vm.call(self.function.clone(), &args)?;
+                let output = self.tensor_output(output)?;
+                if output.len() != 1 {
+                    return Err(RuntimeError::ShapeMismatch(format!(
+                        "'{}' requires a scalar output, got a tensor of shape {:?}",
+                        self.name(),
+                        output.shape()
+                    )));
+                }
+
+                let gradients = pack(output.gradients(&inputs)?);
+                match self.kind {
+                    TransformKind::Grad => Ok(gradients),
+                    _ => Ok(ValueType::List(vec![ValueType::Tensor(output), gradients])),
+                }
+            }
+            TransformKind::Jacobian | TransformKind::Hessian => {
+                // the hessian is the jacobian of the gradient
+                let output = match self.kind {
+                    TransformKind::Hessian => {
+                        let grad = Transform {
+                            kind: TransformKind::Grad,
+                            function: self.function.clone(),
+                            argnums: self.argnums.clone(),
+                        };
+                        grad.call(vm, &args)?
+                    }
+                    _ => vm.call(self.function.clone(), &args)?,
+                };
+                let output = self.tensor_output(output)?;
+                Ok(pack(jacobians(&output, &inputs)?))
+            }
+        }
+    }
+
+    fn tensor_output(&self, output: ValueType) -> RuntimeResult<Tensor> {
+        match output {
+            ValueType::Tensor(tensor) => Ok(tensor),
+            value => Err(RuntimeError::TypeMismatch(format!(
+                "'{}' requires a function returning a tensor, got '{}'",
+                self.name(),
+                value.type_name()
+            ))),
+        }
+    }
+
+    /// One derivative for a single argnum, a list of them for a list of argnums
+    fn pack(&self, mut derivatives: Vec<Tensor>) -> ValueType {
+        match self.argnums {
+            Argnums::Single(_) => ValueType::Tensor(derivatives.remove(0)),
+            Argnums::Many(_) => {
+                ValueType::List(derivatives.into_iter().map(ValueType::Tensor).collect())
+            }
+        }
+    }
+}
+
+/// Jacobian of `output` with respect to each input, of shape `output.shape()` followed by the input's shape.
+/// Each row takes one reverse pass, and is placed with tensor operations so the result stays differentiable
+fn jacobians(output: &Tensor, inputs: &[Tensor]) -> TensorResult<Vec<Tensor>> {
+    let n = output.len();
+    let flat = output.reshape(&[n])?;
+
+    let mut jacobians: Vec<Option<Tensor>> = vec![None; inputs.len()];
+    for i in 0..n {
+        let mut one_hot = vec![0.0; n];
+        one_hot[i] = 1.0;
+        let element = (flat.clone() * Tensor::from_vec(one_hot.clone(), &[n])).sum(None, false)?;
+        let selector = Tensor::from_vec(one_hot, &[n, 1]);
+
+        for (jacobian, gradient) in jacobians.iter_mut().zip(element.gradients(inputs)?) {
+            let row = selector.clone() * gradient.reshape(&[1, gradient.len()])?;
+            *jacobian = Some(match jacobian.take() {
+                Some(previous) => previous + row,
+                None => row,
+            });
+        }
+    }
+
+    inputs
+        .iter()
+        .zip(jacobians)
+        .map(|(input, jacobian)| {
+            let mut shape = output.shape();
+            shape.extend(input.shape());
+            match jacobian {
+                Some(jacobian) => jacobian.reshape(&shape),
+                None => Ok(Tensor::zeros(&shape)),
+            }
+        })
+        .collect()
+}
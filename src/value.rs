@@ -1,20 +1,73 @@
+use std::{cell::RefCell, ops::RangeInclusive, rc::Rc};
+
 use serde::{Deserialize, Serialize};
 
-use crate::{interner::StringObjIdx, tensor::Tensor};
+use crate::{
+    chunk::Chunk,
+    interner::StringObjIdx,
+    nn::Module,
+    optim::Optimizer,
+    tensor::{self, Dual, Tensor, TensorResult},
+    transforms::Transform,
+    vm::{RuntimeError, VM},
+};
+
+/// A compiled function, holding its own bytecode chunk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Function {
+    pub name: String,
+    pub arity: u8,
+    pub chunk: Chunk,
+}
+
+impl Function {
+    pub fn new(name: String, arity: u8) -> Self {
+        Self {
+            name,
+            arity,
+            chunk: Chunk::new(),
+        }
+    }
+}
+
+pub type NativeResult = Result<ValueType, RuntimeError>;
+
+/// A function implemented in Rust, callable from grad code like any other function
+#[derive(Debug, Clone)]
+pub struct NativeFunction {
+    pub name: &'static str,
+    pub arity: RangeInclusive<usize>,
+    pub function: fn(&mut VM, &[ValueType]) -> NativeResult,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ValueType {
-    // Tensor(Tensor), // TODO: Ideally, it should be seperate types for int and float (maybe?)
+    // gradient-tracked values only exist at runtime, so they are never part of serialized bytecode
+    #[serde(skip)]
+    Tensor(Tensor),
+    // a value with its tangent, created by `jvp` for forward-mode differentiation
+    #[serde(skip)]
+    Dual(Dual),
     String(StringObjIdx),
     Identifier(StringObjIdx),
     Boolean(bool),
     Integer(i64),
     Float(f64),
     Nil,
-    // Lists, Dicts, Tensors, etc.
+    List(Vec<ValueType>),
+    // Dicts, etc.
     JumpOffset(usize),
 
-    Function(String),
+    Function(Rc<Function>),
+    #[serde(skip)]
+    NativeFunction(NativeFunction),
+    #[serde(skip)]
+    Optimizer(Rc<RefCell<dyn Optimizer>>),
+    #[serde(skip)]
+    Module(Rc<dyn Module>),
+    // a function wrapped by `grad`, `jacobian`, etc.
+    #[serde(skip)]
+    Transform(Rc<Transform>),
 }
 
 // impl std::fmt::Display for ValueType {
@@ -35,96 +88,247 @@ pub enum ValueType {
 impl ValueType {
     pub fn display(&self, interner: &crate::interner::Interner) -> String {
         match self {
-            // ValueType::Tensor(n) => format!("{}", n),
+            ValueType::Tensor(n) => format!("{}", n),
+            ValueType::Dual(d) => format!("{}", d),
             ValueType::String(s) => interner.lookup(*s).to_string(),
             ValueType::Identifier(s) => interner.lookup(*s).to_string(),
             ValueType::Boolean(b) => format!("{}", b),
             ValueType::Integer(n) => format!("{}", n),
             ValueType::Float(n) => format!("{}", n),
-            ValueType::Nil => format!("nil"),
+            ValueType::Nil => "nil".to_string(),
+            ValueType::List(elements) => format!(
+                "[{}]",
+                elements
+                    .iter()
+                    .map(|element| element.display(interner))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
             ValueType::JumpOffset(j) => format!("jmp->{}", j),
-            ValueType::Function(s) => format!("fn->{}", s),
+            ValueType::Function(function) => format!("fn->{}", function.name),
+            ValueType::NativeFunction(native) => format!("native fn->{}", native.name),
+            ValueType::Optimizer(optimizer) => format!("optimizer->{}", optimizer.borrow().name()),
+            ValueType::Module(module) => format!("module->{}", module.name()),
+            ValueType::Transform(transform) => format!("fn->{}", transform.name()),
         }
     }
 }
 
-// impl +,-,*,/ for ValueType
-impl std::ops::Add for ValueType {
-    type Output = Self;
-
-    fn add(self, other: Self) -> Self {
-        match (self, other) {
-            // (ValueType::Tensor(a), ValueType::Tensor(b)) => ValueType::Tensor(a + b),
-            (ValueType::Integer(a), ValueType::Integer(b)) => ValueType::Integer(a + b),
-            (ValueType::Float(a), ValueType::Float(b)) => ValueType::Float(a + b),
-            (ValueType::Float(a), ValueType::Integer(b)) => ValueType::Float(a + b as f64),
-            (ValueType::Integer(a), ValueType::Float(b)) => ValueType::Float(a as f64 + b),
+impl ValueType {
+    /// Name of the runtime type, used in error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ValueType::Tensor(_) => "tensor",
+            ValueType::Dual(_) => "dual",
+            ValueType::String(_) => "string",
+            ValueType::Identifier(_) => "identifier",
+            ValueType::Boolean(_) => "bool",
+            ValueType::Integer(_) => "int",
+            ValueType::Float(_) => "float",
+            ValueType::Nil => "nil",
+            ValueType::List(_) => "list",
+            ValueType::JumpOffset(_) => "jump offset",
+            ValueType::Function(_) | ValueType::NativeFunction(_) | ValueType::Transform(_) => {
+                "function"
+            }
+            ValueType::Optimizer(_) => "optimizer",
+            ValueType::Module(_) => "module",
+        }
+    }
 
-            (a, b) => panic!("Operands must be numbers. Got: {:?} and {:?}", a, b),
+    /// Lifts numbers into tensors so they can be combined with gradient-tracked values
+    fn to_tensor(&self) -> Option<Tensor> {
+        match self {
+            ValueType::Tensor(t) => Some(t.clone()),
+            ValueType::Dual(d) => Some(d.value.clone()),
+            ValueType::Integer(n) => Some(Tensor::from(*n as f64)),
+            ValueType::Float(n) => Some(Tensor::from(*n)),
+            _ => None,
         }
     }
-}
 
-impl std::ops::Sub for ValueType {
-    type Output = Self;
+    /// Returns both operands as tensors if at least one of them is a tensor and the other a number
+    fn tensor_operands(&self, other: &Self) -> Option<(Tensor, Tensor)> {
+        let is_tensor = |value: &Self| matches!(value, ValueType::Tensor(_) | ValueType::Dual(_));
+        if !is_tensor(self) && !is_tensor(other) {
+            return None;
+        }
+        Some((self.to_tensor()?, other.to_tensor()?))
+    }
 
-    fn sub(self, other: Self) -> Self {
-        match (self, other) {
-            // (ValueType::Tensor(a), ValueType::Tensor(b)) => ValueType::Tensor(a - b),
-            (ValueType::Integer(a), ValueType::Integer(b)) => ValueType::Integer(a - b),
-            (ValueType::Float(a), ValueType::Float(b)) => ValueType::Float(a - b),
-            _ => panic!("Operands must be numbers."),
+    /// Lifts tensors and numbers into duals with a zero tangent
+    pub fn to_dual(&self) -> Option<Dual> {
+        match self {
+            ValueType::Dual(d) => Some(d.clone()),
+            value => Some(Dual::constant(value.to_tensor()?)),
         }
     }
-}
 
-impl std::ops::Mul for ValueType {
-    type Output = Self;
+    /// Returns both operands as duals if at least one of them is a dual and the other a tensor or number
+    fn dual_operands(&self, other: &Self) -> Option<(Dual, Dual)> {
+        if !matches!(self, ValueType::Dual(_)) && !matches!(other, ValueType::Dual(_)) {
+            return None;
+        }
+        Some((self.to_dual()?, other.to_dual()?))
+    }
 
-    fn mul(self, other: Self) -> Self {
-        match (self, other) {
-            // (ValueType::Tensor(a), ValueType::Tensor(b)) => ValueType::Tensor(a * b),
-            (ValueType::Integer(a), ValueType::Integer(b)) => ValueType::Integer(a * b),
-            (ValueType::Float(a), ValueType::Float(b)) => ValueType::Float(a * b),
-            _ => panic!("Operands must be numbers."),
+    /// Compares element-wise into a mask tensor if either operand is a tensor with at least one axis,
+    /// `None` if the operands compare to a single boolean instead
+    pub fn tensor_compare(
+        &self,
+        other: &Self,
+        f: fn(f64, f64) -> bool,
+    ) -> Option<TensorResult<ValueType>> {
+        let has_axes = |value: &ValueType| match value {
+            ValueType::Tensor(tensor) => !tensor.shape().is_empty(),
+            ValueType::Dual(dual) => !dual.shape().is_empty(),
+            _ => false,
+        };
+        if !has_axes(self) && !has_axes(other) {
+            return None;
         }
+
+        let (a, b) = self.tensor_operands(other)?;
+        Some(a.compare(&b, f).map(ValueType::Tensor))
     }
 }
 
-impl std::ops::Div for ValueType {
-    type Output = Self;
+// arithmetic on values, operands of the wrong type raise an error rather than panic
+impl ValueType {
+    pub fn checked_add(self, other: Self) -> Result<Self, RuntimeError> {
+        self.arithmetic(
+            other,
+            "+",
+            |a, b| a + b,
+            Tensor::checked_add,
+            i64::checked_add,
+            |a, b| a + b,
+        )
+    }
 
-    fn div(self, other: Self) -> Self {
-        match (self, other) {
-            // (ValueType::Tensor(a), ValueType::Tensor(b)) => ValueType::Tensor(a / b),
-            (ValueType::Integer(a), ValueType::Integer(b)) => ValueType::Integer(a / b),
-            (ValueType::Float(a), ValueType::Float(b)) => ValueType::Float(a / b),
-            _ => panic!("Operands must be numbers."),
+    pub fn checked_sub(self, other: Self) -> Result<Self, RuntimeError> {
+        self.arithmetic(
+            other,
+            "-",
+            |a, b| a - b,
+            Tensor::checked_sub,
+            i64::checked_sub,
+            |a, b| a - b,
+        )
+    }
+
+    pub fn checked_mul(self, other: Self) -> Result<Self, RuntimeError> {
+        self.arithmetic(
+            other,
+            "*",
+            |a, b| a * b,
+            Tensor::checked_mul,
+            i64::checked_mul,
+            |a, b| a * b,
+        )
+    }
+
+    pub fn checked_div(self, other: Self) -> Result<Self, RuntimeError> {
+        if let (ValueType::Integer(_), ValueType::Integer(0)) = (&self, &other) {
+            return Err(RuntimeError::DivisionByZero);
         }
+        self.arithmetic(
+            other,
+            "/",
+            |a, b| a / b,
+            Tensor::checked_div,
+            i64::checked_div,
+            |a, b| a / b,
+        )
     }
-}
 
-impl std::ops::Neg for ValueType {
-    type Output = Self;
+    pub fn pow(self, other: Self) -> Result<Self, RuntimeError> {
+        // a negative integer exponent gives a fraction
+        if let (ValueType::Integer(a), ValueType::Integer(b)) = (&self, &other) {
+            if *b < 0 {
+                return Ok(ValueType::Float((*a as f64).powf(*b as f64)));
+            }
+        }
+        self.arithmetic(
+            other,
+            "**",
+            |a, b| a.pow(&b),
+            Tensor::checked_pow,
+            |a, b| a.checked_pow(u32::try_from(b).ok()?),
+            f64::powf,
+        )
+    }
 
-    fn neg(self) -> Self {
+    pub fn checked_neg(self) -> Result<Self, RuntimeError> {
         match self {
-            // ValueType::Tensor(n) => ValueType::Tensor(-n),
-            ValueType::Integer(n) => ValueType::Integer(-n),
-            ValueType::Float(n) => ValueType::Float(-n),
-            _ => panic!("Operand must be a number."),
+            ValueType::Tensor(n) => Ok(ValueType::Tensor(-n)),
+            ValueType::Dual(n) => Ok(ValueType::Dual(-n)),
+            ValueType::Integer(n) => n
+                .checked_neg()
+                .map(ValueType::Integer)
+                .ok_or(RuntimeError::IntegerOverflow("-")),
+            ValueType::Float(n) => Ok(ValueType::Float(-n)),
+            value => Err(RuntimeError::InvalidOperand {
+                op: "-",
+                expected: "a number",
+                operand: value.type_name(),
+            }),
         }
     }
-}
-
-impl std::ops::Not for ValueType {
-    type Output = Self;
 
-    fn not(self) -> Self {
+    pub fn checked_not(self) -> Result<Self, RuntimeError> {
         match self {
-            ValueType::Boolean(b) => ValueType::Boolean(!b),
-            ValueType::Nil => ValueType::Boolean(true), // NOTE: nil is falsey, should likely be removed perhaps ?
-            _ => panic!("Operand must be a boolean."),
+            ValueType::Boolean(b) => Ok(ValueType::Boolean(!b)),
+            // inverts comparison masks, which is how `!=`, `<=` and `>=` are compiled
+            ValueType::Tensor(t) => {
+                let mask = t.compare(&Tensor::from(0.0), |x, _| x == 0.0)?;
+                Ok(ValueType::Tensor(mask))
+            }
+            ValueType::Nil => Ok(ValueType::Boolean(true)), // NOTE: nil is falsey, should likely be removed perhaps ?
+            value => Err(RuntimeError::InvalidOperand {
+                op: "!",
+                expected: "a boolean",
+                operand: value.type_name(),
+            }),
+        }
+    }
+
+    /// Applies a binary operator, lifting both operands to duals or tensors if either of them is
+    /// one, an integer mixed with a float gives a float
+    fn arithmetic(
+        self,
+        other: Self,
+        op: &'static str,
+        dual: fn(Dual, Dual) -> Dual,
+        tensor: fn(&Tensor, &Tensor) -> TensorResult<Tensor>,
+        integer: fn(i64, i64) -> Option<i64>,
+        float: fn(f64, f64) -> f64,
+    ) -> Result<Self, RuntimeError> {
+        if let Some((a, b)) = self.dual_operands(&other) {
+            tensor::broadcast_shape(&a.shape(), &b.shape())?;
+            return Ok(ValueType::Dual(dual(a, b)));
+        }
+        if let Some((a, b)) = self.tensor_operands(&other) {
+            return Ok(ValueType::Tensor(tensor(&a, &b)?));
+        }
+
+        match (self, other) {
+            (ValueType::Integer(a), ValueType::Integer(b)) => integer(a, b)
+                .map(ValueType::Integer)
+                .ok_or(RuntimeError::IntegerOverflow(op)),
+            (ValueType::Float(a), ValueType::Float(b)) => Ok(ValueType::Float(float(a, b))),
+            (ValueType::Float(a), ValueType::Integer(b)) => {
+                Ok(ValueType::Float(float(a, b as f64)))
+            }
+            (ValueType::Integer(a), ValueType::Float(b)) => {
+                Ok(ValueType::Float(float(a as f64, b)))
+            }
+            (a, b) => Err(RuntimeError::InvalidOperands {
+                op,
+                expected: "numbers",
+                left: a.type_name(),
+                right: b.type_name(),
+            }),
         }
     }
 }
@@ -132,11 +336,24 @@ impl std::ops::Not for ValueType {
 impl std::cmp::PartialEq for ValueType {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            // (ValueType::Tensor(a), ValueType::Tensor(b)) => a == b,
+            // duals compare like their values, so branching on them behaves like on tensors
+            (ValueType::Dual(a), b) => ValueType::Tensor(a.value.clone()) == *b,
+            (a, ValueType::Dual(b)) => *a == ValueType::Tensor(b.value.clone()),
+            (ValueType::Tensor(a), ValueType::Tensor(b)) => {
+                a.shape() == b.shape() && a.data() == b.data()
+            }
+            (ValueType::Tensor(a), ValueType::Integer(b))
+            | (ValueType::Integer(b), ValueType::Tensor(a)) => a.item() == Some(*b as f64),
             (ValueType::Integer(a), ValueType::Integer(b)) => a == b,
             (ValueType::Float(a), ValueType::Float(b)) => a == b,
             (ValueType::Boolean(a), ValueType::Boolean(b)) => a == b,
             (ValueType::Nil, ValueType::Nil) => true,
+            (ValueType::List(a), ValueType::List(b)) => a == b,
+            (ValueType::Function(a), ValueType::Function(b)) => Rc::ptr_eq(a, b),
+            (ValueType::NativeFunction(a), ValueType::NativeFunction(b)) => a.name == b.name,
+            (ValueType::Optimizer(a), ValueType::Optimizer(b)) => Rc::ptr_eq(a, b),
+            (ValueType::Module(a), ValueType::Module(b)) => Rc::ptr_eq(a, b),
+            (ValueType::Transform(a), ValueType::Transform(b)) => Rc::ptr_eq(a, b),
             _ => false,
         }
     }
@@ -145,8 +362,11 @@ impl std::cmp::PartialEq for ValueType {
 impl std::cmp::PartialOrd for ValueType {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         match (self, other) {
-            // (ValueType::Tensor(a), ValueType::Tensor(b)) => a.partial_cmp(b),
-            // _ => None,
+            (ValueType::Dual(a), b) => ValueType::Tensor(a.value.clone()).partial_cmp(b),
+            (a, ValueType::Dual(b)) => a.partial_cmp(&ValueType::Tensor(b.value.clone())),
+            (ValueType::Tensor(a), ValueType::Tensor(b)) => a.item()?.partial_cmp(&b.item()?),
+            (ValueType::Tensor(a), ValueType::Integer(b)) => a.item()?.partial_cmp(&(*b as f64)),
+            (ValueType::Integer(a), ValueType::Tensor(b)) => (*a as f64).partial_cmp(&b.item()?),
             (ValueType::Integer(a), ValueType::Integer(b)) => a.partial_cmp(b),
             (ValueType::Float(a), ValueType::Float(b)) => a.partial_cmp(b),
             _ => None,
@@ -154,15 +374,14 @@ impl std::cmp::PartialOrd for ValueType {
     }
 }
 
-// impl powf value
-impl ValueType {
-    pub fn pow(&self, other: &Self) -> Self {
-        match (self, other) {
-            // (ValueType::Tensor(a), ValueType::Tensor(b)) => ValueType::Tensor(a.pow(b)),
-            (ValueType::Integer(a), ValueType::Integer(b)) => ValueType::Integer(a.pow(*b as u32)),
-            (ValueType::Float(a), ValueType::Float(b)) => ValueType::Float(a.powf(*b)),
-            (ValueType::Float(a), ValueType::Integer(b)) => ValueType::Float(a.powf(*b as f64)),
-            (a, b) => panic!("{}", format!("Operands must be numbers. Got: {:?} and {:?}", a, b)),
-        }
+impl From<Tensor> for ValueType {
+    fn from(tensor: Tensor) -> Self {
+        ValueType::Tensor(tensor)
+    }
+}
+
+impl From<Dual> for ValueType {
+    fn from(dual: Dual) -> Self {
+        ValueType::Dual(dual)
     }
 }
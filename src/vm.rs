@@ -1,26 +1,33 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, rc::Rc};
 use thiserror::Error;
 
 use crate::{
     chunk::{self, Chunk, VectorType},
+    diagnostics::{self, Diagnostic},
     interner::{Interner, StringObjIdx},
-    tensor::Tensor,
-    value::ValueType,
+    methods, natives,
+    tensor::{self, NoGradGuard, Tensor, TensorError},
+    value::{Function, ValueType},
 };
 
 const STACK_MAX: usize = 256;
+const FRAMES_MAX: usize = 64;
 
+/// An ongoing function call, the script itself runs in the bottom-most frame
 struct CallFrame {
+    function: Rc<Function>,
+
+    // instruction pointer into the function's chunk
     ip: usize,
-    stack_top: usize,
-}
 
-pub struct VM {
-    pub chunk: Chunk,
+    // start of the frame's window on the stack, slot 0 holds the callee
+    slots: usize,
 
-    // instruction pointer
-    ip: usize,
+    // `no_grad` blocks already entered when the frame was called, returning leaves the ones it entered
+    no_grad_depth: usize,
+}
 
+pub struct VM {
     // TODO - implement JIT instead of stack perhaps ?
     // NOTE - using a fixed size array for the stack instead of a Vec
     stack: [ValueType; STACK_MAX],
@@ -31,7 +38,15 @@ pub struct VM {
     globals: HashMap<StringObjIdx, ValueType>,
 
     call_frames: Vec<CallFrame>,
-    frame_index: usize,
+
+    // one guard per `no_grad` block being run, graph recording resumes once they are all dropped
+    no_grad: Vec<NoGradGuard>,
+
+    // values printed so far, returned once the script finishes
+    print_outputs: Vec<String>,
+
+    // tensors whose graph was drawn with `draw_dot`, kept for the CLI and the playground
+    graphs: Vec<Tensor>,
 }
 
 #[derive(Debug, PartialEq, Error)]
@@ -39,40 +54,216 @@ pub enum Result {
     #[error("Ok")]
     Ok(Vec<String>),
 
-    #[error("Compile error : {0}")]
-    CompileErr(String),
+    /// every syntax error of a script, or the error the compiler stopped at
+    #[error("Compile error : {}", diagnostics::summarize(.0))]
+    CompileErr(Vec<Diagnostic>),
 
     #[error("Runtime error : {0}")]
-    RuntimeErr(String),
+    RuntimeErr(Diagnostic),
+}
+
+/// An error raised while running a program
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum RuntimeError {
+    #[error("Undefined variable '{0}'")]
+    UndefinedVariable(String),
+
+    #[error("Operands of '{op}' must be {expected}. Got: '{left}' and '{right}'")]
+    InvalidOperands {
+        op: &'static str,
+        expected: &'static str,
+        left: &'static str,
+        right: &'static str,
+    },
+
+    #[error("Operand of '{op}' must be {expected}. Got: '{operand}'")]
+    InvalidOperand {
+        op: &'static str,
+        expected: &'static str,
+        operand: &'static str,
+    },
+
+    #[error("Integer overflow in '{0}'")]
+    IntegerOverflow(&'static str),
+
+    #[error("Division by zero")]
+    DivisionByZero,
+
+    #[error("Expected {expected} arguments but got {got} when calling '{name}'")]
+    ArityMismatch {
+        expected: String,
+        got: usize,
+        name: String,
+    },
+
+    #[error("Can only call functions, got '{0}'")]
+    NotCallable(String),
+
+    #[error("Method '{name}' of type '{type_name}' expects {expected} arguments but got {got}")]
+    MethodArityMismatch {
+        name: String,
+        type_name: &'static str,
+        expected: String,
+        got: usize,
+    },
+
+    #[error("Undefined method '{name}' for type '{type_name}'")]
+    UndefinedMethod {
+        name: String,
+        type_name: &'static str,
+    },
+
+    /// an argument of the wrong type for a native function or method
+    #[error("{0}")]
+    TypeMismatch(String),
+
+    /// tensors whose shapes don't broadcast or don't fit the operation
+    #[error("{0}")]
+    ShapeMismatch(String),
+
+    /// an argument outside of the domain of a function, e.g. `clamp` bounds with min > max
+    #[error("{0}")]
+    InvalidArgument(String),
+
+    #[error("Stack overflow")]
+    StackOverflow,
+
+    #[error("Stack underflow")]
+    StackUnderflow,
+
+    /// bytecode the compiler doesn't emit, e.g. from a corrupted chunk
+    #[error("Invalid bytecode: {0}")]
+    InvalidBytecode(String),
+
+    /// any other error of a native function, a method or a tensor operation, e.g. a failed `gradcheck`
+    #[error("{0}")]
+    Other(String),
 }
 
+impl From<TensorError> for RuntimeError {
+    fn from(error: TensorError) -> Self {
+        match error {
+            TensorError::Shape(message) => RuntimeError::ShapeMismatch(message),
+            TensorError::InvalidArgument(message) => RuntimeError::InvalidArgument(message),
+            TensorError::Unsupported(message) => RuntimeError::Other(message),
+        }
+    }
+}
+
+pub type RuntimeResult<T> = std::result::Result<T, RuntimeError>;
+
 impl VM {
     pub fn init(chunk: Chunk, interner: Interner) -> VM {
         // TODO: serialize and cache chunk and interner and save it as a file hash
-        VM {
-            chunk,
-            ip: 0,
+        let mut script = Function::new("script".to_string(), 0);
+        script.chunk = chunk;
+
+        let mut vm = VM {
             stack: core::array::from_fn(|_| ValueType::Nil),
             stack_top: 0,
             interner,
             globals: HashMap::new(),
-            call_frames: Vec::new(),
-            frame_index: 0,
-        }
+            call_frames: vec![CallFrame {
+                function: Rc::new(script),
+                ip: 0,
+                slots: 0,
+                no_grad_depth: 0,
+            }],
+            no_grad: Vec::new(),
+            print_outputs: Vec::new(),
+            graphs: Vec::new(),
+        };
+        natives::define_natives(&mut vm);
+
+        vm
+    }
+
+    pub fn define_global(&mut self, name: &str, value: ValueType) {
+        let idx = self.interner.intern_string(name.to_string());
+        self.globals.insert(idx, value);
+    }
+
+    pub fn record_graph(&mut self, tensor: Tensor) {
+        self.graphs.push(tensor);
+    }
+
+    /// Tensors passed to `draw_dot`, in the order they were drawn
+    pub fn graphs(&self) -> &[Tensor] {
+        &self.graphs
     }
 
     pub fn run(&mut self) -> Result {
-        let mut print_outputs: Vec<String> = Vec::new();
+        match self.execute(0) {
+            std::result::Result::Ok(_) => Result::Ok(std::mem::take(&mut self.print_outputs)),
+            Err(e) => Result::RuntimeErr(self.diagnostic(e)),
+        }
+    }
+
+    /// Runs another script on this VM, e.g. the next input of a REPL, keeping the globals defined so far.
+    /// `chunk` must be compiled against `self.interner`. Returns the printed values and the value the script
+    /// returned
+    pub fn run_chunk(
+        &mut self,
+        chunk: Chunk,
+    ) -> std::result::Result<(Vec<String>, ValueType), Diagnostic> {
+        let mut script = Function::new("script".to_string(), 0);
+        script.chunk = chunk;
+
+        // a previous script may have stopped halfway through on an error
+        self.stack_top = 0;
+        self.no_grad.clear();
+        self.call_frames = vec![CallFrame {
+            function: Rc::new(script),
+            ip: 0,
+            slots: 0,
+            no_grad_depth: 0,
+        }];
+
+        let value = self.execute(0).map_err(|e| self.diagnostic(e));
+        let outputs = std::mem::take(&mut self.print_outputs);
+        value.map(|value| (outputs, value))
+    }
+
+    /// Points an error at the instruction that raised it. Frames are left in place on errors, so the
+    /// innermost one is still at the failing instruction
+    fn diagnostic(&self, error: RuntimeError) -> Diagnostic {
+        let frame = self.frame();
+        let chunk = &frame.function.chunk;
+        let diagnostic = Diagnostic::new(error, Rc::clone(&chunk.source));
+        match chunk.spans.get(frame.ip.saturating_sub(1)) {
+            Some(span) => diagnostic.with_span(span.clone()),
+            None => diagnostic,
+        }
+    }
+
+    /// Calls `callee` with `args` from native code, running it to completion and returning its result
+    pub fn call(&mut self, callee: ValueType, args: &[ValueType]) -> RuntimeResult<ValueType> {
+        let depth = self.call_frames.len();
+        std::iter::once(&callee)
+            .chain(args)
+            .try_for_each(|value| self.push(value.clone()))
+            .and_then(|_| self.call_value(callee, args.len()))
+            .and_then(|_| {
+                // natives and modules have already left their result on the stack
+                if self.call_frames.len() == depth {
+                    return self.pop();
+                }
+                self.execute(depth)
+            })
+    }
+
+    /// Runs instructions until the frame count drops back to `depth`, returning the value of the last return
+    fn execute(&mut self, depth: usize) -> RuntimeResult<ValueType> {
 
         macro_rules! push {
             ($value:expr) => {
-                self.push($value)
+                self.push($value)?
             };
         }
 
         macro_rules! pop {
             () => {
-                self.pop()
+                self.pop()?
             };
         }
 
@@ -82,128 +273,190 @@ impl VM {
             };
         }
 
+        macro_rules! read_operand {
+            () => {
+                match self.read_byte()? {
+                    chunk::VectorType::Constant(operand) => operand,
+                    byte => {
+                        let message = format!("invalid operand '{}'", byte);
+                        return Err(RuntimeError::InvalidBytecode(message));
+                    }
+                }
+            };
+        }
+
         macro_rules! get_constant {
             ($index:expr) => {
                 match $index {
-                    chunk::VectorType::Constant(idx) => self.read_constant(idx as usize),
+                    chunk::VectorType::Constant(idx) => self.read_constant(idx)?,
                     _ => {
-                        return Result::RuntimeErr(format!("Invalid constant '{}'", $index));
+                        let message = format!("invalid constant '{}'", $index);
+                        return Err(RuntimeError::InvalidBytecode(message));
                     }
                 }
             };
         }
 
+        macro_rules! compare {
+            ($op:tt) => {{
+                let b = pop!();
+                let a = pop!();
+                match a.tensor_compare(&b, |x, y| x $op y) {
+                    Some(mask) => push!(mask?),
+                    None => push!(ValueType::Boolean(a $op b)),
+                }
+            }};
+        }
+
         loop {
-            let instruction = self.read_byte();
+            let instruction = self.read_byte()?;
 
             match instruction {
                 opcode!(OpReturn) => {
-                    return Result::Ok(print_outputs);
+                    let result = pop!();
+                    let frame = self.call_frames.pop().ok_or(RuntimeError::StackUnderflow)?;
+
+                    // discard the callee and its arguments/locals, leaving the result
+                    self.stack_top = frame.slots;
+                    self.no_grad.truncate(frame.no_grad_depth);
+
+                    if self.call_frames.len() == depth {
+                        return std::result::Result::Ok(result);
+                    }
+                    push!(result);
                 }
                 opcode!(OpAdd) => {
-                    if let ValueType::String(_) = self.peek(0) {
-                        self.concatenate();
-                    } else {
-                        let b = pop!();
-                        let a = pop!();
-                        push!(a + b);
+                    let b = pop!();
+                    let a = pop!();
+                    match (a, b) {
+                        (ValueType::String(a), ValueType::String(b)) => self.concatenate(a, b)?,
+                        (a, b) => push!(a.checked_add(b)?),
                     }
                 }
                 opcode!(OpSubtract) => {
                     let b = pop!();
                     let a = pop!();
-                    push!(a - b);
+                    push!(a.checked_sub(b)?);
                 }
                 opcode!(OpMultiply) => {
                     let b = pop!();
                     let a = pop!();
-                    push!(a * b);
+                    push!(a.checked_mul(b)?);
                 }
                 opcode!(OpDivide) => {
                     let b = pop!();
                     let a = pop!();
-                    push!(a / b);
+                    push!(a.checked_div(b)?);
                 }
                 opcode!(OpPower) => {
                     let b = pop!();
                     let a = pop!();
-                    push!(a.pow(&b));
+                    push!(a.pow(b)?);
+                }
+                opcode!(OpMatmul) => {
+                    let b = pop!();
+                    let a = pop!();
+                    let product = match (&a, &b) {
+                        (ValueType::Tensor(x), ValueType::Tensor(y)) => {
+                            Some(ValueType::Tensor(x.matmul(y)?))
+                        }
+                        (ValueType::Dual(_), _) | (_, ValueType::Dual(_)) => {
+                            match (a.to_dual(), b.to_dual()) {
+                                (Some(x), Some(y)) => Some(ValueType::Dual(x.matmul(&y)?)),
+                                _ => None,
+                            }
+                        }
+                        _ => None,
+                    };
+                    match product {
+                        Some(product) => push!(product),
+                        None => {
+                            return Err(RuntimeError::InvalidOperands {
+                                op: "@",
+                                expected: "tensors",
+                                left: a.type_name(),
+                                right: b.type_name(),
+                            });
+                        }
+                    }
+                }
+                opcode!(OpBuildList) => {
+                    let len = read_operand!();
+                    let elements = self.peek_n(len)?;
+                    self.stack_top -= len;
+                    push!(ValueType::List(elements));
+                }
+                opcode!(OpNoGrad) => self.no_grad.push(tensor::no_grad()),
+                opcode!(OpEndNoGrad) => {
+                    self.no_grad.pop();
                 }
                 opcode!(OpNegate) => {
                     let value = pop!();
-                    push!(-value);
+                    push!(value.checked_neg()?);
                 }
                 opcode!(OpNil) => push!(ValueType::Nil),
                 opcode!(OpTrue) => push!(ValueType::Boolean(true)),
                 opcode!(OpFalse) => push!(ValueType::Boolean(false)),
                 opcode!(OpNot) => {
                     let value = pop!();
-                    push!(!value)
-                }
-                opcode!(OpEqualEqual) => {
-                    let b = pop!();
-                    let a = pop!();
-                    push!(ValueType::Boolean(a == b));
+                    push!(value.checked_not()?)
                 }
+                opcode!(OpEqualEqual) => compare!(==),
                 // TODO: Not working for now
-                opcode!(OpGreater) => {
-                    let b = pop!();
-                    let a = pop!();
-                    push!(ValueType::Boolean(a > b));
-                }
-                opcode!(OpLess) => {
-                    let b = pop!();
-                    let a = pop!();
-                    push!(ValueType::Boolean(a < b));
-                }
+                opcode!(OpGreater) => compare!(>),
+                opcode!(OpLess) => compare!(<),
                 opcode!(OpPrint) => {
                     let value = pop!();
 
-                    print_outputs.push(value.display(&self.interner));
+                    self.print_outputs.push(value.display(&self.interner));
                     println!("{}", value.display(&self.interner));
                 }
                 opcode!(OpPop) => {
                     pop!();
                 }
                 opcode!(OpConstant) => {
-                    let constant = get_constant!(self.read_byte());
-                    push!(constant);
+                    let constant = get_constant!(self.read_byte()?);
+
+                    // float literals become fresh leaf tensors, so every evaluation gets its own graph node
+                    match constant {
+                        ValueType::Float(n) => push!(ValueType::Tensor(Tensor::from(n))),
+                        constant => push!(constant),
+                    }
                 }
                 opcode!(OpJumpIfFalse) => {
-                    self.read_byte();
-                    let offset = self.read_byte();
-                    let value = self.peek(0);
+                    self.read_byte()?;
+                    let offset = self.read_byte()?;
+                    let value = self.peek(0)?;
 
                     if let ValueType::Boolean(false) = value {
                         if let VectorType::Constant(idx) = offset {
-                            if let ValueType::JumpOffset(offset) = self.read_constant(idx as usize)
-                            {
-                                self.ip = offset;
+                            if let ValueType::JumpOffset(offset) = self.read_constant(idx)? {
+                                self.frame_mut().ip = offset;
                             }
                         }
                     }
                 }
                 opcode!(OpJump) => {
-                    self.read_byte();
-                    let offset = self.read_byte();
+                    self.read_byte()?;
+                    let offset = self.read_byte()?;
                     if let VectorType::Constant(idx) = offset {
-                        if let ValueType::JumpOffset(offset) = self.read_constant(idx as usize) {
-                            self.ip = offset
+                        if let ValueType::JumpOffset(offset) = self.read_constant(idx)? {
+                            self.frame_mut().ip = offset
                         }
                     }
                 }
                 opcode!(OpLoop) => {
-                    self.read_byte();
-                    let offset = self.read_byte();
+                    self.read_byte()?;
+                    let offset = self.read_byte()?;
                     if let VectorType::Constant(idx) = offset {
-                        if let ValueType::JumpOffset(offset) = self.read_constant(idx as usize) {
-                            self.ip = offset
+                        if let ValueType::JumpOffset(offset) = self.read_constant(idx)? {
+                            self.frame_mut().ip = offset
                         }
                     }
                 }
                 opcode!(OpDefineGlobal) => {
-                    let constant = get_constant!(self.read_byte());
-                    let value = self.peek(0);
+                    let constant = get_constant!(self.read_byte()?);
+                    let value = self.peek(0)?;
 
                     if let ValueType::Identifier(idx) = constant {
                         self.globals.insert(idx, value);
@@ -212,150 +465,241 @@ impl VM {
                     pop!();
                 }
                 opcode!(OpGetGlobal) => {
-                    let constant = get_constant!(self.read_byte());
+                    let constant = get_constant!(self.read_byte()?);
                     match constant {
                         ValueType::Identifier(idx) => {
                             let value = self.globals.get(&idx);
                             if let Some(value) = value {
                                 push!(value.clone());
                             } else {
-                                return Result::RuntimeErr(format!(
-                                    "Undefined variable '{}'",
-                                    self.interner.lookup(idx)
-                                ));
+                                let name = self.interner.lookup(idx).to_string();
+                                return Err(RuntimeError::UndefinedVariable(name));
                             }
                         }
                         _ => {
-                            return Result::RuntimeErr(format!(
-                                "Invalid global variable '{}'",
+                            return Err(RuntimeError::InvalidBytecode(format!(
+                                "invalid global variable '{}'",
                                 constant.display(&self.interner)
-                            ));
+                            )));
                         }
                     }
                 }
                 opcode!(OpSetGlobal) => {
-                    let index = self.read_byte();
+                    let index = self.read_byte()?;
                     let constant = get_constant!(index);
 
                     match constant {
                         ValueType::Identifier(idx) => {
-                            let value = self.peek(0);
+                            let value = self.peek(0)?;
                             self.globals.insert(idx, value);
                             // TODO - only set the value if it exists
                         }
                         _ => {
-                            return Result::RuntimeErr(format!(
-                                "Invalid global variable '{}'",
+                            return Err(RuntimeError::InvalidBytecode(format!(
+                                "invalid global variable '{}'",
                                 constant.display(&self.interner)
-                            ));
+                            )));
                         }
                     }
                 }
                 opcode!(OpGetLocal) => {
-                    let slot = self.read_byte();
+                    let slot = self.read_byte()?;
 
                     match slot {
                         VectorType::Constant(idx) => {
-                            let value = self.stack[idx as usize].clone();
+                            let value = self.local(idx)?.clone();
                             push!(value);
                         }
                         _ => {
-                            return Result::RuntimeErr(format!("Invalid slot '{}'", slot));
+                            let message = format!("invalid slot '{}'", slot);
+                            return Err(RuntimeError::InvalidBytecode(message));
                         }
                     }
                 }
                 opcode!(OpSetLocal) => {
-                    let slot = self.read_byte();
+                    let slot = self.read_byte()?;
 
                     match slot {
                         VectorType::Constant(idx) => {
-                            let value = self.peek(0);
-                            self.stack[idx as usize] = value;
+                            let value = self.peek(0)?;
+                            *self.local(idx)? = value;
                         }
                         _ => {
-                            return Result::RuntimeErr(format!("Invalid slot '{}'", slot));
+                            let message = format!("invalid slot '{}'", slot);
+                            return Err(RuntimeError::InvalidBytecode(message));
                         }
                     }
                 }
-                // opcode!(OpCall) => {
-                //     let callee = self.read_byte();
-                //     let caller = pop!();
-
-                //     let constant = get_constant!(callee);
-                //     let str_idx = match constant {
-                //         ValueType::Identifier(idx) => idx,
-                //         _ => {
-                //             return Result::RuntimeErr("Invalid function".to_string());
-                //         }
-                //     };
-                //     let calle_str = self.interner.lookup(str_idx);
-
-                //     let tensor = match caller {
-                //         ValueType::Tensor(tensor) => tensor,
-                //         _ => {
-                //             return Result::RuntimeErr("Invalid function".to_string());
-                //         }
-                //     };
-
-                //     match calle_str {
-                //         "relu" => push!(ValueType::Tensor(Tensor::from(tensor.relu()))),
-                //         "backward" => tensor.backward(),
-                //         "grad" => push!(ValueType::Tensor(Tensor::from(tensor.gradient()))),
-                //         _ => {
-                //             return Result::RuntimeErr("Undefined function. Currently only supports relu, backward and grad".to_string());
-                //         }
-                //     }
-                // }
-                _ => {
-                    return {
-                        if let chunk::VectorType::Constant(idx) = instruction {
-                            let value = self.read_constant(idx as usize);
-                            println!("Constant: {:?}", value);
+                opcode!(OpCall) => {
+                    let arg_count = read_operand!();
+                    self.call_value(self.peek(arg_count)?, arg_count)?;
+                }
+                opcode!(OpCallMethod) => {
+                    let method = match get_constant!(self.read_byte()?) {
+                        ValueType::Identifier(idx) => self.interner.lookup(idx).to_string(),
+                        constant => {
+                            return Err(RuntimeError::InvalidBytecode(format!(
+                                "invalid method '{}'",
+                                constant.display(&self.interner)
+                            )));
                         }
-
-                        Result::RuntimeErr(format!("Invalid opcode '{}'", instruction))
                     };
+                    let arg_count = read_operand!();
+
+                    let receiver = self.peek(arg_count)?;
+                    let args = self.peek_n(arg_count)?;
+
+                    let result =
+                        methods::call_method(&receiver, &method, &args, &mut self.interner)?;
+
+                    self.stack_top -= arg_count + 1;
+                    push!(result);
+                }
+                _ => {
+                    let message = format!("invalid opcode '{}'", instruction);
+                    return Err(RuntimeError::InvalidBytecode(message));
                 }
             }
         }
     }
 
-    fn read_byte(&mut self) -> VectorType {
-        let byte = self.chunk.code[self.ip].clone();
-        self.ip += 1;
-        return byte;
+    fn call_value(&mut self, callee: ValueType, arg_count: usize) -> RuntimeResult<()> {
+        match callee {
+            ValueType::Function(function) => {
+                if arg_count != function.arity as usize {
+                    return Err(RuntimeError::ArityMismatch {
+                        expected: function.arity.to_string(),
+                        got: arg_count,
+                        name: function.name.clone(),
+                    });
+                }
+                if self.call_frames.len() == FRAMES_MAX {
+                    return Err(RuntimeError::StackOverflow);
+                }
+
+                self.call_frames.push(CallFrame {
+                    function,
+                    ip: 0,
+                    slots: self.stack_top - arg_count - 1,
+                    no_grad_depth: self.no_grad.len(),
+                });
+                Ok(())
+            }
+            ValueType::NativeFunction(native) => {
+                if !native.arity.contains(&arg_count) {
+                    return Err(RuntimeError::ArityMismatch {
+                        expected: format_arity(&native.arity),
+                        got: arg_count,
+                        name: native.name.to_string(),
+                    });
+                }
+
+                let args = self.peek_n(arg_count)?;
+                let result = (native.function)(self, &args)?;
+                self.stack_top -= arg_count + 1;
+                self.push(result)
+            }
+            ValueType::Transform(transform) => {
+                let args = self.peek_n(arg_count)?;
+                let result = transform.call(self, &args)?;
+                self.stack_top -= arg_count + 1;
+                self.push(result)
+            }
+            // calling a module runs its forward pass
+            ValueType::Module(module) => {
+                if arg_count != 1 {
+                    return Err(RuntimeError::ArityMismatch {
+                        expected: "1".to_string(),
+                        got: arg_count,
+                        name: module.name().to_string(),
+                    });
+                }
+
+                let input = match self.pop()? {
+                    ValueType::Tensor(input) => input,
+                    value => {
+                        return Err(RuntimeError::TypeMismatch(format!(
+                            "Expected a tensor input for '{}', got '{}'",
+                            module.name(),
+                            value.type_name()
+                        )));
+                    }
+                };
+                self.pop()?;
+                self.push(ValueType::Tensor(module.forward(&input)?))
+            }
+            _ => Err(RuntimeError::NotCallable(callee.display(&self.interner))),
+        }
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.call_frames.last().expect("no active call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.call_frames.last_mut().expect("no active call frame")
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.frame().function.chunk
     }
 
-    fn read_constant(&mut self, index: usize) -> ValueType {
-        self.chunk.constants[index].clone()
+    fn read_byte(&mut self) -> RuntimeResult<VectorType> {
+        let frame = self.frame_mut();
+        let byte = frame.function.chunk.code.get(frame.ip).copied();
+        frame.ip += 1;
+        let error = || RuntimeError::InvalidBytecode("ran past the end of the chunk".to_string());
+        byte.ok_or_else(error)
     }
 
-    fn push(&mut self, value: ValueType) {
-        self.stack[self.stack_top] = value;
+    fn read_constant(&self, index: usize) -> RuntimeResult<ValueType> {
+        let constant = self.chunk().constants.get(index).cloned();
+        let error = || RuntimeError::InvalidBytecode(format!("invalid constant index {}", index));
+        constant.ok_or_else(error)
+    }
+
+    fn push(&mut self, value: ValueType) -> RuntimeResult<()> {
+        let slot = self.stack.get_mut(self.stack_top).ok_or(RuntimeError::StackOverflow)?;
+        *slot = value;
         self.stack_top += 1;
+        Ok(())
     }
 
-    fn pop(&mut self) -> ValueType {
-        self.stack_top -= 1;
-        self.stack[self.stack_top].clone()
+    fn pop(&mut self) -> RuntimeResult<ValueType> {
+        self.stack_top = self.stack_top.checked_sub(1).ok_or(RuntimeError::StackUnderflow)?;
+        Ok(self.stack[self.stack_top].clone())
     }
 
-    fn peek(&self, distance: usize) -> ValueType {
-        self.stack[self.stack_top - 1 - distance].clone()
+    fn peek(&self, distance: usize) -> RuntimeResult<ValueType> {
+        let index = self.stack_top.checked_sub(distance + 1);
+        index.map(|i| self.stack[i].clone()).ok_or(RuntimeError::StackUnderflow)
     }
 
-    fn concatenate(&mut self) {
-        let b = self.pop();
-        let a = self.pop();
-
-        if let ValueType::String(a) = a {
-            if let ValueType::String(b) = b {
-                let b_str = self.interner.lookup(b);
-                let a_str = self.interner.lookup(a);
-                let res = a_str.to_owned() + b_str;
-                let res_idx = self.interner.intern_string(res);
-                self.push(ValueType::String(res_idx));
-            }
-        }
+    /// The top `count` values of the stack, bottom-most first, e.g. the arguments of a call
+    fn peek_n(&self, count: usize) -> RuntimeResult<Vec<ValueType>> {
+        let start = self.stack_top.checked_sub(count).ok_or(RuntimeError::StackUnderflow)?;
+        Ok(self.stack[start..self.stack_top].to_vec())
+    }
+
+    /// Slot `index` of the current frame's locals
+    fn local(&mut self, index: usize) -> RuntimeResult<&mut ValueType> {
+        let slot = self.frame().slots + index;
+        let local = self.stack[..self.stack_top].get_mut(slot);
+        local.ok_or_else(|| RuntimeError::InvalidBytecode(format!("invalid slot {}", index)))
+    }
+
+    fn concatenate(&mut self, a: StringObjIdx, b: StringObjIdx) -> RuntimeResult<()> {
+        let res = self.interner.lookup(a).to_owned() + self.interner.lookup(b);
+        let res_idx = self.interner.intern_string(res);
+        self.push(ValueType::String(res_idx))
+    }
+}
+
+/// Describes an accepted argument count, e.g. `2` or `1 to 3`
+pub fn format_arity(arity: &std::ops::RangeInclusive<usize>) -> String {
+    if arity.start() == arity.end() {
+        arity.start().to_string()
+    } else {
+        format!("{} to {}", arity.start(), arity.end())
     }
 }
@@ -2,18 +2,22 @@ use grad::{
     ast::{ast_to_ascii, ASTNode, Parser},
     chunk::Chunk,
     compiler, debug,
+    diagnostics::{from_parse_errors, render_all},
     interner::Interner,
     run_source,
     scanner::Lexer,
+    source::Source,
+    tensor::GraphNode,
     vm::{
         self,
         Result::{self as CompilerResult, CompileErr, Ok as CompilerOk, RuntimeErr},
     },
 };
 
+use crate::graph::show_graph;
 use eframe::egui;
 use egui::RichText;
-use std::collections::HashMap;
+use std::{collections::HashMap, rc::Rc};
 
 #[derive(serde::Deserialize, serde::Serialize)]
 struct CustomLanguage;
@@ -29,24 +33,30 @@ impl CustomLanguage {
         let mut lexer = Lexer::new(code.to_string());
         let ast_out = match Parser::new(&mut lexer).parse() {
             Ok(ast) => ast,
-            Err(e) => return Err(format!("{:?}", e)),
+            Err(errors) => {
+                let source = Rc::new(Source::new("playground", code));
+                return Err(render_all(&from_parse_errors(&errors, source), false));
+            }
         };
 
         Ok(ast_out)
     }
 
-    fn compile(&self, ast: &Vec<ASTNode>) -> DisassembledOutput {
-        let mut compiler = compiler::Compiler::new();
-        let (bytecode, interner) = compiler.compile(ast.clone());
+    fn compile(&self, code: &str, ast: &Vec<ASTNode>) -> Result<DisassembledOutput, String> {
+        let source = Rc::new(Source::new("playground", code));
+        let mut compiler = compiler::Compiler::new().with_source(source);
+        let (bytecode, interner) = compiler.compile(ast.clone()).map_err(|e| e.render(false))?;
 
-        DisassembledOutput { bytecode, interner }
+        Ok(DisassembledOutput { bytecode, interner })
     }
 
-    fn execute(&self, compiled: DisassembledOutput) -> String {
+    /// Runs the program, returning its output along with the graphs it drew with `draw_dot`
+    fn execute(&self, compiled: DisassembledOutput) -> (String, Vec<Vec<GraphNode>>) {
         let mut vm = vm::VM::init(compiled.bytecode, compiled.interner);
         let result: CompilerResult = vm.run();
+        let graphs = vm.graphs().iter().map(|tensor| tensor.graph()).collect();
 
-        match result {
+        let output = match result {
             CompilerOk(v) => {
                 let mut result = String::new();
                 for i in v.iter() {
@@ -55,9 +65,11 @@ impl CustomLanguage {
 
                 result
             }
-            CompileErr(e) => format!("CompileError({:?})", e),
-            RuntimeErr(e) => format!("RuntimeError({:?})", e),
-        }
+            CompileErr(errors) => render_all(&errors, false),
+            RuntimeErr(e) => e.render(false),
+        };
+
+        (output, graphs)
     }
 }
 
@@ -72,6 +84,8 @@ pub struct CustomLanguageDemo {
     disassembled: Option<DisassembledOutput>,
     result: String,
     constants: HashMap<String, String>,
+    #[serde(skip)]
+    graphs: Vec<Vec<GraphNode>>,
 }
 
 impl Default for CustomLanguageDemo {
@@ -92,7 +106,28 @@ let c = a + b;
 let d = a * b + b**3;
 c += c + 1;
 c += 1 + c + (-a);
-print(c);
+d += d * 2 + (b + a).relu();
+d += 3 * d + (b - a).relu();
+let e = c - d;
+let f = e**2;
+let g = f / 2.0;
+g += 10.0 / f;
+
+print(g);
+g.backward();
+print(a.grad);
+print(b.grad);
+                    "
+                    .to_string(),
+                ),
+                (
+                    "Graph".to_string(),
+                    "let x = tensor([1.0, -2.0]);
+let w = tensor([0.5, -0.25]);
+let y = (x * w).sum().tanh();
+y.backward();
+
+draw_dot(y);
                     "
                     .to_string(),
                 ),
@@ -102,6 +137,7 @@ print(c);
             disassembled: None,
             result: String::new(),
             constants: HashMap::new(),
+            graphs: Vec::new(),
         }
     }
 }
@@ -170,7 +206,8 @@ impl eframe::App for CustomLanguageDemo {
 
                 ui.heading(RichText::new("Execution Result").strong().size(16.));
                 ui.add_space(10.0);
-                ui.label(&self.result);
+                // monospace, so the carets of an error line up with its code
+                ui.label(RichText::new(&self.result).monospace());
             });
 
         // Right panel (AST and Disassembled Output)
@@ -195,7 +232,27 @@ impl eframe::App for CustomLanguageDemo {
                     }
                 });
 
-            // Bottom right (Disassembled Output)
+            // Bottom right (Computation Graph)
+            egui::TopBottomPanel::bottom("graph_panel")
+                .resizable(true)
+                .default_height(top_panel_height)
+                .show_inside(ui, |ui| {
+                    ui.heading(RichText::new("Computation Graph").strong().size(16.));
+
+                    ui.add_space(10.0);
+                    if self.graphs.is_empty() {
+                        ui.label("Call draw_dot(tensor) to see its graph");
+                    } else {
+                        egui::ScrollArea::both().show(ui, |ui| {
+                            for graph in &self.graphs {
+                                show_graph(ui, graph);
+                                ui.add_space(20.0);
+                            }
+                        });
+                    }
+                });
+
+            // Right (Disassembled Output)
 
             ui.heading(RichText::new("Disassembled Output").strong().size(16.));
 
@@ -235,10 +292,17 @@ impl CustomLanguageDemo {
             Ok(ast) => {
                 self.ast = Some(ast.clone());
 
-                let disassembled_output = self.custom_lang.compile(&ast);
-                self.disassembled = Some(disassembled_output.clone());
-
-                self.result = self.custom_lang.execute(disassembled_output);
+                match self.custom_lang.compile(&self.code, &ast) {
+                    Ok(disassembled_output) => {
+                        self.disassembled = Some(disassembled_output.clone());
+                        (self.result, self.graphs) = self.custom_lang.execute(disassembled_output);
+                    }
+                    Err(e) => {
+                        self.disassembled = None;
+                        self.graphs.clear();
+                        self.result = e;
+                    }
+                }
 
                 // Populate constants (replace this with actual constant extraction)
                 // self.constants.clear();
@@ -250,7 +314,8 @@ impl CustomLanguageDemo {
             Err(e) => {
                 self.ast = None;
                 self.disassembled = None;
-                self.result = format!("Error: {}", e);
+                self.graphs.clear();
+                self.result = e;
                 self.constants.clear();
             }
         }
@@ -0,0 +1,67 @@
+use eframe::egui;
+use egui::{Align2, FontId, Rect, Sense, Stroke, Vec2};
+use grad::tensor::GraphNode;
+
+const NODE_SIZE: Vec2 = Vec2::new(170.0, 48.0);
+const SPACING: Vec2 = Vec2::new(50.0, 14.0);
+
+/// Draws a computation graph left to right, each node one column after its latest input
+pub fn show_graph(ui: &mut egui::Ui, nodes: &[GraphNode]) {
+    // nodes come inputs first, so the inputs of a node are always placed before it
+    let mut columns: Vec<usize> = Vec::with_capacity(nodes.len());
+    let mut rows = Vec::with_capacity(nodes.len());
+    let mut column_sizes: Vec<usize> = Vec::new();
+    for node in nodes {
+        let column = node.inputs.iter().map(|&input| columns[input] + 1).max().unwrap_or(0);
+        if column_sizes.len() <= column {
+            column_sizes.resize(column + 1, 0);
+        }
+        columns.push(column);
+        rows.push(column_sizes[column]);
+        column_sizes[column] += 1;
+    }
+
+    let step = NODE_SIZE + SPACING;
+    let tallest = column_sizes.iter().max().copied().unwrap_or(0);
+    let size = Vec2::new(column_sizes.len() as f32 * step.x, tallest as f32 * step.y);
+    let (response, painter) = ui.allocate_painter(size, Sense::hover());
+
+    let rects = columns
+        .iter()
+        .zip(&rows)
+        .map(|(&column, &row)| {
+            let offset = Vec2::new(column as f32 * step.x, row as f32 * step.y);
+            Rect::from_min_size(response.rect.min + offset, NODE_SIZE)
+        })
+        .collect::<Vec<_>>();
+
+    let visuals = ui.visuals();
+    let edge = Stroke::new(1.0, visuals.weak_text_color());
+    for (node, rect) in nodes.iter().zip(&rects) {
+        for &input in &node.inputs {
+            let from = rects[input].right_center();
+            painter.arrow(from, rect.left_center() - from, edge);
+        }
+    }
+
+    let font = FontId::monospace(11.0);
+    for (node, rect) in nodes.iter().zip(&rects) {
+        let stroke = visuals.widgets.noninteractive.bg_stroke;
+        painter.rect(*rect, 4.0, visuals.extreme_bg_color, stroke);
+
+        let title = node.label.as_deref().or(node.operation.as_deref()).unwrap_or("leaf");
+        let text = format!(
+            "{}\ndata {}\ngrad {}",
+            title,
+            node.data_summary(),
+            node.gradient_summary()
+        );
+        painter.with_clip_rect(*rect).text(
+            rect.center(),
+            Align2::CENTER_CENTER,
+            text,
+            font.clone(),
+            visuals.text_color(),
+        );
+    }
+}